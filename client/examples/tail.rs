@@ -0,0 +1,28 @@
+//! Lists the most recent chats and prints every message event, e.g.
+//! `cargo run -p bluebubbles-client --example tail -- http://localhost:8000 <password>`
+
+use bluebubbles_client::{ChatQuery, Client, ServerEvent};
+use bluebubbles_core::database::ChatSort;
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut args = std::env::args().skip(1);
+    let (Some(url), Some(password)) = (args.next(), args.next()) else {
+        return Err("usage: tail <server url> <password>".into());
+    };
+    let client = Client::new(&url, password)?;
+    let chats = client.query_chats(&ChatQuery { limit: Some(10), sort: Some(ChatSort::LastMessage), ..Default::default() }).await?;
+    for chat in chats {
+        println!("{} {}", chat.guid, chat.display_name.unwrap_or_default());
+    }
+    let mut events = client.subscribe().await?;
+    while let Some(event) = events.next().await {
+        match event? {
+            ServerEvent::NewMessage(event) => println!("new {}: {}", event.message.guid, event.message.text.unwrap_or_default()),
+            ServerEvent::UpdatedMessage(event) => println!("updated {}", event.message.guid),
+            ServerEvent::GroupNameChange(event) => println!("renamed {:?}", event.chats.first().map(|chat| &chat.guid)),
+            ServerEvent::ChatReadStatusChanged(status) => println!("read {} {}", status.chat_guid, status.read),
+        }
+    }
+    Ok(())
+}
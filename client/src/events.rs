@@ -0,0 +1,93 @@
+use bluebubbles_core::api::{ChatReadStatus, MessageEvent};
+use futures_util::FutureExt;
+use rust_socketio::{asynchronous::{Client as SocketClient, ClientBuilder}, Payload, TransportType};
+use serde_json::Value;
+use tokio::sync::mpsc;
+
+use crate::{Client, ClientError};
+
+/// Events pushed by the server as chat.db changes.
+#[derive(Debug, Clone)]
+pub enum ServerEvent {
+    NewMessage(MessageEvent),
+    UpdatedMessage(MessageEvent),
+    /// Sent before the `NewMessage` of a message that renamed a group chat.
+    GroupNameChange(MessageEvent),
+    ChatReadStatusChanged(ChatReadStatus),
+}
+
+const EVENTS: [&str; 4] = ["new-message", "updated-message", "group-name-change", "chat-read-status-changed"];
+
+impl ServerEvent {
+    /// Decodes the payload of the event called `name`, `None` for events this client doesn't know.
+    pub fn parse(name: &str, payload: Value) -> Result<Option<Self>, ClientError> {
+        return Ok(Some(match name {
+            "new-message" => Self::NewMessage(serde_json::from_value(payload)?),
+            "updated-message" => Self::UpdatedMessage(serde_json::from_value(payload)?),
+            "group-name-change" => Self::GroupNameChange(serde_json::from_value(payload)?),
+            "chat-read-status-changed" => Self::ChatReadStatusChanged(serde_json::from_value(payload)?),
+            _ => return Ok(None),
+        }));
+    }
+}
+
+/// A Socket.IO connection, events arrive in the order the server sent them.
+pub struct EventStream {
+    socket: SocketClient,
+    events: mpsc::UnboundedReceiver<Result<ServerEvent, ClientError>>,
+}
+
+impl EventStream {
+    /// The next event, or an error for one that couldn't be decoded. `None` once the connection is closed.
+    pub async fn next(&mut self) -> Option<Result<ServerEvent, ClientError>> {
+        self.events.recv().await
+    }
+
+    pub async fn close(self) -> Result<(), ClientError> {
+        return Ok(self.socket.disconnect().await?);
+    }
+}
+
+impl Client {
+    /// Connects to the server's Socket.IO endpoint and streams its events.
+    pub async fn subscribe(&self) -> Result<EventStream, ClientError> {
+        let mut url = self.base.clone();
+        url.query_pairs_mut().append_pair("guid", &self.password);
+        let (sender, events) = mpsc::unbounded_channel();
+        // the server's long polling answers rust_socketio's requests with 400, connect with a websocket right away
+        let mut builder = ClientBuilder::new(url.as_str()).transport_type(TransportType::Websocket);
+        for name in EVENTS {
+            let sender = sender.clone();
+            builder = builder.on(name, move |payload, _| {
+                let sender = sender.clone();
+                async move {
+                    let event = match payload {
+                        Payload::Text(mut values) if !values.is_empty() => ServerEvent::parse(name, values.swap_remove(0)),
+                        _ => Err(ClientError::Decode(serde::de::Error::custom(format!("{name} without a JSON payload")))),
+                    };
+                    if let Some(event) = event.transpose() {
+                        // the stream was dropped, nobody is listening anymore
+                        let _ = sender.send(event);
+                    }
+                }.boxed()
+            });
+        }
+        let socket = builder.connect().await?;
+        return Ok(EventStream { socket, events });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::ServerEvent;
+
+    #[test]
+    fn test_parse() {
+        let event = ServerEvent::parse("chat-read-status-changed", json!({"chatGuid": "iMessage;-;+15555550100", "read": true})).unwrap();
+        assert!(matches!(event, Some(ServerEvent::ChatReadStatusChanged(status)) if status.read && status.chat_guid == "iMessage;-;+15555550100"));
+        assert!(ServerEvent::parse("typing-indicator", json!({})).unwrap().is_none());
+        assert!(ServerEvent::parse("new-message", json!({"guid": 1})).is_err());
+    }
+}
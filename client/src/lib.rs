@@ -0,0 +1,419 @@
+//! Async client for the BlueBubbles server. [`Client`] has a method for every REST route and
+//! [`Client::subscribe`] streams the Socket.IO events, both returning the `bluebubbles-core` types
+//! the server serializes.
+#![allow(clippy::needless_return)]
+
+use std::fmt;
+
+use bluebubbles_core::{api::{HandleCount, PageMetadata, SearchResult, ServerInfo, Statistics, UpdateCheck}, database::{ChatCounts, ChatSort, MessageCursor, SortOrder}, structs::{Chat, Message, Participant}};
+use reqwest::RequestBuilder;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use url::Url;
+
+pub mod events;
+
+pub use events::{EventStream, ServerEvent};
+
+#[derive(Debug)]
+pub enum ClientError {
+    /// The base url can't have api paths appended to it.
+    InvalidUrl(String),
+    Http(reqwest::Error),
+    /// The server answered with its error envelope.
+    Api { status: u16, kind: String, message: String },
+    /// The response or event wasn't the JSON this client expects.
+    Decode(serde_json::Error),
+    Socket(Box<rust_socketio::Error>),
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidUrl(url) => write!(f, "invalid server url {url:?}"),
+            Self::Http(err) => write!(f, "request failed: {err}"),
+            Self::Api { status, kind, message } => write!(f, "{status} {kind}: {message}"),
+            Self::Decode(err) => write!(f, "unexpected response: {err}"),
+            Self::Socket(err) => write!(f, "socket.io error: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Http(err) => Some(err),
+            Self::Decode(err) => Some(err),
+            Self::Socket(err) => Some(err.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for ClientError {
+    fn from(err: reqwest::Error) -> Self {
+        Self::Http(err)
+    }
+}
+
+impl From<serde_json::Error> for ClientError {
+    fn from(err: serde_json::Error) -> Self {
+        Self::Decode(err)
+    }
+}
+
+impl From<rust_socketio::Error> for ClientError {
+    fn from(err: rust_socketio::Error) -> Self {
+        Self::Socket(Box::new(err))
+    }
+}
+
+/// `{status, message, data, metadata}` around every successful response.
+#[derive(Debug, Deserialize)]
+struct Envelope<T> {
+    data: T,
+    metadata: Option<PageMetadata>,
+}
+
+#[derive(Deserialize)]
+struct ErrorEnvelope {
+    status: u16,
+    error: ErrorBody,
+}
+
+#[derive(Deserialize)]
+struct ErrorBody {
+    #[serde(rename = "type")]
+    kind: String,
+    message: String,
+}
+
+/// A page of messages, pass `metadata.next_cursor` as the next `cursor`.
+#[derive(Debug, Clone)]
+pub struct Page<T> {
+    pub data: Vec<T>,
+    pub metadata: Option<PageMetadata>,
+}
+
+/// Body of `POST /chat/query`.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ChatQuery {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub offset: Option<usize>,
+    /// `lastmessage` and/or `participants`
+    pub with: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none", serialize_with = "display")]
+    pub sort: Option<ChatSort>,
+}
+
+/// Body of `POST /handle/query`.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct HandleQuery {
+    /// Only handles whose address contains this.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub address: Option<String>,
+    /// `chats`
+    pub with: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub offset: Option<usize>,
+}
+
+/// A condition of [`MessageQuery::filters`], the server only accepts these.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Where {
+    IsFromMe(bool),
+    /// `originalROWID` of the sender's handle, 0 for messages sent from the server's Mac
+    HandleId(u32),
+    /// SQL `LIKE` pattern on the message text, e.g. `%lunch%`
+    TextLike(String),
+}
+
+impl Serialize for Where {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let clause = match self {
+            Self::IsFromMe(is_from_me) => serde_json::json!({"field": "isFromMe", "value": is_from_me}),
+            Self::HandleId(handle_id) => serde_json::json!({"field": "handleId", "value": handle_id}),
+            Self::TextLike(pattern) => serde_json::json!({"field": "text", "operator": "like", "value": pattern}),
+        };
+        clause.serialize(serializer)
+    }
+}
+
+/// Body of `POST /message/query`, without a `chat_guid` it searches all chats.
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MessageQuery {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chat_guid: Option<String>,
+    /// `attachment`, `handle`, `reactions` and/or `replyCount`, attachments and handles are included when empty
+    pub with: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub offset: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none", serialize_with = "display")]
+    pub sort: Option<SortOrder>,
+    /// unix time in milliseconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub after: Option<u128>,
+    /// unix time in milliseconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub before: Option<u128>,
+    #[serde(skip_serializing_if = "Option::is_none", serialize_with = "display")]
+    pub cursor: Option<MessageCursor>,
+    #[serde(rename = "where")]
+    pub filters: Vec<Where>,
+}
+
+/// Body of `POST /message/search`.
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MessageSearch {
+    /// Words the messages have to contain, the last one can be the start of a word.
+    pub query: String,
+    /// Only search these chats, all of them when empty.
+    pub chat_guids: Vec<String>,
+    /// `attachment` and/or `handle`
+    pub with: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub offset: Option<usize>,
+}
+
+/// Query of `/chat/:guid/message` and `/message/:guid/thread`, unset fields use the server's defaults.
+#[derive(Debug, Clone, Default)]
+pub struct MessagesQuery {
+    /// `attachment`, `handle`, `reactions` and/or `replyCount`, attachments and handles are included when empty
+    pub with: Vec<String>,
+    pub offset: Option<usize>,
+    pub limit: Option<usize>,
+    pub sort: Option<SortOrder>,
+    /// unix time in milliseconds
+    pub after: Option<u128>,
+    /// unix time in milliseconds
+    pub before: Option<u128>,
+    pub cursor: Option<MessageCursor>,
+}
+
+impl MessagesQuery {
+    fn pairs(&self) -> Vec<(&'static str, String)> {
+        let mut pairs = vec![];
+        if !self.with.is_empty() {
+            pairs.push(("with", self.with.join(",")));
+        }
+        pairs.extend(self.offset.map(|offset| ("offset", offset.to_string())));
+        pairs.extend(self.limit.map(|limit| ("limit", limit.to_string())));
+        pairs.extend(self.sort.map(|sort| ("sort", sort.to_string())));
+        pairs.extend(self.after.map(|after| ("after", after.to_string())));
+        pairs.extend(self.before.map(|before| ("before", before.to_string())));
+        pairs.extend(self.cursor.map(|cursor| ("cursor", cursor.to_string())));
+        return pairs;
+    }
+}
+
+fn display<T: fmt::Display, S: serde::Serializer>(value: &Option<T>, serializer: S) -> Result<S::Ok, S::Error> {
+    match value {
+        Some(value) => serializer.collect_str(value),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// Parses a response body, turning error envelopes and non 2xx statuses into `ClientError::Api`.
+fn decode<T: DeserializeOwned>(status: u16, body: &[u8]) -> Result<Envelope<T>, ClientError> {
+    if !(200..300).contains(&status) {
+        return Err(api_error(status, body));
+    }
+    return Ok(serde_json::from_slice(body)?);
+}
+
+fn api_error(status: u16, body: &[u8]) -> ClientError {
+    match serde_json::from_slice::<ErrorEnvelope>(body) {
+        Ok(envelope) => ClientError::Api { status: envelope.status, kind: envelope.error.kind, message: envelope.error.message },
+        Err(_) => ClientError::Api { status, kind: "Unknown Error".into(), message: String::from_utf8_lossy(body).into_owned() },
+    }
+}
+
+/// A connection to one server, cheap to clone.
+#[derive(Debug, Clone)]
+pub struct Client {
+    http: reqwest::Client,
+    base: Url,
+    password: String,
+}
+
+impl Client {
+    /// `base_url` is where the server listens, e.g. `http://192.168.1.2:8000`.
+    pub fn new(base_url: &str, password: impl Into<String>) -> Result<Self, ClientError> {
+        let base = Url::parse(base_url).map_err(|_| ClientError::InvalidUrl(base_url.into()))?;
+        if base.cannot_be_a_base() || !matches!(base.scheme(), "http" | "https") {
+            return Err(ClientError::InvalidUrl(base_url.into()));
+        }
+        return Ok(Self {
+            http: reqwest::Client::new(),
+            base,
+            password: password.into(),
+        });
+    }
+
+    /// `/api/v1/<segments>?guid=<password>`, segments are percent-encoded so chat guids can be passed as is.
+    fn url(&self, segments: &[&str]) -> Url {
+        let mut url = self.base.clone();
+        url.path_segments_mut().expect("checked in Client::new").pop_if_empty().extend(["api", "v1"]).extend(segments);
+        url.query_pairs_mut().append_pair("guid", &self.password);
+        return url;
+    }
+
+    async fn send<T: DeserializeOwned>(&self, request: RequestBuilder) -> Result<Envelope<T>, ClientError> {
+        let response = request.send().await?;
+        let status = response.status().as_u16();
+        return decode(status, &response.bytes().await?);
+    }
+
+    async fn get<T: DeserializeOwned>(&self, segments: &[&str], query: &[(&str, String)]) -> Result<T, ClientError> {
+        return Ok(self.send(self.http.get(self.url(segments)).query(query)).await?.data);
+    }
+
+    pub async fn ping(&self) -> Result<String, ClientError> {
+        self.get(&["ping"], &[]).await
+    }
+
+    pub async fn statistics(&self) -> Result<Statistics, ClientError> {
+        self.get(&["server", "statistics", "totals"], &[]).await
+    }
+
+    pub async fn update_check(&self) -> Result<UpdateCheck, ClientError> {
+        self.get(&["server", "update", "check"], &[]).await
+    }
+
+    pub async fn server_info(&self) -> Result<ServerInfo, ClientError> {
+        self.get(&["server", "info"], &[]).await
+    }
+
+    pub async fn contacts(&self) -> Result<Vec<Value>, ClientError> {
+        self.get(&["contact"], &[]).await
+    }
+
+    /// `with` can contain `lastmessage` and `participants`.
+    pub async fn chat(&self, guid: &str, with: &[&str]) -> Result<Chat, ClientError> {
+        self.get(&["chat", guid], &[("with", with.join(","))]).await
+    }
+
+    pub async fn query_chats(&self, query: &ChatQuery) -> Result<Vec<Chat>, ClientError> {
+        return Ok(self.send(self.http.post(self.url(&["chat", "query"])).json(query)).await?.data);
+    }
+
+    pub async fn chat_count(&self) -> Result<ChatCounts, ClientError> {
+        self.get(&["chat", "count"], &[]).await
+    }
+
+    pub async fn chat_messages(&self, chat_guid: &str, query: &MessagesQuery) -> Result<Page<Message>, ClientError> {
+        let envelope = self.send(self.http.get(self.url(&["chat", chat_guid, "message"])).query(&query.pairs())).await?;
+        return Ok(Page { data: envelope.data, metadata: envelope.metadata });
+    }
+
+    pub async fn query_messages(&self, query: &MessageQuery) -> Result<Page<Message>, ClientError> {
+        let envelope = self.send(self.http.post(self.url(&["message", "query"])).json(query)).await?;
+        return Ok(Page { data: envelope.data, metadata: envelope.metadata });
+    }
+
+    /// Best matches first, each with an HTML snippet of the text, escaped, where the matched words are in `<b>` tags.
+    pub async fn search_messages(&self, search: &MessageSearch) -> Result<Vec<SearchResult>, ClientError> {
+        return Ok(self.send(self.http.post(self.url(&["message", "search"])).json(search)).await?.data);
+    }
+
+    /// `with` can contain `handle`, `attachment`, `reactions` and `replyCount`.
+    pub async fn message(&self, guid: &str, with: &[&str]) -> Result<Message, ClientError> {
+        self.get(&["message", guid], &[("with", with.join(","))]).await
+    }
+
+    /// The message that started the thread `guid` is in followed by its replies, oldest first.
+    pub async fn message_thread(&self, guid: &str, query: &MessagesQuery) -> Result<Page<Message>, ClientError> {
+        let envelope = self.send(self.http.get(self.url(&["message", guid, "thread"])).query(&query.pairs())).await?;
+        return Ok(Page { data: envelope.data, metadata: envelope.metadata });
+    }
+
+    pub async fn query_handles(&self, query: &HandleQuery) -> Result<Vec<Participant>, ClientError> {
+        return Ok(self.send(self.http.post(self.url(&["handle", "query"])).json(query)).await?.data);
+    }
+
+    /// The chats the handle is in are included unless `with` asks for something else.
+    pub async fn handle(&self, address: &str, with: &[&str]) -> Result<Participant, ClientError> {
+        self.get(&["handle", address], &[("with", with.join(","))]).await
+    }
+
+    pub async fn handle_count(&self) -> Result<HandleCount, ClientError> {
+        self.get(&["handle", "count"], &[]).await
+    }
+
+    /// `with` can contain `lastmessage` and `participants`.
+    pub async fn handle_chats(&self, address: &str, with: &[&str]) -> Result<Vec<Chat>, ClientError> {
+        self.get(&["handle", address, "chats"], &[("with", with.join(","))]).await
+    }
+
+    async fn get_bytes(&self, segments: &[&str], query: &[(&str, String)]) -> Result<Vec<u8>, ClientError> {
+        let response = self.http.get(self.url(segments)).query(query).send().await?;
+        let status = response.status().as_u16();
+        let body = response.bytes().await?;
+        if !(200..300).contains(&status) {
+            return Err(api_error(status, &body));
+        }
+        return Ok(body.to_vec());
+    }
+
+    /// The attachment's file contents.
+    pub async fn download_attachment(&self, guid: &str) -> Result<Vec<u8>, ClientError> {
+        self.get_bytes(&["attachment", guid, "download"], &[]).await
+    }
+
+    /// An image attachment fitted into `width` x `height` as a JPEG, the server's default size if both are `None`.
+    pub async fn attachment_thumbnail(&self, guid: &str, width: Option<u32>, height: Option<u32>) -> Result<Vec<u8>, ClientError> {
+        let size = |name, size: Option<u32>| size.map(|size| (name, size.to_string()));
+        let query: Vec<_> = [size("width", width), size("height", height)].into_iter().flatten().collect();
+        self.get_bytes(&["attachment", guid, "thumbnail"], &query).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bluebubbles_core::database::{ChatSort, MessageCursor, SortOrder};
+
+    use super::{decode, ChatQuery, Client, ClientError, MessageQuery, MessagesQuery, Where};
+
+    #[test]
+    fn test_urls() {
+        let client = Client::new("http://localhost:8000", "p@ss word").unwrap();
+        assert_eq!(client.url(&["chat", "iMessage;-;+15555550100", "message"]).as_str(), "http://localhost:8000/api/v1/chat/iMessage;-;+15555550100/message?guid=p%40ss+word");
+        let client = Client::new("https://example.com/bluebubbles/", "pw").unwrap();
+        assert_eq!(client.url(&["chat", "SMS;+;chat#1"]).as_str(), "https://example.com/bluebubbles/api/v1/chat/SMS;+;chat%231?guid=pw");
+        assert!(matches!(Client::new("mailto:someone", "pw"), Err(ClientError::InvalidUrl(_))));
+
+        let query = MessagesQuery { with: vec!["handle".into()], sort: Some(SortOrder::Asc), cursor: Some(MessageCursor { date: 10, rowid: 2 }), ..Default::default() };
+        assert_eq!(query.pairs(), [("with", "handle".to_string()), ("sort", "ASC".into()), ("cursor", "10:2".into())]);
+        let query = ChatQuery { with: vec!["lastmessage".into()], sort: Some(ChatSort::LastMessage), ..Default::default() };
+        assert_eq!(serde_json::to_value(query).unwrap(), serde_json::json!({"with": ["lastmessage"], "sort": "lastmessage"}));
+        let query = MessageQuery { filters: vec![Where::IsFromMe(false), Where::TextLike("%lunch%".into())], sort: Some(SortOrder::Asc), ..Default::default() };
+        assert_eq!(serde_json::to_value(query).unwrap(), serde_json::json!({
+            "with": [],
+            "sort": "ASC",
+            "where": [{"field": "isFromMe", "value": false}, {"field": "text", "operator": "like", "value": "%lunch%"}],
+        }));
+    }
+
+    #[test]
+    fn test_decode() {
+        let envelope = decode::<String>(200, br#"{"status":200,"message":"Ping received!","data":"pong"}"#).unwrap();
+        assert_eq!(envelope.data, "pong");
+        assert!(envelope.metadata.is_none());
+
+        let err = decode::<String>(401, br#"{"status":401,"message":"You are not authorized to access this resource","error":{"type":"Authentication Error","message":"Unauthorized"}}"#).unwrap_err();
+        assert!(matches!(err, ClientError::Api { status: 401, ref kind, .. } if kind == "Authentication Error"));
+        let err = decode::<String>(404, b"No route for /api/v1/nope").unwrap_err();
+        assert!(matches!(err, ClientError::Api { status: 404, ref message, .. } if message == "No route for /api/v1/nope"));
+        assert!(matches!(decode::<u32>(200, br#"{"data":"pong"}"#), Err(ClientError::Decode(_))));
+    }
+}
@@ -0,0 +1,104 @@
+//! Bodies of the REST responses and Socket.IO events that aren't chat.db rows, shared by the
+//! server and clients. Every REST response wraps them in `{status, message, data, metadata}`.
+
+use serde::{de, Deserialize, Deserializer, Serialize};
+use serde_json::{Map, Value};
+
+use crate::{database::{MessagePage, MessageQuery}, schema::Capabilities, structs::{Chat, Message}};
+
+/// `data` of `/server/statistics/totals`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Statistics {
+    pub handles: usize,
+    pub messages: usize,
+    pub chats: usize,
+    pub attachments: usize,
+}
+
+/// `data` of `/server/update/check`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UpdateCheck {
+    pub available: bool,
+    pub current: String,
+    pub metadata: Option<Value>,
+}
+
+/// `data` of `/server/info`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerInfo {
+    pub os_version: String,
+    pub server_version: String,
+    pub private_api: bool,
+    pub proxy_service: String,
+    pub helper_connected: bool,
+    pub detected_icloud: String,
+    /// The macOS version chat.db looks like it was written by, see [`SchemaVersion`](crate::schema::SchemaVersion).
+    #[serde(default)]
+    pub chat_db_schema: String,
+    #[serde(default)]
+    pub chat_db_capabilities: Capabilities,
+}
+
+/// `data` of `/handle/count`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HandleCount {
+    pub total: usize,
+}
+
+/// Paging details sent next to a list of messages, pass `nextCursor` back as `cursor` to get the following page.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PageMetadata {
+    pub offset: usize,
+    pub limit: usize,
+    pub count: usize,
+    #[serde(rename = "nextCursor")]
+    pub next_cursor: Option<String>,
+}
+
+impl PageMetadata {
+    pub fn new(query: &MessageQuery, page: &MessagePage) -> Self {
+        Self {
+            offset: query.offset,
+            limit: query.limit,
+            count: page.messages.len(),
+            next_cursor: page.next_cursor.map(|cursor| cursor.to_string()),
+        }
+    }
+}
+
+/// One entry of `/message/search`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SearchResult {
+    pub message: Message,
+    /// HTML: the matching part of the text, escaped, with the matched words in `<b>` tags.
+    pub snippet: String,
+}
+
+/// Payload of the `new-message`, `updated-message` and `group-name-change` events: the message
+/// with the chats it belongs to, so clients can file it without another request.
+#[derive(Debug, Clone, Serialize)]
+pub struct MessageEvent {
+    #[serde(flatten)]
+    pub message: Message,
+    pub chats: Vec<Chat>,
+}
+
+// serde can't buffer the u128 dates of a flattened Message, so split the chats off by hand
+impl<'de> Deserialize<'de> for MessageEvent {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut message = Map::deserialize(deserializer)?;
+        let chats = message.remove("chats").map(Vec::<Chat>::deserialize).transpose().map_err(de::Error::custom)?;
+        return Ok(Self {
+            message: Message::deserialize(Value::Object(message)).map_err(de::Error::custom)?,
+            chats: chats.unwrap_or_default(),
+        });
+    }
+}
+
+/// Payload of the `chat-read-status-changed` event.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatReadStatus {
+    #[serde(rename = "chatGuid")]
+    pub chat_guid: String,
+    pub read: bool,
+}
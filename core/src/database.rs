@@ -0,0 +1,1311 @@
+use std::{collections::HashMap, fmt, path::{Path, PathBuf}, str::FromStr};
+
+use rusqlite::{types::Value as SqlValue, Connection, OpenFlags, OptionalExtension, Row, ToSql};
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::{dimensions::DimensionCache, schema::Schema, structs::{attributed_body, current_reactions, Attachment, Chat, Message, Participant, Reaction}, util::{expand_home, unix_to_apple}};
+
+/// Schema of the tables the server reads from chat.db, used to build fixture databases.
+pub const FIXTURE_SCHEMA: &str = include_str!("fixtures/chat.sql");
+
+/// How the underlying chat.db file is opened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OpenMode {
+    ReadWrite,
+    /// Messages.app keeps writing to chat.db, so the server only ever needs to read it.
+    #[default]
+    ReadOnly,
+    /// Read-only and tells SQLite the file can never change, skipping all locking.
+    /// Only safe for copies of chat.db, a live database will return stale or corrupt results.
+    Immutable,
+}
+
+impl FromStr for OpenMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "readwrite" | "read-write" => Ok(Self::ReadWrite),
+            "readonly" | "read-only" => Ok(Self::ReadOnly),
+            "immutable" => Ok(Self::Immutable),
+            _ => Err(format!("unknown open mode {s:?}, expected read-write, read-only or immutable")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DatabaseOptions {
+    pub mode: OpenMode,
+    /// Unix time in nanoseconds, messages older than this are not reported by `poll`.
+    pub last_read_time: u128,
+    /// SQLite file attachment dimensions are cached in, `None` keeps them in memory.
+    pub dimension_cache: Option<PathBuf>,
+}
+
+pub struct Database {
+    conn: Connection,
+    schema: Schema,
+    dimensions: DimensionCache,
+    last_read_time: u128,
+    watermark: Option<Watermark>,
+}
+
+/// A change to the message table picked up by [`Database::poll`].
+#[derive(Debug, Clone)]
+pub enum ChangeEvent {
+    NewMessage(Message),
+    UpdatedMessage(Message),
+    /// chat.db only records when a chat was read on this Mac, so `read` is currently always true.
+    ChatReadStatusChanged { chat_guid: String, read: bool },
+}
+
+/// The dates that change after a message is inserted, NULL on some older rows. Edits and unsends
+/// both move `date_edited`, `date_retracted` is covered as well in case only it changes.
+const CHANGE_DATES: &[&str] = &["date_read", "date_delivered", "date_edited", "date_retracted"];
+
+/// How far `poll` has read: new rows are found by ROWID, changes to older rows by their newest change date
+/// and chat reads by `chat.last_read_message_timestamp`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Watermark {
+    rowid: i64,
+    change_date: i64,
+    chat_read: i64,
+}
+
+impl Watermark {
+    fn from_row(row: &Row) -> rusqlite::Result<(String, Self)> {
+        Ok((row.get("guid")?, Self {
+            rowid: row.get("ROWID")?,
+            change_date: row.get("change_date")?,
+            chat_read: 0,
+        }))
+    }
+
+    fn max(self, other: Self) -> Self {
+        Self {
+            rowid: self.rowid.max(other.rowid),
+            change_date: self.change_date.max(other.change_date),
+            chat_read: self.chat_read.max(other.chat_read),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SortOrder {
+    Asc,
+    #[default]
+    Desc,
+}
+
+impl FromStr for SortOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_uppercase().as_str() {
+            "ASC" => Ok(Self::Asc),
+            "DESC" => Ok(Self::Desc),
+            _ => Err(format!("unknown sort {s:?}, expected ASC or DESC")),
+        }
+    }
+}
+
+impl fmt::Display for SortOrder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Asc => "ASC",
+            Self::Desc => "DESC",
+        })
+    }
+}
+
+/// Order of `query_chats`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ChatSort {
+    /// The order chats were created in.
+    #[default]
+    RowId,
+    /// Most recently active first, the order of the conversation list in Messages.app.
+    LastMessage,
+}
+
+impl FromStr for ChatSort {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "rowid" => Ok(Self::RowId),
+            "lastmessage" => Ok(Self::LastMessage),
+            _ => Err(format!("unknown chat sort {s:?}, expected rowid or lastmessage")),
+        }
+    }
+}
+
+impl fmt::Display for ChatSort {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::RowId => "rowid",
+            Self::LastMessage => "lastmessage",
+        })
+    }
+}
+
+
+/// Position of the last message of a page, the next page starts right after it even if
+/// new messages arrived in between. Sent to clients as `date:rowid`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MessageCursor {
+    /// `chat_message_join.message_date` in apple time
+    pub date: i64,
+    pub rowid: i64,
+}
+
+impl fmt::Display for MessageCursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.date, self.rowid)
+    }
+}
+
+impl FromStr for MessageCursor {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (date, rowid) = s.split_once(':').ok_or_else(|| format!("invalid cursor {s:?}"))?;
+        Ok(Self {
+            date: date.parse().map_err(|_| format!("invalid cursor {s:?}"))?,
+            rowid: rowid.parse().map_err(|_| format!("invalid cursor {s:?}"))?,
+        })
+    }
+}
+
+/// A condition on the message table, clients can only filter on these so every query stays a fixed
+/// SQL fragment with the value bound as a parameter.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MessageFilter {
+    IsFromMe(bool),
+    /// `handle.ROWID` of the sender, 0 for messages sent from this Mac
+    HandleId(u32),
+    /// SQL `LIKE` pattern on the plain text, e.g. `%lunch%`. Case insensitive for ASCII only.
+    TextLike(String),
+    /// The message with this guid and every inline reply to it.
+    Thread(String),
+}
+
+impl MessageFilter {
+    /// The condition on `message AS m` and the value to bind to `param` in it.
+    fn condition(&self, param: &str, schema: &Schema) -> (String, SqlValue) {
+        match self {
+            Self::IsFromMe(is_from_me) => (format!("m.is_from_me = {param}"), (*is_from_me).into()),
+            Self::HandleId(handle_id) => (format!("m.handle_id = {param}"), (*handle_id).into()),
+            Self::TextLike(pattern) => (format!("m.text LIKE {param}"), pattern.clone().into()),
+            Self::Thread(guid) if schema.capabilities().threads => (format!("(m.guid = {param} OR m.thread_originator_guid = {param})"), guid.clone().into()),
+            // nothing can have replies before inline replies existed
+            Self::Thread(guid) => (format!("m.guid = {param}"), guid.clone().into()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MessageQuery {
+    pub attachments: bool,
+    pub handle: bool,
+    /// Fill in [`Message::reactions`].
+    pub reactions: bool,
+    /// Fill in [`Message::reply_count`].
+    pub reply_counts: bool,
+    pub offset: usize,
+    pub limit: usize,
+    pub sort: SortOrder,
+    /// apple time in nanoseconds, exclusive
+    pub after: u128,
+    /// apple time in nanoseconds, exclusive
+    pub before: u128,
+    /// Continue after this message instead of counting `offset` from the start.
+    pub cursor: Option<MessageCursor>,
+    /// Only messages matching all of these.
+    pub filters: Vec<MessageFilter>,
+}
+
+impl Default for MessageQuery {
+    fn default() -> Self {
+        Self {
+            attachments: true,
+            handle: true,
+            reactions: false,
+            reply_counts: false,
+            offset: 0,
+            limit: 1000,
+            sort: SortOrder::Desc,
+            after: 0,
+            before: u128::MAX,
+            cursor: None,
+            filters: vec![],
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MessagePage {
+    pub messages: Vec<Message>,
+    /// Cursor for the page after this one, `None` once the last page was returned.
+    pub next_cursor: Option<MessageCursor>,
+}
+
+/// The text of a message as it goes into the search index.
+#[derive(Debug, Clone)]
+pub struct MessageText {
+    pub rowid: i64,
+    pub guid: String,
+    /// empty while the message isn't in a chat yet
+    pub chat_guid: String,
+    pub text: Option<String>,
+}
+
+/// `attachment.transfer_state` of attachments whose file is on this Mac. Files that aren't finished are still
+/// being received, or were removed to save space and only exist in iCloud.
+pub const TRANSFER_FINISHED: i64 = 5;
+
+/// What's needed to serve an attachment's file.
+#[derive(Debug, Clone)]
+pub struct AttachmentFile {
+    /// `None` until the file was downloaded, may start with `~`.
+    pub path: Option<String>,
+    pub mime_type: Option<String>,
+    pub transfer_name: Option<String>,
+    /// [`TRANSFER_FINISHED`] once the file was downloaded.
+    pub transfer_state: i64,
+}
+
+/// SQLite integers are signed 64 bit, which still covers apple time until the year 2293.
+fn clamp_date(date: u128) -> i64 {
+    date.min(i64::MAX as u128) as i64
+}
+
+fn participant_from_row(row: &Row) -> rusqlite::Result<Participant> {
+    Ok(Participant {
+        original_rowid: row.get("ROWID")?,
+        address: row.get("id")?,
+        country: row.get::<_, Option<String>>("country")?.unwrap_or_default(),
+        uncanonicalized_id: row.get("uncanonicalized_id").ok(),
+        service: row.get("service")?,
+        chats: None,
+    })
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatCounts {
+    pub total: usize,
+    pub breakdown: HashMap<String, usize>,
+}
+
+impl Database {
+    /// The chat.db of the current user.
+    pub fn default_path() -> PathBuf {
+        PathBuf::from(std::env::var("HOME").unwrap_or_default()).join("Library/Messages/chat.db")
+    }
+
+    pub fn open(path: impl AsRef<Path>, options: DatabaseOptions) -> rusqlite::Result<Self> {
+        let path = path.as_ref();
+        tracing::info!("opening: {path:?} ({:?})", options.mode);
+        let conn = match options.mode {
+            OpenMode::ReadWrite => Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_NO_MUTEX)?,
+            OpenMode::ReadOnly => Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX)?,
+            OpenMode::Immutable => {
+                // immutable can only be set through a URI, let the url crate deal with escaping the path
+                let absolute = std::path::absolute(path).map_err(|_| rusqlite::Error::InvalidPath(path.to_path_buf()))?;
+                let mut uri = Url::from_file_path(&absolute).map_err(|_| rusqlite::Error::InvalidPath(absolute.clone()))?;
+                uri.set_query(Some("immutable=1"));
+                Connection::open_with_flags(uri.as_str(), OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_URI | OpenFlags::SQLITE_OPEN_NO_MUTEX)?
+            }
+        };
+        let schema = Schema::probe(&conn)?;
+        tracing::info!("chat.db schema: {}", schema.version());
+        let dimensions = match &options.dimension_cache {
+            Some(path) => DimensionCache::open(path)?,
+            None => DimensionCache::open_in_memory()?,
+        };
+        Ok(Self {
+            last_read_time: options.last_read_time,
+            watermark: None,
+            schema,
+            dimensions,
+            conn,
+        })
+    }
+
+    /// Creates an empty in-memory database with the chat.db schema, for tests and tooling that can't reach a real chat.db.
+    pub fn open_fixture(options: DatabaseOptions) -> rusqlite::Result<Self> {
+        let conn = Connection::open_in_memory()?;
+        conn.execute_batch(FIXTURE_SCHEMA)?;
+        let schema = Schema::probe(&conn)?;
+        tracing::info!("chat.db schema: {}", schema.version());
+        let dimensions = match &options.dimension_cache {
+            Some(path) => DimensionCache::open(path)?,
+            None => DimensionCache::open_in_memory()?,
+        };
+        Ok(Self {
+            last_read_time: options.last_read_time,
+            watermark: None,
+            schema,
+            dimensions,
+            conn,
+        })
+    }
+
+    /// The columns this chat.db has.
+    pub fn schema(&self) -> &Schema {
+        &self.schema
+    }
+
+    /// Every chat column plus the guid and date of the chat's newest message. Both come from
+    /// `chat_message_join_idx_message_date_id_chat_id`, so this stays one indexed lookup per chat.
+    fn chat_select(&self) -> String {
+        format!("SELECT {}, lm.guid AS last_message_guid, \
+            (SELECT MAX(message_date) FROM chat_message_join WHERE chat_id = c.ROWID) AS last_message_date \
+            FROM chat AS c LEFT JOIN message AS lm ON lm.ROWID = (SELECT message_id FROM chat_message_join WHERE chat_id = c.ROWID ORDER BY message_date DESC LIMIT 1)", self.schema.chat_columns())
+    }
+
+    /// The newest of the [`CHANGE_DATES`] this chat.db has.
+    fn change_date(&self) -> String {
+        let dates: Vec<String> = CHANGE_DATES.iter().filter(|date| self.schema.has_column("message", date)).map(|date| format!("IFNULL({date}, 0)")).collect();
+        if dates.is_empty() {
+            return "0".to_string();
+        }
+        // MAX with a single argument is the aggregate, not the scalar function
+        format!("MAX(0, {})", dates.join(", "))
+    }
+
+    /// Whether `column` is the first column of a (non partial) index on `table`.
+    fn is_indexed(&self, table: &str, column: &str) -> rusqlite::Result<bool> {
+        self.conn.query_row("SELECT EXISTS (SELECT 1 FROM pragma_index_list(?1) AS l, pragma_index_info(l.name) AS i WHERE l.partial = 0 AND i.seqno = 0 AND i.name = ?2)", [table, column], |row| row.get(0))
+    }
+
+    /// Messages up to ROWID `?1` with a change date after `?2`. SQLite won't use several indexes for one OR of
+    /// ranges, so when every change date is indexed each is searched on its own, otherwise one scan checks them all.
+    fn updated_messages_sql(&self) -> rusqlite::Result<String> {
+        let change_date = self.change_date();
+        let dates: Vec<&str> = CHANGE_DATES.iter().copied().filter(|date| self.schema.has_column("message", date)).collect();
+        let select = |rowid: &str, condition: &str| format!("SELECT ROWID, guid, {change_date} AS change_date FROM message WHERE {rowid} <= ?1 AND {condition}");
+        let mut indexed = !dates.is_empty();
+        for date in &dates {
+            indexed = indexed && self.is_indexed("message", date)?;
+        }
+        let sql = match indexed {
+            // `+` keeps the planner from picking the ROWID range over the date index
+            true => dates.iter().map(|date| select("+ROWID", &format!("{date} > ?2"))).collect::<Vec<_>>().join(" UNION "),
+            false if dates.is_empty() => select("ROWID", "0 > ?2"),
+            false => select("ROWID", &format!("({})", dates.iter().map(|date| format!("{date} > ?2")).collect::<Vec<_>>().join(" OR "))),
+        };
+        Ok(format!("{sql} ORDER BY change_date"))
+    }
+
+    /// Returns every message inserted since the last poll, every older message whose
+    /// read, delivered or edited date moved forward, and every chat read since the last poll.
+    pub fn poll(&mut self) -> rusqlite::Result<Vec<ChangeEvent>> {
+        let watermark = match self.watermark {
+            Some(watermark) => watermark,
+            None => {
+                //apple time starts from 1-1-2001 in nanoseconds 😤
+                let since = unix_to_apple(self.last_read_time) as i64;
+                let rowid = self.conn.query_row("SELECT COALESCE(MAX(ROWID), 0) FROM message WHERE date <= ?", [since], |row| row.get(0))?;
+                Watermark { rowid, change_date: since, chat_read: since }
+            }
+        };
+        let (new_rows, updated_rows, read_chats) = {
+            let change_date = self.change_date();
+            let mut new_stmt = self.conn.prepare(&format!("SELECT ROWID, guid, {change_date} AS change_date FROM message WHERE ROWID > ? ORDER BY ROWID"))?;
+            let new_rows = new_stmt.query_map([watermark.rowid], Watermark::from_row)?.collect::<rusqlite::Result<Vec<_>>>()?;
+            let mut updated_stmt = self.conn.prepare(&self.updated_messages_sql()?)?;
+            let updated_rows = updated_stmt.query_map((watermark.rowid, watermark.change_date), Watermark::from_row)?.collect::<rusqlite::Result<Vec<_>>>()?;
+            let read_chats = if self.schema.capabilities().chat_read_status {
+                let mut read_stmt = self.conn.prepare("SELECT guid, last_read_message_timestamp FROM chat WHERE last_read_message_timestamp > ? ORDER BY last_read_message_timestamp")?;
+                let read_chats = read_stmt.query_map([watermark.chat_read], |row| {
+                    Ok((row.get::<_, String>("guid")?, row.get::<_, i64>("last_read_message_timestamp")?))
+                })?.collect::<rusqlite::Result<Vec<_>>>()?;
+                read_chats
+            } else {
+                vec![]
+            };
+            (new_rows, updated_rows, read_chats)
+        };
+
+        let mut next = watermark;
+        let mut events = Vec::with_capacity(new_rows.len() + updated_rows.len() + read_chats.len());
+        for (guid, seen) in new_rows {
+            next = next.max(seen);
+            if let Some(message) = self.get_message_by_guid(guid, true, true)? {
+                events.push(ChangeEvent::NewMessage(message));
+            }
+        }
+        for (guid, seen) in updated_rows {
+            next = next.max(seen);
+            if let Some(message) = self.get_message_by_guid(guid, true, true)? {
+                events.push(ChangeEvent::UpdatedMessage(message));
+            }
+        }
+        for (chat_guid, read_time) in read_chats {
+            next.chat_read = next.chat_read.max(read_time);
+            events.push(ChangeEvent::ChatReadStatusChanged { chat_guid, read: true });
+        }
+        self.watermark = Some(next);
+        Ok(events)
+    }
+
+    pub fn get_chat_by_guid(&self, guid: String, last_message: bool, participants: bool) -> rusqlite::Result<Option<Chat>> {
+        let mut stmt = self.conn.prepare(&format!("{} WHERE c.guid = ?", self.chat_select()))?;
+        return stmt.query_row([guid], |row| {
+            self.chat_from_row(row, last_message, participants)
+        }).optional();
+    }
+
+    pub fn query_chats(&self, limit: usize, offset: usize, sort: ChatSort, last_message: bool, participants: bool) -> rusqlite::Result<Vec<Chat>> {
+        let order = match sort {
+            ChatSort::RowId => "c.ROWID",
+            // chats without any messages go last, like in Messages.app
+            ChatSort::LastMessage => "last_message_date DESC NULLS LAST, c.ROWID DESC",
+        };
+        let mut stmt = self.conn.prepare(&format!("{} ORDER BY {order} LIMIT ? OFFSET ?", self.chat_select()))?;
+        return stmt.query_map([limit as i64, offset as i64], |row| {
+            self.chat_from_row(row, last_message, participants)
+        })?.collect();
+    }
+
+    /// Builds a chat from a row selected with [`Self::chat_select`].
+    fn chat_from_row(&self, row: &Row, last_message: bool, participants: bool) -> rusqlite::Result<Chat> {
+        let original_rowid = row.get("ROWID")?;
+        let participants = if participants {
+            self.get_chat_participants(original_rowid)?
+        } else {
+            vec![]
+        };
+        let last_message = match row.get::<_, Option<String>>("last_message_guid")? {
+            Some(guid) if last_message => self.get_message_by_guid(guid, true, true)?,
+            _ => None,
+        };
+        Ok(Chat {
+            original_rowid,
+            guid: row.get("guid")?,
+            participants,
+            last_message,
+            style: row.get("style")?,
+            chat_identifier: row.get("chat_identifier")?,
+            is_archived: row.get("is_archived")?,
+            is_filtered: row.get("is_filtered")?,
+            display_name: row.get("display_name")?,
+            group_id: row.get("group_id")?,
+            last_addressed_handle: row.get("last_addressed_handle")?,
+        })
+    }
+
+    pub fn get_chat_service_count(&self) -> rusqlite::Result<ChatCounts> {
+        let mut stmt = self.conn.prepare("SELECT service_name, COUNT(*) AS count FROM chat GROUP BY service_name")?;
+        let mut breakdown = HashMap::from([("iMessage".to_string(), 0), ("SMS".to_string(), 0)]);
+        let mut total = 0;
+        for service in stmt.query_map([], |row| Ok((row.get::<_, Option<String>>("service_name")?, row.get::<_, usize>("count")?)))? {
+            let (service, count) = service?;
+            total += count;
+            if let Some(known) = service.and_then(|service| breakdown.get_mut(&service)) {
+                *known += count;
+            }
+        }
+        Ok(ChatCounts { total, breakdown })
+    }
+
+    pub fn get_count(&self, table: &str) -> rusqlite::Result<usize> {
+        let mut stmt = self.conn.prepare(&format!("SELECT COUNT(*) FROM {table}"))?;
+        stmt.query_row([], |row| row.get::<_, usize>("COUNT(*)"))
+    }
+
+    pub fn get_chat_participants(&self, chat_row_id: u32) -> rusqlite::Result<Vec<Participant>> {
+        let mut stmt = self.conn.prepare("SELECT handle_id FROM chat_handle_join WHERE chat_id = ?")?;
+        let participants = stmt.query_map([chat_row_id], |row| {
+            self.get_participant(row.get("handle_id")?)
+        })?.collect::<rusqlite::Result<Vec<_>>>()?;
+        // the join table can outlive a deleted handle
+        Ok(participants.into_iter().flatten().collect())
+    }
+
+    pub fn get_participant(&self, row_id: u32) -> rusqlite::Result<Option<Participant>> {
+        let mut stmt = self.conn.prepare(&format!("SELECT {} FROM handle AS h WHERE h.ROWID = ?", self.schema.handle_columns()))?;
+        return stmt.query_row([row_id], participant_from_row).optional();
+    }
+
+    /// Handles whose address contains `address` (all of them when it's empty), in the order they were created.
+    pub fn query_handles(&self, address: &str, limit: usize, offset: usize) -> rusqlite::Result<Vec<Participant>> {
+        let mut stmt = self.conn.prepare(&format!("SELECT {} FROM handle AS h WHERE instr(h.id, ?) > 0 ORDER BY h.ROWID LIMIT ? OFFSET ?", self.schema.handle_columns()))?;
+        return stmt.query_map((address, limit as i64, offset as i64), participant_from_row)?.collect();
+    }
+
+    /// An address has one handle per service it was used with, this returns the oldest.
+    pub fn get_handle(&self, address: &str) -> rusqlite::Result<Option<Participant>> {
+        let mut stmt = self.conn.prepare(&format!("SELECT {} FROM handle AS h WHERE h.id = ? ORDER BY h.ROWID LIMIT 1", self.schema.handle_columns()))?;
+        return stmt.query_row([address], participant_from_row).optional();
+    }
+
+    /// Chats any of the handles of `address` is in.
+    pub fn get_handle_chats(&self, address: &str, last_message: bool, participants: bool) -> rusqlite::Result<Vec<Chat>> {
+        let mut stmt = self.conn.prepare(&format!("{} WHERE c.ROWID IN \
+            (SELECT chj.chat_id FROM chat_handle_join AS chj JOIN handle AS h ON h.ROWID = chj.handle_id WHERE h.id = ?) ORDER BY c.ROWID", self.chat_select()))?;
+        return stmt.query_map([address], |row| {
+            self.chat_from_row(row, last_message, participants)
+        })?.collect();
+    }
+
+    pub fn get_message_by_guid(&self, message_guid: String, handle: bool, attachments: bool) -> rusqlite::Result<Option<Message>> {
+        let mut stmt = self.conn.prepare(&format!("SELECT {} FROM message AS m WHERE m.guid = ?", self.schema.message_columns()))?;
+        return stmt.query_row([message_guid.clone()], |row| {
+            let original_rowid = row.get("ROWID")?;
+            // messages are written before they are added to a chat, the poller can see them in between
+            let chat_guid = self.conn.prepare("SELECT c.guid FROM chat_message_join AS cmj JOIN chat AS c ON c.ROWID = cmj.chat_id WHERE cmj.message_id = ?")?.query_row([original_rowid], |row| {
+                row.get::<_, String>("guid")
+            }).optional()?.unwrap_or_default();
+            let attachments = if attachments {
+                self.get_attachments_for_message(original_rowid)?
+            } else {
+                Vec::new()
+            };
+            let handle = if handle { self.get_participant(row.get("handle_id")?)? } else { None };
+            Message::from_row(row, handle, attachments, chat_guid, message_guid, original_rowid)
+        }).optional();
+    }
+
+    /// The reactions currently on a message, oldest first.
+    pub fn get_reactions(&self, message_guid: &str) -> rusqlite::Result<Vec<Reaction>> {
+        Ok(self.get_reactions_for(&[message_guid])?.remove(message_guid).unwrap_or_default())
+    }
+
+    /// The reactions currently on each of these messages, by guid, oldest first. Reaction messages aren't added to the
+    /// target, they point at it through `associated_message_guid`, so this replays every reaction to them.
+    pub fn get_reactions_for(&self, message_guids: &[&str]) -> rusqlite::Result<HashMap<String, Vec<Reaction>>> {
+        if !self.schema.capabilities().reactions || message_guids.is_empty() {
+            return Ok(HashMap::new());
+        }
+        // reactions point at the whole message (`bp:`) or one of its parts (`p:<part>/`), every possible
+        // target is listed so the lookup can use the associated_message_guid index
+        let body_column = if self.schema.capabilities().attributed_body { "m.attributedBody" } else { "NULL AS attributedBody" };
+        let mut parts_stmt = self.conn.prepare(&format!("SELECT m.guid, {body_column}, \
+            (SELECT COUNT(*) FROM message_attachment_join AS maj WHERE maj.message_id = m.ROWID) AS attachment_count \
+            FROM message AS m WHERE m.guid IN (SELECT value FROM json_each(?))"))?;
+        let guids = serde_json::to_string(message_guids).expect("strings always serialize");
+        let mut targets = vec![];
+        for row in parts_stmt.query_map([guids], |row| {
+            // without an attributedBody, assume text before, between and after every attachment
+            let attachment_count: usize = row.get("attachment_count")?;
+            let part_count = attributed_body(row.get("attributedBody")?).map_or(attachment_count * 2 + 1, |body| body.part_count());
+            Ok((row.get::<_, String>("guid")?, part_count.max(1)))
+        })? {
+            let (guid, part_count) = row?;
+            targets.extend((0..part_count).map(|part| format!("p:{part}/{guid}")));
+            targets.push(format!("bp:{guid}"));
+            targets.push(guid);
+        }
+        let mut stmt = self.conn.prepare(&format!("SELECT {} FROM message AS m WHERE m.associated_message_guid IN (SELECT value FROM json_each(?)) \
+            AND m.associated_message_type BETWEEN 2000 AND 3999 ORDER BY m.date, m.ROWID", self.schema.message_columns()))?;
+        let targets = serde_json::to_string(&targets).expect("strings always serialize");
+        let mut by_target: HashMap<String, Vec<Message>> = HashMap::new();
+        for message in stmt.query_map([targets], |row| Message::from_row(row, None, vec![], String::new(), row.get("guid")?, row.get("ROWID")?))? {
+            let message = message?;
+            if let Some(target) = message.tapback.as_ref().map(|tapback| tapback.target_guid.clone()) {
+                by_target.entry(target).or_default().push(message);
+            }
+        }
+        Ok(by_target.into_iter().map(|(guid, messages)| (guid, current_reactions(messages))).collect())
+    }
+
+    pub fn get_attachment_by_guid(&self, attachment_guid: String) -> rusqlite::Result<Option<Attachment>> {
+        let mut stmt = self.conn.prepare(&format!("SELECT {} FROM attachment AS a WHERE a.guid = ?", self.schema.attachment_columns()))?;
+        return stmt.query_row([attachment_guid.clone()], |row| {
+            let mime_type: Option<String> = row.get("mime_type")?;
+            // only images have dimensions, attachments without a mime type might still be one
+            let (width, height) = match row.get::<_, Option<String>>("filename")? {
+                Some(path) if mime_type.as_deref().is_none_or(|mime_type| mime_type.starts_with("image/")) => {
+                    self.dimensions.get(&attachment_guid, &expand_home(&path))?.unzip()
+                },
+                _ => (None, None),
+            };
+
+            Ok(Attachment {
+                original_rowid: row.get("ROWID")?,
+                guid: attachment_guid,
+                uti: row.get("uti").ok(),
+                mime_type,
+                transfer_state: row.get("transfer_state")?,
+                transfer_name: row.get::<_, Option<String>>("transfer_name")?.unwrap_or_default(),
+                total_bytes: row.get("total_bytes")?,
+                is_outgoing: row.get::<&str, i32>("is_outgoing")? == 1,
+                hide_attachment: row.get::<&str, i32>("hide_attachment")? == 1,
+                is_sticker: row.get::<&str, i32>("is_sticker")? == 1,
+                original_guid: row.get("original_guid")?,
+                metadata: Some("{}".to_string()),
+                has_live_photo: false,
+                width,
+                height,
+            })
+        }).optional();
+    }
+
+    pub fn get_attachments_for_message(&self, message_id: u32) -> rusqlite::Result<Vec<Attachment>> {
+        let mut stmt = self.conn.prepare("SELECT a.guid FROM message_attachment_join AS maj JOIN attachment AS a ON a.ROWID = maj.attachment_id WHERE maj.message_id = ?")?;
+        let attachments = stmt.query_map([message_id], |row| {
+            self.get_attachment_by_guid(row.get("guid")?)
+        })?.collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(attachments.into_iter().flatten().collect())
+    }
+
+    /// `None` if the attachment doesn't exist.
+    pub fn get_attachment_file(&self, guid: String) -> rusqlite::Result<Option<AttachmentFile>> {
+        let mut stmt = self.conn.prepare(&format!("SELECT {} FROM attachment AS a WHERE a.guid = ?", self.schema.attachment_columns()))?;
+        stmt.query_row([guid], |row| {
+            Ok(AttachmentFile {
+                path: row.get("filename")?,
+                mime_type: row.get("mime_type")?,
+                transfer_name: row.get("transfer_name")?,
+                transfer_state: row.get::<_, Option<i64>>("transfer_state")?.unwrap_or(0),
+            })
+        }).optional()
+    }
+
+    /// Returns one page of the messages in a chat, or `None` if the chat doesn't exist.
+    /// Ordering, paging and the date range are all applied by SQLite so only the page itself is loaded.
+    pub fn get_chat_messages(&self, chat_guid: String, query: &MessageQuery) -> rusqlite::Result<Option<MessagePage>> {
+        let Some(chat_id) = self.conn.prepare("SELECT ROWID FROM chat WHERE guid = ?")?.query_row([chat_guid.clone()], |row| {
+            row.get::<_, i64>("ROWID")
+        }).optional()? else {
+            return Ok(None);
+        };
+        return self.messages_page(Some((chat_id, chat_guid)), query).map(Some);
+    }
+
+    /// One page of the thread a message is part of: the message that started it followed by its inline replies,
+    /// whichever of them `message_guid` is. `None` if the message doesn't exist.
+    pub fn get_thread(&self, message_guid: &str, query: &MessageQuery) -> rusqlite::Result<Option<MessagePage>> {
+        let originator = if self.schema.capabilities().threads { "COALESCE(thread_originator_guid, guid)" } else { "guid" };
+        let Some(originator_guid) = self.conn.prepare(&format!("SELECT {originator} FROM message WHERE guid = ?"))?.query_row([message_guid], |row| {
+            row.get::<_, String>(0)
+        }).optional()? else {
+            return Ok(None);
+        };
+        let mut query = query.clone();
+        query.filters.push(MessageFilter::Thread(originator_guid));
+        return self.messages_page(None, &query).map(Some);
+    }
+
+    /// Number of inline replies to a message.
+    pub fn get_reply_count(&self, message_guid: &str) -> rusqlite::Result<u32> {
+        if !self.schema.capabilities().threads {
+            return Ok(0);
+        }
+        self.conn.query_row("SELECT COUNT(*) FROM message WHERE thread_originator_guid = ?", [message_guid], |row| row.get(0))
+    }
+
+    /// Texts of up to `limit` messages after `rowid`, oldest first.
+    pub fn get_message_texts(&self, after_rowid: i64, limit: usize) -> rusqlite::Result<Vec<MessageText>> {
+        self.message_texts("m.ROWID > ? ORDER BY m.ROWID LIMIT ?", [after_rowid, limit as i64])
+    }
+
+    /// Texts of the messages with these `ROWID`s, messages that were deleted are left out.
+    pub fn get_message_texts_by_rowid(&self, rowids: &[i64]) -> rusqlite::Result<Vec<MessageText>> {
+        let rowids = serde_json::to_string(rowids).expect("integers always serialize");
+        self.message_texts("m.ROWID IN (SELECT value FROM json_each(?)) ORDER BY m.ROWID", [rowids])
+    }
+
+    fn message_texts(&self, condition: &str, params: impl rusqlite::Params) -> rusqlite::Result<Vec<MessageText>> {
+        let body_column = if self.schema.capabilities().attributed_body { "m.attributedBody" } else { "NULL AS attributedBody" };
+        let mut stmt = self.conn.prepare(&format!("SELECT m.ROWID, m.guid, m.text, {body_column}, \
+            COALESCE((SELECT c.guid FROM chat_message_join AS cmj JOIN chat AS c ON c.ROWID = cmj.chat_id WHERE cmj.message_id = m.ROWID), '') AS chat_guid \
+            FROM message AS m WHERE {condition}"))?;
+        return stmt.query_map(params, |row| {
+            Ok(MessageText {
+                rowid: row.get("ROWID")?,
+                guid: row.get("guid")?,
+                chat_guid: row.get("chat_guid")?,
+                text: row.get::<_, Option<String>>("text")?.or_else(|| attributed_body(row.get("attributedBody").ok().flatten()).map(|body| body.string)),
+            })
+        })?.collect();
+    }
+
+    /// Returns one page of messages across all chats, sorted by the date they were sent.
+    pub fn query_messages(&self, query: &MessageQuery) -> rusqlite::Result<MessagePage> {
+        self.messages_page(None, query)
+    }
+
+    fn messages_page(&self, chat: Option<(i64, String)>, query: &MessageQuery) -> rusqlite::Result<MessagePage> {
+        let (direction, cursor_comparison) = match query.sort {
+            SortOrder::Asc => ("ASC", ">"),
+            SortOrder::Desc => ("DESC", "<"),
+        };
+        // within a chat messages are ordered by the date they were added to it, across chats by the date they were sent
+        let (from, date, chat_guid) = match chat {
+            Some(_) => ("chat_message_join AS cmj JOIN message AS m ON cmj.message_id = m.ROWID", "cmj.message_date", ":chat_guid"),
+            None => ("message AS m", "m.date", "COALESCE((SELECT c.guid FROM chat_message_join AS cmj JOIN chat AS c ON c.ROWID = cmj.chat_id WHERE cmj.message_id = m.ROWID), '')"),
+        };
+        let mut conditions = vec![
+            format!("{date} > :after"),
+            format!("{date} < :before"),
+            format!("(:cursor_rowid IS NULL OR ({date}, m.ROWID) {cursor_comparison} (:cursor_date, :cursor_rowid))"),
+        ];
+        let mut params: Vec<(String, SqlValue)> = vec![
+            (":after".into(), clamp_date(query.after).into()),
+            (":before".into(), clamp_date(query.before).into()),
+            (":cursor_date".into(), query.cursor.map(|cursor| cursor.date).into()),
+            (":cursor_rowid".into(), query.cursor.map(|cursor| cursor.rowid).into()),
+            (":limit".into(), (query.limit as i64).into()),
+            (":offset".into(), (query.offset as i64).into()),
+        ];
+        if let Some((chat_id, chat_guid)) = chat {
+            conditions.push("cmj.chat_id = :chat_id".into());
+            params.push((":chat_id".into(), chat_id.into()));
+            params.push((":chat_guid".into(), chat_guid.into()));
+        }
+        for (i, filter) in query.filters.iter().enumerate() {
+            let param = format!(":filter{i}");
+            let (condition, value) = filter.condition(&param, &self.schema);
+            conditions.push(condition);
+            params.push((param, value));
+        }
+        let reply_count = match (query.reply_counts, self.schema.capabilities().threads) {
+            (true, true) => "(SELECT COUNT(*) FROM message AS r WHERE r.thread_originator_guid = m.guid)",
+            (true, false) => "0",
+            (false, _) => "NULL",
+        };
+        let mut stmt = self.conn.prepare(&format!("SELECT {}, {date} AS cursor_date, {chat_guid} AS message_chat_guid, {reply_count} AS reply_count FROM {from} \
+            WHERE {} ORDER BY {date} {direction}, m.ROWID {direction} LIMIT :limit OFFSET :offset", self.schema.message_columns(), conditions.join(" AND ")))?;
+        let params: Vec<(&str, &dyn ToSql)> = params.iter().map(|(name, value)| (name.as_str(), value as &dyn ToSql)).collect();
+        let mut next_cursor = None;
+        let mut messages = stmt.query_map(params.as_slice(), |row| {
+            let original_rowid = row.get("ROWID")?;
+            next_cursor = Some(MessageCursor { date: row.get("cursor_date")?, rowid: original_rowid as i64 });
+            let attachments = if query.attachments {
+                self.get_attachments_for_message(original_rowid)?
+            } else {
+                vec![]
+            };
+            let handle = if query.handle { self.get_participant(row.get("handle_id")?)? } else { None };
+            let mut message = Message::from_row(row, handle, attachments, row.get("message_chat_guid")?, row.get("guid")?, original_rowid)?;
+            message.reply_count = row.get("reply_count")?;
+            Ok(message)
+        })?.collect::<rusqlite::Result<Vec<Message>>>()?;
+        if query.reactions {
+            // one lookup for the whole page
+            let mut reactions = self.get_reactions_for(&messages.iter().map(|message| message.guid.as_str()).collect::<Vec<_>>())?;
+            for message in &mut messages {
+                message.reactions = reactions.remove(&message.guid).unwrap_or_default();
+            }
+        }
+        tracing::debug!("returning {} messages", messages.len());
+        // a short page means there is nothing left to fetch
+        if messages.len() < query.limit {
+            next_cursor = None;
+        }
+        Ok(MessagePage { messages, next_cursor })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ChangeEvent, ChatSort, Database, DatabaseOptions, MessageCursor, MessageFilter, MessageQuery, OpenMode, SortOrder, CHANGE_DATES, FIXTURE_SCHEMA};
+    use crate::{api::MessageEvent, structs::{Chat, Message, Participant, ReactionKind}, util::apple_to_unix};
+
+    // 2023-01-01 in apple time
+    const BASE_DATE: i64 = 694_224_000_000_000_000;
+
+    fn seeded() -> Database {
+        seeded_with(DatabaseOptions::default())
+    }
+
+    fn seeded_with(options: DatabaseOptions) -> Database {
+        let db = Database::open_fixture(options).unwrap();
+        db.conn.execute_batch(&format!("
+            INSERT INTO handle (ROWID, id, country, service) VALUES (1, '+15555550100', 'us', 'iMessage'), (2, 'friend@example.com', 'us', 'iMessage');
+            INSERT INTO chat (ROWID, guid, style, chat_identifier, service_name, display_name, group_id, last_addressed_handle)
+                VALUES (1, 'iMessage;-;+15555550100', 45, '+15555550100', 'iMessage', NULL, 'group-1', '+15555550199'),
+                       (2, 'SMS;+;chat1234', 43, 'chat1234', 'SMS', 'Friends', 'group-2', '+15555550199');
+            INSERT INTO chat_handle_join (chat_id, handle_id) VALUES (1, 1), (2, 1), (2, 2);
+            INSERT INTO message (ROWID, guid, text, handle_id, date, is_from_me) VALUES
+                (1, 'message-1', 'hello', 1, {d1}, 0),
+                (2, 'message-2', 'hi there', 0, {d2}, 1),
+                (3, 'message-3', 'group hello', 2, {d3}, 0);
+            INSERT INTO chat_message_join (chat_id, message_id, message_date) VALUES (1, 1, {d1}), (1, 2, {d2}), (2, 3, {d3});
+            INSERT INTO attachment (ROWID, guid, filename, mime_type, transfer_name, total_bytes, original_guid)
+                VALUES (1, 'attachment-1', '~/Library/Messages/Attachments/00/00/attachment-1/photo.jpeg', 'image/jpeg', 'photo.jpeg', 1024, 'attachment-1');
+            INSERT INTO message_attachment_join (message_id, attachment_id) VALUES (2, 1);
+        ", d1 = BASE_DATE, d2 = BASE_DATE + 60_000_000_000, d3 = BASE_DATE + 120_000_000_000)).unwrap();
+        db
+    }
+
+    #[test]
+    fn test_fixture_schema() {
+        let db = Database::open_fixture(DatabaseOptions::default()).unwrap();
+        for table in ["chat", "message", "handle", "attachment", "chat_handle_join", "chat_message_join", "message_attachment_join"] {
+            assert_eq!(db.get_count(table).unwrap(), 0);
+        }
+    }
+
+    #[test]
+    fn test_open_modes() {
+        let path = std::env::temp_dir().join(format!("bluebubbles-open-modes-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        rusqlite::Connection::open(&path).unwrap().execute_batch(FIXTURE_SCHEMA).unwrap();
+
+        for mode in [OpenMode::ReadWrite, OpenMode::ReadOnly, OpenMode::Immutable] {
+            let db = Database::open(&path, DatabaseOptions { mode, ..Default::default() }).unwrap();
+            assert_eq!(db.get_count("message").unwrap(), 0);
+            let write = db.conn.execute("INSERT INTO handle (id, service) VALUES ('a', 'iMessage')", []);
+            assert_eq!(write.is_ok(), mode == OpenMode::ReadWrite, "{mode:?}");
+        }
+        assert!(Database::open(path.with_extension("missing"), DatabaseOptions::default()).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_open_mode_from_str() {
+        assert_eq!("immutable".parse::<OpenMode>(), Ok(OpenMode::Immutable));
+        assert_eq!("Read-Only".parse::<OpenMode>(), Ok(OpenMode::ReadOnly));
+        assert!("sometimes".parse::<OpenMode>().is_err());
+    }
+
+    #[test]
+    fn test_chat_queries() {
+        let db = seeded();
+        let chat = db.get_chat_by_guid("SMS;+;chat1234".into(), true, true).unwrap().unwrap();
+        assert_eq!(chat.display_name.as_deref(), Some("Friends"));
+        assert_eq!(chat.participants.len(), 2);
+        assert_eq!(chat.last_message.map(|message| message.guid), Some("message-3".to_string()));
+        assert!(db.get_chat_by_guid("missing".into(), false, false).unwrap().is_none());
+        let last_message = db.get_chat_by_guid("iMessage;-;+15555550100".into(), true, false).unwrap().unwrap().last_message;
+        assert_eq!(last_message.map(|message| message.guid), Some("message-2".to_string()));
+
+        assert_eq!(db.query_chats(10, 0, ChatSort::RowId, false, true).unwrap().len(), 2);
+        assert_eq!(db.query_chats(10, 1, ChatSort::RowId, false, false).unwrap().len(), 1);
+        assert!(db.query_chats(10, 5, ChatSort::RowId, false, false).unwrap().is_empty());
+
+        let counts = db.get_chat_service_count().unwrap();
+        assert_eq!(counts.total, 2);
+        assert_eq!(counts.breakdown["iMessage"], 1);
+        assert_eq!(counts.breakdown["SMS"], 1);
+    }
+
+    #[test]
+    fn test_handles() {
+        let db = seeded();
+        db.conn.execute_batch("
+            INSERT INTO handle (ROWID, id, country, service) VALUES (3, '+15555550100', 'us', 'SMS'), (4, 'nobody@example.com', NULL, 'iMessage');
+            INSERT INTO chat (ROWID, guid, style, chat_identifier, group_id, last_addressed_handle) VALUES (3, 'SMS;-;+15555550100', 45, '+15555550100', 'group-3', '');
+            INSERT INTO chat_handle_join (chat_id, handle_id) VALUES (3, 3);
+        ").unwrap();
+
+        let addresses = |handles: Vec<Participant>| handles.into_iter().map(|handle| format!("{} {}", handle.address, handle.service)).collect::<Vec<_>>();
+        assert_eq!(addresses(db.query_handles("", 10, 0).unwrap()), ["+15555550100 iMessage", "friend@example.com iMessage", "+15555550100 SMS", "nobody@example.com iMessage"]);
+        assert_eq!(addresses(db.query_handles("example.com", 10, 1).unwrap()), ["nobody@example.com iMessage"]);
+        assert!(db.query_handles("%", 10, 0).unwrap().is_empty());
+
+        let handle = db.get_handle("+15555550100").unwrap().unwrap();
+        assert_eq!((handle.original_rowid, handle.service.as_str()), (1, "iMessage"));
+        assert_eq!(db.get_handle("nobody@example.com").unwrap().unwrap().country, "");
+        assert!(db.get_handle("missing").unwrap().is_none());
+
+        let chats: Vec<_> = db.get_handle_chats("+15555550100", false, false).unwrap().into_iter().map(|chat| chat.guid).collect();
+        assert_eq!(chats, ["iMessage;-;+15555550100", "SMS;+;chat1234", "SMS;-;+15555550100"]);
+        assert!(db.get_handle_chats("nobody@example.com", false, false).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_old_schema() {
+        let path = std::env::temp_dir().join(format!("bluebubbles-old-schema-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        rusqlite::Connection::open(&path).unwrap().execute_batch("
+            CREATE TABLE handle (ROWID INTEGER PRIMARY KEY AUTOINCREMENT, id TEXT NOT NULL, service TEXT NOT NULL);
+            CREATE TABLE chat (ROWID INTEGER PRIMARY KEY AUTOINCREMENT, guid TEXT UNIQUE NOT NULL, style INTEGER, chat_identifier TEXT, service_name TEXT, display_name TEXT);
+            CREATE TABLE message (ROWID INTEGER PRIMARY KEY AUTOINCREMENT, guid TEXT UNIQUE NOT NULL, text TEXT, handle_id INTEGER DEFAULT 0, date INTEGER, date_read INTEGER, is_from_me INTEGER DEFAULT 0);
+            CREATE TABLE attachment (ROWID INTEGER PRIMARY KEY AUTOINCREMENT, guid TEXT UNIQUE NOT NULL, filename TEXT, mime_type TEXT, transfer_name TEXT, total_bytes INTEGER DEFAULT 0);
+            CREATE TABLE chat_handle_join (chat_id INTEGER, handle_id INTEGER);
+            CREATE TABLE chat_message_join (chat_id INTEGER, message_id INTEGER, message_date INTEGER DEFAULT 0);
+            CREATE TABLE message_attachment_join (message_id INTEGER, attachment_id INTEGER);
+            INSERT INTO handle (ROWID, id, service) VALUES (1, '+15555550100', 'iMessage');
+            INSERT INTO chat (ROWID, guid, style, chat_identifier, service_name) VALUES (1, 'iMessage;-;+15555550100', 45, '+15555550100', 'iMessage');
+            INSERT INTO chat_handle_join VALUES (1, 1);
+            INSERT INTO message (ROWID, guid, text, handle_id, date) VALUES (1, 'message-1', 'hello from 2015', 1, 1), (2, 'message-2', 'hi', 0, 2);
+            INSERT INTO chat_message_join VALUES (1, 1, 1), (1, 2, 2);
+            INSERT INTO attachment (ROWID, guid, filename, mime_type, transfer_name) VALUES (1, 'attachment-1', NULL, 'image/png', 'a.png');
+            INSERT INTO message_attachment_join VALUES (2, 1);
+        ").unwrap();
+        let mut db = Database::open(&path, DatabaseOptions::default()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(db.schema().version(), crate::schema::SchemaVersion::Legacy);
+
+        let chat = db.get_chat_by_guid("iMessage;-;+15555550100".into(), true, true).unwrap().unwrap();
+        assert_eq!((chat.participants.len(), chat.group_id.as_str()), (1, ""));
+        let message = chat.last_message.unwrap();
+        assert_eq!((message.guid.as_str(), message.attachments[0].original_guid.as_str()), ("message-2", "attachment-1"));
+        assert!(!message.is_spam && message.date_edited.is_none());
+
+        let query = MessageQuery { reactions: true, reply_counts: true, ..Default::default() };
+        let page = db.get_chat_messages("iMessage;-;+15555550100".into(), &query).unwrap().unwrap();
+        assert_eq!(page.messages.iter().map(|message| message.reply_count).collect::<Vec<_>>(), [Some(0), Some(0)]);
+        assert_eq!(message_guids(&db.get_thread("message-1", &query).unwrap().unwrap().messages), ["message-1"]);
+        assert_eq!(guids(&db.poll().unwrap()), ["new message-1", "new message-2"]);
+        assert_eq!(db.get_message_texts(0, 10).unwrap()[0].text.as_deref(), Some("hello from 2015"));
+        assert_eq!(db.get_handle("+15555550100").unwrap().unwrap().country, "");
+    }
+
+    #[test]
+    fn test_unexpected_rows_are_errors() {
+        let db = seeded();
+        db.conn.execute("INSERT INTO chat (guid, style, chat_identifier, group_id, last_addressed_handle) VALUES ('broken', NULL, 'broken', 'group-4', '')", []).unwrap();
+        assert!(db.get_chat_by_guid("broken".into(), false, false).is_err());
+        assert!(db.query_chats(10, 0, ChatSort::RowId, false, false).is_err());
+    }
+
+    #[test]
+    fn test_message_queries() {
+        let db = seeded();
+        let message = db.get_message_by_guid("message-2".into(), true, true).unwrap().unwrap();
+        assert_eq!(message.chat_guid, "iMessage;-;+15555550100");
+        assert!(message.is_from_me);
+        assert!(message.handle.is_none());
+        assert_eq!(message.attachments.len(), 1);
+        assert_eq!(message.attachments[0].transfer_name, "photo.jpeg");
+
+        let messages = db.get_chat_messages("iMessage;-;+15555550100".into(), &MessageQuery::default()).unwrap().unwrap().messages;
+        assert_eq!(message_guids(&messages), ["message-2", "message-1"]);
+        assert_eq!(messages[1].handle.as_ref().map(|handle| handle.address.as_str()), Some("+15555550100"));
+        assert!(db.get_chat_messages("missing".into(), &MessageQuery::default()).unwrap().is_none());
+
+        let file = db.get_attachment_file("attachment-1".into()).unwrap().unwrap();
+        assert_eq!(file.path.as_deref(), Some("~/Library/Messages/Attachments/00/00/attachment-1/photo.jpeg"));
+        assert_eq!(file.transfer_name.as_deref(), Some("photo.jpeg"));
+        assert!(db.get_attachment_by_guid("missing".into()).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_attributed_body_text() {
+        let db = seeded();
+        // "Hello" archived as an NSAttributedString, the way newer versions of macOS store all texts
+        let body = [
+            &b"\x04\x0bstreamtyped\x81\xe8\x03\x84\x01@\x84\x84\x84\x12NSAttributedString\x00\x84\x84\x08NSObject\x00\x85"[..],
+            b"\x92\x84\x84\x84\x08NSString\x01\x94\x84\x01+\x05Hello\x86\x84\x02iI\x01\x05\x92\x84\x84\x84\x0cNSDictionary\x00\x94\x84\x01i\x01",
+            b"\x92\x84\x96\x96\x1d__kIMMessagePartAttributeName\x86\x92\x84\x84\x84\x08NSNumber\x00\x84\x84\x07NSValue\x00\x94\x84\x01*\x84\x99\x99\x00\x86\x86\x86",
+        ].concat();
+        db.conn.execute("INSERT INTO message (ROWID, guid, text, attributedBody, handle_id, date) VALUES (4, 'message-4', NULL, ?, 1, 0), (5, 'message-5', NULL, X'0102', 1, 0)", [body]).unwrap();
+
+        let message = db.get_message_by_guid("message-4".into(), false, false).unwrap().unwrap();
+        assert_eq!(message.text.as_deref(), Some("Hello"));
+        assert_eq!(message.attributed_body[0].runs[0].range, [0, 5]);
+        // garbage is skipped instead of failing the whole row
+        let message = db.get_message_by_guid("message-5".into(), false, false).unwrap().unwrap();
+        assert!(message.text.is_none() && message.attributed_body.is_empty());
+        let texts: Vec<_> = db.get_message_texts(3, 10).unwrap().into_iter().map(|text| text.text).collect();
+        assert_eq!(texts, [Some("Hello".to_string()), None]);
+        // plain text rows have no body at all
+        assert!(db.get_message_by_guid("message-1".into(), false, false).unwrap().unwrap().attributed_body.is_empty());
+    }
+
+    #[test]
+    fn test_reactions() {
+        let db = seeded();
+        db.conn.execute_batch(&format!("
+            INSERT INTO message (ROWID, guid, text, handle_id, is_from_me, date, associated_message_guid, associated_message_type, associated_message_emoji) VALUES
+                (4, 'reaction-1', 'Loved “hello”', 1, 0, {d} + 1, 'p:0/message-1', 2000, NULL),
+                (5, 'reaction-2', 'Liked “hello”', 0, 1, {d} + 2, 'p:0/message-1', 2001, NULL),
+                (6, 'reaction-3', 'Laughed at “hello”', 1, 0, {d} + 3, 'p:0/message-1', 2003, NULL),
+                (7, 'reaction-4', 'Removed a like from “hello”', 0, 1, {d} + 4, 'p:0/message-1', 3001, NULL),
+                (8, 'reaction-5', 'Reacted 🎉 to “hello”', 2, 0, {d} + 5, 'p:1/message-1', 2006, '🎉'),
+                (9, 'reaction-6', 'Emphasized a link', 2, 0, {d} + 6, 'bp:message-2', 2004, NULL),
+                (10, 'reply-1', 'not a reaction', 2, 0, {d} + 7, 'p:0/message-1', 1000, NULL),
+                (11, 'reaction-7', 'Liked “hello”', 2, 0, {d} + 8, 'p:0/MESSAGE-1', 2001, NULL);
+            INSERT INTO chat_message_join (chat_id, message_id, message_date) VALUES (1, 4, {d} + 1), (1, 5, {d} + 2), (1, 6, {d} + 3), (1, 7, {d} + 4);
+            -- a photo after the text, so message-1 has more than one part
+            INSERT INTO message_attachment_join (message_id, attachment_id) VALUES (1, 1);
+        ", d = BASE_DATE + 60_000_000_000)).unwrap();
+
+        let reaction = db.get_message_by_guid("reaction-5".into(), false, false).unwrap().unwrap();
+        let tapback = reaction.tapback.unwrap();
+        assert_eq!((tapback.kind, tapback.emoji.as_deref(), tapback.removed, tapback.target_guid.as_str(), tapback.part_index), (ReactionKind::Emoji, Some("🎉"), false, "message-1", 1));
+        assert_eq!(reaction.associated_message_type, Some(2006));
+        assert!(db.get_message_by_guid("reaction-4".into(), false, false).unwrap().unwrap().tapback.unwrap().removed);
+        assert!(db.get_message_by_guid("reply-1".into(), false, false).unwrap().unwrap().tapback.is_none());
+        assert!(db.get_message_by_guid("message-1".into(), false, false).unwrap().unwrap().associated_message_type.is_none());
+
+        // the laugh replaced the love, the like was taken back
+        let reactions = db.get_reactions("message-1").unwrap();
+        let summary: Vec<_> = reactions.iter().map(|reaction| (reaction.guid.as_str(), reaction.kind, reaction.part_index, reaction.handle_id)).collect();
+        assert_eq!(summary, [("reaction-3", ReactionKind::Laugh, 0, 1), ("reaction-5", ReactionKind::Emoji, 1, 2)]);
+        assert_eq!(db.get_reactions("message-2").unwrap()[0].kind, ReactionKind::Emphasize);
+        assert!(db.get_reactions("message-3").unwrap().is_empty());
+
+        let query = MessageQuery { reactions: true, ..Default::default() };
+        let page = db.get_chat_messages("iMessage;-;+15555550100".into(), &query).unwrap().unwrap();
+        let message = page.messages.iter().find(|message| message.guid == "message-1").unwrap();
+        assert_eq!(message.reactions, reactions);
+        assert!(db.query_messages(&MessageQuery::default()).unwrap().messages.iter().all(|message| message.reactions.is_empty()));
+
+        let by_guid = db.get_reactions_for(&["message-1", "message-2", "message-3"]).unwrap();
+        assert_eq!((by_guid["message-1"].len(), by_guid["message-2"].len(), by_guid.get("message-3")), (2, 1, None));
+        let plan: String = db.conn.query_row("EXPLAIN QUERY PLAN SELECT ROWID FROM message AS m WHERE m.associated_message_guid IN (SELECT value FROM json_each(?))", ["[]"], |row| row.get(3)).unwrap();
+        assert!(plan.contains("message_idx_associated_message2"), "{plan}");
+    }
+
+    #[test]
+    fn test_thread() {
+        let db = seeded();
+        db.conn.execute_batch(&format!("
+            INSERT INTO message (ROWID, guid, text, handle_id, date, thread_originator_guid, thread_originator_part) VALUES
+                (4, 'reply-1', 'first reply', 0, {d} + 2, 'message-1', '0:0:5'),
+                (5, 'reply-2', 'second reply', 1, {d} + 1, 'message-1', '0:0:5'),
+                (6, 'reply-3', 'elsewhere', 2, {d} + 3, 'message-3', '0:0:11');
+            INSERT INTO chat_message_join (chat_id, message_id, message_date) VALUES (1, 4, {d} + 2), (1, 5, {d} + 1), (2, 6, {d} + 3);
+        ", d = BASE_DATE + 600_000_000_000)).unwrap();
+
+        let query = MessageQuery { sort: SortOrder::Asc, ..Default::default() };
+        let thread = db.get_thread("message-1", &query).unwrap().unwrap();
+        assert_eq!(message_guids(&thread.messages), ["message-1", "reply-2", "reply-1"]);
+        assert_eq!(thread.messages[1].chat_guid, "iMessage;-;+15555550100");
+        // asking for a reply gives the whole thread
+        assert_eq!(message_guids(&db.get_thread("reply-1", &query).unwrap().unwrap().messages), ["message-1", "reply-2", "reply-1"]);
+        assert_eq!(message_guids(&db.get_thread("message-2", &query).unwrap().unwrap().messages), ["message-2"]);
+        assert!(db.get_thread("missing", &query).unwrap().is_none());
+        let page = db.get_thread("message-1", &MessageQuery { limit: 2, ..query.clone() }).unwrap().unwrap();
+        let rest = db.get_thread("message-1", &MessageQuery { cursor: page.next_cursor, ..query }).unwrap().unwrap();
+        assert_eq!(message_guids(&rest.messages), ["reply-1"]);
+
+        let counts = |query: &MessageQuery| {
+            let messages = db.query_messages(query).unwrap().messages;
+            messages.into_iter().map(|message| (message.guid, message.reply_count)).filter(|(guid, _)| guid.starts_with("message")).collect::<Vec<_>>()
+        };
+        let query = MessageQuery { reply_counts: true, sort: SortOrder::Asc, ..Default::default() };
+        assert_eq!(counts(&query), [("message-1".into(), Some(2)), ("message-2".into(), Some(0)), ("message-3".into(), Some(1))]);
+        assert!(counts(&MessageQuery::default()).iter().all(|(_, count)| count.is_none()));
+        assert_eq!(db.get_reply_count("message-3").unwrap(), 1);
+    }
+
+    #[test]
+    fn test_json_round_trip() {
+        let db = seeded();
+        let chat = db.get_chat_by_guid("iMessage;-;+15555550100".into(), true, true).unwrap().unwrap();
+        let json = serde_json::to_string(&chat).unwrap();
+        let parsed: Chat = serde_json::from_str(&json).unwrap();
+        assert_eq!(serde_json::to_string(&parsed).unwrap(), json);
+        assert_eq!(parsed.last_message.as_ref().unwrap().guid, "message-2");
+        // only known server side, clients never see it
+        assert!(!json.contains("chatGuid") && !json.contains("chat_guid"));
+
+        let message = db.get_message_by_guid("message-1".into(), true, true).unwrap().unwrap();
+        let event = serde_json::to_value(MessageEvent { message, chats: vec![parsed] }).unwrap();
+        let parsed: MessageEvent = serde_json::from_value(event.clone()).unwrap();
+        assert_eq!(serde_json::to_value(parsed).unwrap(), event);
+        let parsed: MessageEvent = serde_json::from_str(&event.to_string()).unwrap();
+        assert_eq!((parsed.message.guid.as_str(), parsed.chats.len()), ("message-1", 1));
+    }
+
+    fn guids(changes: &[ChangeEvent]) -> Vec<String> {
+        changes.iter().map(|change| match change {
+            ChangeEvent::NewMessage(message) => format!("new {}", message.guid),
+            ChangeEvent::UpdatedMessage(message) => format!("updated {}", message.guid),
+            ChangeEvent::ChatReadStatusChanged { chat_guid, read } => format!("read {chat_guid} {read}"),
+        }).collect()
+    }
+
+    #[test]
+    fn test_poll_without_change_dates() {
+        let path = std::env::temp_dir().join(format!("bluebubbles-no-change-dates-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        rusqlite::Connection::open(&path).unwrap().execute_batch("
+            CREATE TABLE handle (ROWID INTEGER PRIMARY KEY AUTOINCREMENT, id TEXT NOT NULL, service TEXT NOT NULL);
+            CREATE TABLE chat (ROWID INTEGER PRIMARY KEY AUTOINCREMENT, guid TEXT UNIQUE NOT NULL, style INTEGER, chat_identifier TEXT, service_name TEXT);
+            CREATE TABLE message (ROWID INTEGER PRIMARY KEY AUTOINCREMENT, guid TEXT UNIQUE NOT NULL, text TEXT, handle_id INTEGER DEFAULT 0, date INTEGER);
+            CREATE TABLE attachment (ROWID INTEGER PRIMARY KEY AUTOINCREMENT, guid TEXT UNIQUE NOT NULL, filename TEXT);
+            CREATE TABLE chat_handle_join (chat_id INTEGER, handle_id INTEGER);
+            CREATE TABLE chat_message_join (chat_id INTEGER, message_id INTEGER, message_date INTEGER DEFAULT 0);
+            CREATE TABLE message_attachment_join (message_id INTEGER, attachment_id INTEGER);
+            INSERT INTO message (ROWID, guid, text, date) VALUES (1, 'message-1', 'hello', 1);
+        ").unwrap();
+        let mut db = Database::open(&path, DatabaseOptions::default()).unwrap();
+        assert!(CHANGE_DATES.iter().all(|date| !db.schema().has_column("message", date)));
+        assert_eq!(guids(&db.poll().unwrap()), ["new message-1"]);
+        assert!(db.poll().unwrap().is_empty());
+
+        rusqlite::Connection::open(&path).unwrap().execute("INSERT INTO message (ROWID, guid, text, date) VALUES (2, 'message-2', 'later', 2)", []).unwrap();
+        assert_eq!(guids(&db.poll().unwrap()), ["new message-2"]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_poll() {
+        let mut db = seeded();
+        assert_eq!(guids(&db.poll().unwrap()), ["new message-1", "new message-2", "new message-3"]);
+        assert!(db.poll().unwrap().is_empty());
+
+        db.conn.execute_batch(&format!("
+            INSERT INTO message (ROWID, guid, text, handle_id, date, date_delivered) VALUES (4, 'message-4', 'later', 1, {d4}, {d4});
+            INSERT INTO chat_message_join (chat_id, message_id, message_date) VALUES (1, 4, {d4});
+            UPDATE message SET date_read = {d4} WHERE guid = 'message-2';
+        ", d4 = BASE_DATE + 180_000_000_000)).unwrap();
+        assert_eq!(guids(&db.poll().unwrap()), ["new message-4", "updated message-2"]);
+        assert!(db.poll().unwrap().is_empty());
+
+        db.conn.execute("UPDATE message SET date_edited = ? WHERE guid = 'message-4'", [BASE_DATE + 240_000_000_000]).unwrap();
+        assert_eq!(guids(&db.poll().unwrap()), ["updated message-4"]);
+
+        db.conn.execute("UPDATE chat SET last_read_message_timestamp = ? WHERE ROWID = 1", [BASE_DATE + 240_000_000_000]).unwrap();
+        assert_eq!(guids(&db.poll().unwrap()), ["read iMessage;-;+15555550100 true"]);
+        assert!(db.poll().unwrap().is_empty());
+
+        // without indexes on the change dates one scan checks them all, with them every date is searched on its own
+        let plan = |db: &Database| -> Vec<String> {
+            db.conn.prepare(&format!("EXPLAIN QUERY PLAN {}", db.updated_messages_sql().unwrap())).unwrap()
+                .query_map((0, 0), |row| row.get(3)).unwrap().collect::<rusqlite::Result<_>>().unwrap()
+        };
+        assert_eq!(plan(&db).iter().filter(|step| step.contains("message")).count(), 1, "{:?}", plan(&db));
+        for date in CHANGE_DATES {
+            db.conn.execute(&format!("CREATE INDEX message_idx_{date} ON message ({date})"), []).unwrap();
+        }
+        let steps = plan(&db);
+        assert!(CHANGE_DATES.iter().all(|date| steps.iter().any(|step| step.contains(&format!("USING INDEX message_idx_{date}")))), "{steps:?}");
+        assert!(!steps.iter().any(|step| step.starts_with("SCAN")), "{steps:?}");
+        db.conn.execute("UPDATE message SET date_read = ? WHERE guid = 'message-3'", [BASE_DATE + 300_000_000_000]).unwrap();
+        assert_eq!(guids(&db.poll().unwrap()), ["updated message-3"]);
+        assert!(db.poll().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_edits_and_unsends() {
+        let mut db = seeded();
+        db.poll().unwrap();
+        let info = |info: plist::Dictionary| {
+            let mut bytes = vec![];
+            plist::to_writer_binary(&mut bytes, &plist::Value::Dictionary(info)).unwrap();
+            bytes
+        };
+        let edited = info(plist::Dictionary::from_iter([("ec", plist::Value::Dictionary(plist::Dictionary::from_iter([
+            ("0", plist::Value::Array(vec![plist::Value::Dictionary(plist::Dictionary::from_iter([("d", plist::Value::Real(700_000_000.0))]))])),
+        ])))]));
+        let date = BASE_DATE + 300_000_000_000;
+        db.conn.execute("UPDATE message SET text = 'hello!', date_edited = ?, message_summary_info = ? WHERE guid = 'message-1'", (date, edited)).unwrap();
+        let changes = db.poll().unwrap();
+        assert_eq!(guids(&changes), ["updated message-1"]);
+        let ChangeEvent::UpdatedMessage(message) = &changes[0] else { unreachable!() };
+        assert_eq!(message.date_edited, Some(apple_to_unix(date as u128) / 1_000_000));
+        assert_eq!(message.edit_history[0].part_index, 0);
+        assert_eq!(message.edit_history[0].versions[0].date, 1_678_307_200_000);
+        assert!(message.retracted_parts.is_empty() && message.date_retracted.is_none());
+
+        let unsent = info(plist::Dictionary::from_iter([("rp", plist::Value::Array(vec![plist::Value::Integer(0.into())]))]));
+        db.conn.execute("UPDATE message SET text = NULL, date_retracted = ?, message_summary_info = ? WHERE guid = 'message-3'", (date + 1, unsent)).unwrap();
+        let changes = db.poll().unwrap();
+        let ChangeEvent::UpdatedMessage(message) = &changes[0] else { unreachable!() };
+        assert_eq!((message.guid.as_str(), message.retracted_parts.as_slice()), ("message-3", &[0][..]));
+        assert!(message.date_retracted.is_some());
+        // garbage in the column is ignored
+        db.conn.execute("UPDATE message SET message_summary_info = X'00' WHERE guid = 'message-2'", []).unwrap();
+        assert!(db.get_message_by_guid("message-2".into(), false, false).unwrap().unwrap().edit_history.is_empty());
+    }
+
+    #[test]
+    fn test_poll_skips_history() {
+        let mut db = seeded_with(DatabaseOptions {
+            last_read_time: apple_to_unix((BASE_DATE + 90_000_000_000) as u128),
+            ..Default::default()
+        });
+        assert_eq!(guids(&db.poll().unwrap()), ["new message-3"]);
+    }
+
+    fn message_guids(messages: &[Message]) -> Vec<&str> {
+        messages.iter().map(|message| message.guid.as_str()).collect()
+    }
+
+    #[test]
+    fn test_chat_messages_paging() {
+        let db = seeded();
+        let chat = "iMessage;-;+15555550100";
+        for i in 4..=10 {
+            db.conn.execute_batch(&format!("
+                INSERT INTO message (ROWID, guid, handle_id, date) VALUES ({i}, 'message-{i}', 1, {date});
+                INSERT INTO chat_message_join (chat_id, message_id, message_date) VALUES (1, {i}, {date});
+            ", date = BASE_DATE + i * 60_000_000_000)).unwrap();
+        }
+
+        let query = MessageQuery { sort: SortOrder::Asc, limit: 3, offset: 1, ..Default::default() };
+        let page = db.get_chat_messages(chat.into(), &query).unwrap().unwrap();
+        assert_eq!(message_guids(&page.messages), ["message-2", "message-4", "message-5"]);
+
+        let query = MessageQuery { after: (BASE_DATE + 5 * 60_000_000_000) as u128, before: (BASE_DATE + 9 * 60_000_000_000) as u128, ..Default::default() };
+        let page = db.get_chat_messages(chat.into(), &query).unwrap().unwrap();
+        assert_eq!(message_guids(&page.messages), ["message-8", "message-7", "message-6"]);
+        assert_eq!(page.next_cursor, None);
+
+        // walking with the cursor stays stable while a newer message arrives
+        let mut query = MessageQuery { limit: 4, ..Default::default() };
+        let first = db.get_chat_messages(chat.into(), &query).unwrap().unwrap();
+        assert_eq!(message_guids(&first.messages), ["message-10", "message-9", "message-8", "message-7"]);
+        db.conn.execute_batch(&format!("
+            INSERT INTO message (ROWID, guid, handle_id, date) VALUES (11, 'message-11', 1, {date});
+            INSERT INTO chat_message_join (chat_id, message_id, message_date) VALUES (1, 11, {date});
+        ", date = BASE_DATE + 11 * 60_000_000_000)).unwrap();
+        query.cursor = first.next_cursor;
+        let second = db.get_chat_messages(chat.into(), &query).unwrap().unwrap();
+        assert_eq!(message_guids(&second.messages), ["message-6", "message-5", "message-4", "message-2"]);
+        query.cursor = second.next_cursor;
+        let third = db.get_chat_messages(chat.into(), &query).unwrap().unwrap();
+        assert_eq!(message_guids(&third.messages), ["message-1"]);
+        assert_eq!(third.next_cursor, None);
+    }
+
+    #[test]
+    fn test_query_messages() {
+        let db = seeded();
+        let page = db.query_messages(&MessageQuery::default()).unwrap();
+        assert_eq!(message_guids(&page.messages), ["message-3", "message-2", "message-1"]);
+        assert_eq!(page.messages[0].chat_guid, "SMS;+;chat1234");
+
+        let filtered = |filters: Vec<MessageFilter>| {
+            let query = MessageQuery { sort: SortOrder::Asc, filters, ..Default::default() };
+            message_guids(&db.query_messages(&query).unwrap().messages).join(" ")
+        };
+        assert_eq!(filtered(vec![MessageFilter::IsFromMe(false)]), "message-1 message-3");
+        assert_eq!(filtered(vec![MessageFilter::HandleId(2)]), "message-3");
+        assert_eq!(filtered(vec![MessageFilter::TextLike("%HELLO%".into())]), "message-1 message-3");
+        assert_eq!(filtered(vec![MessageFilter::TextLike("%hello%".into()), MessageFilter::HandleId(1)]), "message-1");
+        // a pattern is only ever bound as a value
+        assert_eq!(filtered(vec![MessageFilter::TextLike("' OR 1=1 --".into())]), "");
+
+        let query = MessageQuery { filters: vec![MessageFilter::IsFromMe(false)], ..Default::default() };
+        let page = db.get_chat_messages("iMessage;-;+15555550100".into(), &query).unwrap().unwrap();
+        assert_eq!(message_guids(&page.messages), ["message-1"]);
+        assert_eq!(page.messages[0].chat_guid, "iMessage;-;+15555550100");
+
+        let query = MessageQuery { limit: 2, ..Default::default() };
+        let first = db.query_messages(&query).unwrap();
+        let second = db.query_messages(&MessageQuery { cursor: first.next_cursor, ..query }).unwrap();
+        assert_eq!(message_guids(&second.messages), ["message-1"]);
+    }
+
+    #[test]
+    fn test_query_chats_by_last_message() {
+        let db = seeded();
+        db.conn.execute_batch(&format!("
+            INSERT INTO chat (ROWID, guid, style, chat_identifier, service_name, group_id, last_addressed_handle) VALUES (3, 'iMessage;-;empty', 45, 'empty', 'iMessage', 'group-3', '');
+            INSERT INTO message (ROWID, guid, text, handle_id, date) VALUES (4, 'message-4', 'newest', 1, {date});
+            INSERT INTO chat_message_join (chat_id, message_id, message_date) VALUES (1, 4, {date});
+        ", date = BASE_DATE + 600_000_000_000)).unwrap();
+
+        let chats = db.query_chats(10, 0, ChatSort::LastMessage, true, false).unwrap();
+        let order: Vec<_> = chats.iter().map(|chat| (chat.guid.as_str(), chat.last_message.as_ref().map(|message| message.guid.as_str()))).collect();
+        assert_eq!(order, [
+            ("iMessage;-;+15555550100", Some("message-4")),
+            ("SMS;+;chat1234", Some("message-3")),
+            ("iMessage;-;empty", None),
+        ]);
+
+        let page = db.query_chats(1, 1, ChatSort::LastMessage, false, false).unwrap();
+        assert_eq!(page.iter().map(|chat| chat.guid.as_str()).collect::<Vec<_>>(), ["SMS;+;chat1234"]);
+        assert_eq!("LastMessage".parse::<ChatSort>(), Ok(ChatSort::LastMessage));
+    }
+
+    #[test]
+    fn test_cursor_round_trip() {
+        let cursor = MessageCursor { date: BASE_DATE, rowid: 42 };
+        assert_eq!(cursor.to_string().parse::<MessageCursor>(), Ok(cursor));
+        assert!("42".parse::<MessageCursor>().is_err());
+        assert_eq!("asc".parse::<SortOrder>(), Ok(SortOrder::Asc));
+    }
+}
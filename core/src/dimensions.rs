@@ -0,0 +1,97 @@
+//! Width and height of image attachments. Only the image header is read, and the result is kept in
+//! its own SQLite file keyed by attachment guid and file modification time, so listing a chat full of
+//! photos doesn't touch every file again.
+
+use std::{path::Path, time::UNIX_EPOCH};
+
+use image::io::Reader as ImageReader;
+use rusqlite::{params, Connection, OptionalExtension};
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS attachment_dimensions (
+        guid TEXT PRIMARY KEY,
+        mtime INTEGER NOT NULL,
+        width INTEGER,
+        height INTEGER
+    );
+";
+
+/// Reads the dimensions from the file's header, `None` if it isn't an image `image` understands.
+pub fn image_dimensions(path: &Path) -> Option<(u32, u32)> {
+    ImageReader::open(path).ok()?.with_guessed_format().ok()?.into_dimensions().ok()
+}
+
+pub struct DimensionCache {
+    conn: Connection,
+}
+
+impl DimensionCache {
+    /// Opens the cache at `path`, creating it if needed. Safe to delete, entries are read again from the files.
+    pub fn open(path: impl AsRef<Path>) -> rusqlite::Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(Self { conn })
+    }
+
+    /// A cache that only lives as long as the process.
+    pub fn open_in_memory() -> rusqlite::Result<Self> {
+        let conn = Connection::open_in_memory()?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(Self { conn })
+    }
+
+    /// Dimensions of the attachment stored at `path`, read from the file only if it changed since it was cached.
+    /// Files that aren't images are cached as well, `None` is returned for them and for missing files.
+    pub fn get(&self, guid: &str, path: &Path) -> rusqlite::Result<Option<(u32, u32)>> {
+        let Some(mtime) = std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map(|modified| modified.as_nanos().min(i64::MAX as u128) as i64) else {
+            return Ok(None);
+        };
+        let cached = self.conn.query_row("SELECT width, height FROM attachment_dimensions WHERE guid = ? AND mtime = ?", params![guid, mtime], |row| {
+            Ok(row.get::<_, Option<u32>>("width")?.zip(row.get::<_, Option<u32>>("height")?))
+        }).optional()?;
+        if let Some(dimensions) = cached {
+            return Ok(dimensions);
+        }
+        let dimensions = image_dimensions(path);
+        self.conn.execute("INSERT OR REPLACE INTO attachment_dimensions (guid, mtime, width, height) VALUES (?, ?, ?, ?)", params![
+            guid, mtime, dimensions.map(|(width, _)| width), dimensions.map(|(_, height)| height),
+        ])?;
+        return Ok(dimensions);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use image::{Rgb, RgbImage};
+
+    use super::DimensionCache;
+
+    #[test]
+    fn test_dimension_cache() {
+        let dir = std::env::temp_dir().join(format!("bluebubbles-dimensions-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let image = dir.join("image.png");
+        RgbImage::from_pixel(30, 20, Rgb([0, 0, 255])).save(&image).unwrap();
+        let text = dir.join("text.txt");
+        std::fs::write(&text, "not an image").unwrap();
+
+        let cache = DimensionCache::open(dir.join("dimensions.db")).unwrap();
+        assert_eq!(cache.get("image", &image).unwrap(), Some((30, 20)));
+        assert_eq!(cache.get("text", &text).unwrap(), None);
+        assert_eq!(cache.get("missing", &dir.join("missing.png")).unwrap(), None);
+        let count = |cache: &DimensionCache| cache.conn.query_row("SELECT COUNT(*) FROM attachment_dimensions", [], |row| row.get::<_, u32>(0)).unwrap();
+        assert_eq!(count(&cache), 2);
+
+        // a cached entry is used as long as the file's mtime matches
+        cache.conn.execute("UPDATE attachment_dimensions SET width = 1 WHERE guid = 'image'", []).unwrap();
+        assert_eq!(cache.get("image", &image).unwrap(), Some((1, 20)));
+        cache.conn.execute("UPDATE attachment_dimensions SET mtime = 0 WHERE guid = 'image'", []).unwrap();
+        assert_eq!(cache.get("image", &image).unwrap(), Some((30, 20)));
+
+        drop(cache);
+        assert_eq!(count(&DimensionCache::open(dir.join("dimensions.db")).unwrap()), 2);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
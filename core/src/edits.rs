@@ -0,0 +1,121 @@
+//! Edits and unsends, which macOS 13+ records in the `message_summary_info` binary plist.
+//!
+//! `ec` maps each edited part (by index, as a string) to its versions oldest first, each a dictionary
+//! with the apple time in seconds under `d` and the text as an archived `NSAttributedString` under `t`.
+//! `rp` lists the parts that were unsent.
+
+use plist::{Dictionary, Value};
+use serde::{Deserialize, Serialize};
+
+use crate::{typedstream::decode_attributed_body, util::apple_to_unix};
+
+/// Every version of one edited part of a message.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EditedPart {
+    #[serde(rename = "partIndex")]
+    pub part_index: u32,
+    /// Oldest first, the last one is the current text.
+    pub versions: Vec<TextVersion>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TextVersion {
+    /// `None` if the archived text couldn't be decoded.
+    pub text: Option<String>,
+    /// unix time in milliseconds
+    pub date: u128,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SummaryInfo {
+    /// Sorted by part.
+    pub edited_parts: Vec<EditedPart>,
+    /// Parts that were unsent, sorted.
+    pub retracted_parts: Vec<u32>,
+}
+
+/// Apple time in seconds, written as a real by current versions and as an integer by some older ones.
+fn seconds_to_unix_millis(value: &Value) -> Option<u128> {
+    let seconds = value.as_real().or_else(|| value.as_signed_integer().map(|seconds| seconds as f64))?;
+    if !seconds.is_finite() || seconds < 0.0 {
+        return None;
+    }
+    return Some(apple_to_unix((seconds * 1e9) as u128) / 1000000);
+}
+
+fn edited_part(part: &str, versions: &Value) -> Option<EditedPart> {
+    let versions = versions.as_array()?.iter().filter_map(Value::as_dictionary).map(|version| {
+        TextVersion {
+            text: version.get("t").and_then(Value::as_data).and_then(|bytes| decode_attributed_body(bytes).ok()).map(|body| body.string),
+            date: version.get("d").and_then(seconds_to_unix_millis).unwrap_or(0),
+        }
+    }).collect();
+    return Some(EditedPart { part_index: part.parse().ok()?, versions });
+}
+
+/// Parses a `message_summary_info` plist, parts it doesn't understand are left out.
+pub fn parse_summary_info(bytes: &[u8]) -> Result<SummaryInfo, plist::Error> {
+    let info: Dictionary = plist::from_bytes(bytes)?;
+    let mut edited_parts: Vec<EditedPart> = info.get("ec").and_then(Value::as_dictionary).map(|parts| {
+        parts.iter().filter_map(|(part, versions)| edited_part(part, versions)).collect()
+    }).unwrap_or_default();
+    edited_parts.sort_by_key(|part| part.part_index);
+    let mut retracted_parts: Vec<u32> = info.get("rp").and_then(Value::as_array).map(|parts| {
+        parts.iter().filter_map(Value::as_unsigned_integer).filter_map(|part| u32::try_from(part).ok()).collect()
+    }).unwrap_or_default();
+    retracted_parts.sort_unstable();
+    return Ok(SummaryInfo { edited_parts, retracted_parts });
+}
+
+#[cfg(test)]
+mod test {
+    use plist::{Dictionary, Value};
+
+    use super::parse_summary_info;
+
+    /// A one-run attributed string archived the way Messages does, `text` has to be shorter than 128 bytes.
+    fn body(text: &str) -> Vec<u8> {
+        [
+            &b"\x04\x0bstreamtyped\x81\xe8\x03\x84\x01@\x84\x84\x84\x12NSAttributedString\x00\x84\x84\x08NSObject\x00\x85"[..],
+            b"\x92\x84\x84\x84\x08NSString\x01\x94\x84\x01+", &[text.len() as u8], text.as_bytes(), b"\x86",
+            b"\x84\x02iI\x01", &[text.encode_utf16().count() as u8],
+            b"\x92\x84\x84\x84\x0cNSDictionary\x00\x94\x84\x01i\x01\x92\x84\x96\x96\x1d__kIMMessagePartAttributeName\x86",
+            b"\x92\x84\x84\x84\x08NSNumber\x00\x84\x84\x07NSValue\x00\x94\x84\x01*\x84\x99\x99\x00\x86\x86\x86",
+        ].concat()
+    }
+
+    fn version(text: &str, seconds: Value) -> Value {
+        Value::Dictionary(Dictionary::from_iter([("t", Value::Data(body(text))), ("d", seconds)]))
+    }
+
+    fn binary(info: Dictionary) -> Vec<u8> {
+        let mut bytes = vec![];
+        plist::to_writer_binary(&mut bytes, &Value::Dictionary(info)).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn test_summary_info() {
+        let info = binary(Dictionary::from_iter([
+            ("ec", Value::Dictionary(Dictionary::from_iter([
+                ("1", Value::Array(vec![version("tpyo", Value::Real(700_000_000.5)), version("typo", Value::Integer(700_000_060.into()))])),
+                ("0", Value::Array(vec![version("first", Value::Real(700_000_000.0)), Value::String("junk".into())])),
+                ("not a part", Value::Array(vec![])),
+            ]))),
+            ("rp", Value::Array(vec![Value::Integer(3.into()), Value::Integer(2.into())])),
+            ("otr", Value::Dictionary(Dictionary::new())),
+        ]));
+        let info = parse_summary_info(&info).unwrap();
+        assert_eq!(info.retracted_parts, [2, 3]);
+        assert_eq!(info.edited_parts.iter().map(|part| part.part_index).collect::<Vec<_>>(), [0, 1]);
+        let versions = &info.edited_parts[1].versions;
+        assert_eq!(versions.iter().map(|version| version.text.as_deref()).collect::<Vec<_>>(), [Some("tpyo"), Some("typo")]);
+        // 2023-03-07T20:26:40.5Z
+        assert_eq!(versions[0].date, 1_678_307_200_500);
+        assert_eq!(versions[1].date, 1_678_307_260_000);
+        assert_eq!(info.edited_parts[0].versions.len(), 1);
+
+        assert_eq!(parse_summary_info(&binary(Dictionary::new())).unwrap(), Default::default());
+        assert!(parse_summary_info(b"not a plist").is_err());
+    }
+}
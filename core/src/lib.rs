@@ -0,0 +1,24 @@
+//! Read model for the macOS Messages database (`~/Library/Messages/chat.db`).
+//!
+//! [`Database`] opens chat.db and answers the queries the BlueBubbles server needs, returning the
+//! [`structs`] clients receive as JSON, [`api`] holds the rest of the API's response bodies. [`util`] converts between unix time and the apple epoch
+//! chat.db stores dates in. [`search`] keeps a full-text index of message texts next to chat.db and
+//! [`typedstream`] decodes the archived `NSAttributedString`s newer macOS versions store message texts as, [`edits`] the
+//! history of edited and unsent messages. [`schema`] finds out which columns the chat.db of this macOS version has
+//! and [`thumbnail`] renders resized copies of image attachments, [`dimensions`] caches their sizes.
+#![allow(clippy::needless_return)]
+
+pub mod api;
+pub mod database;
+pub mod dimensions;
+pub mod edits;
+pub mod schema;
+pub mod search;
+pub mod structs;
+pub mod thumbnail;
+pub mod typedstream;
+pub mod util;
+
+pub use database::{ChangeEvent, Database, DatabaseOptions, OpenMode};
+pub use search::SearchIndex;
+pub use structs::{Attachment, AttributeRun, AttributedBody, Chat, Message, Participant, Reaction, ReactionKind, Tapback};
@@ -0,0 +1,206 @@
+//! Which chat.db columns exist. Every macOS release adds a few (and very rarely drops one), so
+//! [`Schema::probe`] reads `PRAGMA table_info` once when the database is opened and the queries
+//! select missing columns as their default value instead of failing on them.
+
+use std::{collections::{HashMap, HashSet}, fmt};
+
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+
+/// Column name and the SQL value to use when the column doesn't exist.
+type Column = (&'static str, &'static str);
+
+/// Everything `Message::from_row` reads.
+const MESSAGE_COLUMNS: &[Column] = &[
+    ("ROWID", "0"), ("guid", "''"), ("text", "NULL"), ("attributedBody", "NULL"), ("handle_id", "0"), ("other_handle", "0"),
+    ("subject", "NULL"), ("country", "NULL"), ("error", "0"),
+    ("date", "0"), ("date_read", "0"), ("date_delivered", "0"), ("date_played", "0"), ("date_edited", "0"), ("date_retracted", "0"),
+    ("is_from_me", "0"), ("is_delayed", "0"), ("is_auto_reply", "0"), ("is_system_message", "0"), ("is_service_message", "0"),
+    ("is_forward", "0"), ("is_corrupt", "0"), ("is_spam", "0"), ("is_audio_message", "0"), ("has_dd_results", "0"),
+    ("was_delivered_quietly", "0"), ("did_notify_recipient", "0"), ("cache_roomnames", "NULL"),
+    ("group_title", "NULL"), ("group_action_type", "0"), ("item_type", "0"), ("share_status", "0"), ("share_direction", "0"),
+    ("associated_message_guid", "NULL"), ("associated_message_type", "0"), ("associated_message_emoji", "NULL"),
+    ("expressive_send_style_id", "NULL"), ("reply_to_guid", "NULL"), ("thread_originator_guid", "NULL"), ("thread_originator_part", "NULL"),
+    ("message_summary_info", "NULL"),
+];
+
+/// Everything `Database::get_attachment_by_guid` reads.
+const ATTACHMENT_COLUMNS: &[Column] = &[
+    ("ROWID", "0"), ("guid", "''"), ("filename", "NULL"), ("uti", "NULL"), ("mime_type", "NULL"), ("transfer_name", "NULL"),
+    ("total_bytes", "0"), ("transfer_state", "0"), ("is_outgoing", "0"), ("hide_attachment", "0"), ("is_sticker", "0"),
+    ("original_guid", "guid"),
+];
+
+/// Everything `Database::chat_from_row` reads.
+const CHAT_COLUMNS: &[Column] = &[
+    ("ROWID", "0"), ("guid", "''"), ("style", "0"), ("chat_identifier", "''"), ("service_name", "NULL"), ("display_name", "NULL"),
+    ("group_id", "''"), ("last_addressed_handle", "''"), ("is_archived", "0"), ("is_filtered", "0"), ("last_read_message_timestamp", "0"),
+];
+
+/// Everything `Participant`s are built from.
+const HANDLE_COLUMNS: &[Column] = &[
+    ("ROWID", "0"), ("id", "''"), ("country", "NULL"), ("service", "''"), ("uncanonicalized_id", "NULL"),
+];
+
+/// The oldest macOS release whose chat.db has every column that was found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SchemaVersion {
+    /// Before tapbacks.
+    Legacy,
+    /// macOS 10.12, tapbacks and stickers.
+    Sierra,
+    /// macOS 11, inline replies.
+    BigSur,
+    /// macOS 13, edits and unsends.
+    Ventura,
+    /// macOS 15, emoji tapbacks.
+    Sequoia,
+}
+
+impl fmt::Display for SchemaVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Legacy => "macOS 10.11 or older",
+            Self::Sierra => "macOS 10.12",
+            Self::BigSur => "macOS 11",
+            Self::Ventura => "macOS 13",
+            Self::Sequoia => "macOS 15",
+        })
+    }
+}
+
+/// What the server can read from this chat.db.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct Capabilities {
+    /// `message.attributedBody`, the only place newer versions keep message texts.
+    pub attributed_body: bool,
+    pub reactions: bool,
+    pub emoji_reactions: bool,
+    pub threads: bool,
+    pub edits: bool,
+    pub unsends: bool,
+    /// `chat.last_read_message_timestamp`, needed for chat read events.
+    pub chat_read_status: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Schema {
+    /// Lowercase table name to its lowercase column names.
+    tables: HashMap<String, HashSet<String>>,
+    message_columns: String,
+    attachment_columns: String,
+    chat_columns: String,
+    handle_columns: String,
+}
+
+impl Schema {
+    pub fn probe(conn: &Connection) -> rusqlite::Result<Self> {
+        let mut tables: HashMap<String, HashSet<String>> = HashMap::new();
+        let mut stmt = conn.prepare("SELECT m.name AS table_name, p.name AS column_name FROM sqlite_master AS m JOIN pragma_table_info(m.name) AS p WHERE m.type = 'table'")?;
+        for column in stmt.query_map([], |row| Ok((row.get::<_, String>("table_name")?, row.get::<_, String>("column_name")?)))? {
+            let (table, column) = column?;
+            tables.entry(table.to_ascii_lowercase()).or_default().insert(column.to_ascii_lowercase());
+        }
+        return Ok(Self::from_tables(tables));
+    }
+
+    fn from_tables(tables: HashMap<String, HashSet<String>>) -> Self {
+        let mut schema = Self { tables, message_columns: String::new(), attachment_columns: String::new(), chat_columns: String::new(), handle_columns: String::new() };
+        schema.message_columns = schema.select_list("message", "m", MESSAGE_COLUMNS);
+        schema.attachment_columns = schema.select_list("attachment", "a", ATTACHMENT_COLUMNS);
+        schema.chat_columns = schema.select_list("chat", "c", CHAT_COLUMNS);
+        schema.handle_columns = schema.select_list("handle", "h", HANDLE_COLUMNS);
+        schema
+    }
+
+    pub fn has_column(&self, table: &str, column: &str) -> bool {
+        // ROWID is there even when the table doesn't declare it
+        self.tables.get(&table.to_ascii_lowercase()).is_some_and(|columns| column.eq_ignore_ascii_case("rowid") || columns.contains(&column.to_ascii_lowercase()))
+    }
+
+    /// `alias.column` for every column that exists, `default AS column` for the others.
+    fn select_list(&self, table: &str, alias: &str, columns: &[Column]) -> String {
+        columns.iter().map(|(column, default)| match self.has_column(table, column) {
+            true => format!("{alias}.{column}"),
+            false => format!("{default} AS {column}"),
+        }).collect::<Vec<_>>().join(", ")
+    }
+
+    /// Columns for `FROM message AS m`.
+    pub(crate) fn message_columns(&self) -> &str {
+        &self.message_columns
+    }
+
+    /// Columns for `FROM attachment AS a`.
+    pub(crate) fn attachment_columns(&self) -> &str {
+        &self.attachment_columns
+    }
+
+    /// Columns for `FROM chat AS c`.
+    pub(crate) fn chat_columns(&self) -> &str {
+        &self.chat_columns
+    }
+
+    /// Columns for `FROM handle AS h`.
+    pub(crate) fn handle_columns(&self) -> &str {
+        &self.handle_columns
+    }
+
+    pub fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            attributed_body: self.has_column("message", "attributedBody"),
+            reactions: self.has_column("message", "associated_message_type") && self.has_column("message", "associated_message_guid"),
+            emoji_reactions: self.has_column("message", "associated_message_emoji"),
+            threads: self.has_column("message", "thread_originator_guid"),
+            edits: self.has_column("message", "date_edited") && self.has_column("message", "message_summary_info"),
+            unsends: self.has_column("message", "date_retracted"),
+            chat_read_status: self.has_column("chat", "last_read_message_timestamp"),
+        }
+    }
+
+    pub fn version(&self) -> SchemaVersion {
+        let capabilities = self.capabilities();
+        if capabilities.emoji_reactions {
+            SchemaVersion::Sequoia
+        } else if capabilities.edits {
+            SchemaVersion::Ventura
+        } else if capabilities.threads {
+            SchemaVersion::BigSur
+        } else if capabilities.reactions {
+            SchemaVersion::Sierra
+        } else {
+            SchemaVersion::Legacy
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rusqlite::Connection;
+
+    use super::{Schema, SchemaVersion};
+    use crate::database::FIXTURE_SCHEMA;
+
+    #[test]
+    fn test_probe() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(FIXTURE_SCHEMA).unwrap();
+        let schema = Schema::probe(&conn).unwrap();
+        assert_eq!(schema.version(), SchemaVersion::Sequoia);
+        assert!(schema.capabilities().chat_read_status);
+        assert!(schema.has_column("Message", "ATTRIBUTEDBODY") && schema.has_column("message", "ROWID"));
+        assert!(!schema.has_column("message", "missing") && !schema.has_column("missing", "ROWID"));
+        assert!(!schema.message_columns().contains(" AS "));
+
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("
+            CREATE TABLE message (ROWID INTEGER PRIMARY KEY, guid TEXT, text TEXT, date INTEGER, associated_message_guid TEXT, associated_message_type INTEGER);
+            CREATE TABLE attachment (guid TEXT, filename TEXT);
+        ").unwrap();
+        let schema = Schema::probe(&conn).unwrap();
+        assert_eq!(schema.version(), SchemaVersion::Sierra);
+        assert!(schema.message_columns().starts_with("m.ROWID, m.guid, m.text, NULL AS attributedBody, 0 AS handle_id"));
+        assert!(schema.attachment_columns().starts_with("a.ROWID, a.guid, a.filename, NULL AS uti") && schema.attachment_columns().ends_with("guid AS original_guid"));
+        assert!(!schema.capabilities().chat_read_status);
+    }
+}
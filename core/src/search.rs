@@ -0,0 +1,290 @@
+//! Full-text search over message texts. chat.db has no text index and is never written to, so the
+//! index lives in its own SQLite file and is filled from chat.db in batches by [`SearchIndex::sync`].
+
+use std::path::Path;
+
+use rusqlite::{named_params, params, Connection, OptionalExtension, Transaction};
+
+use crate::{database::Database, structs::Message};
+
+const SCHEMA: &str = "
+    CREATE VIRTUAL TABLE IF NOT EXISTS message_fts USING fts5(text, guid UNINDEXED, chat_guid UNINDEXED, tokenize = 'unicode61 remove_diacritics 2');
+    CREATE TABLE IF NOT EXISTS search_state (name TEXT PRIMARY KEY, value INTEGER NOT NULL);
+    CREATE TABLE IF NOT EXISTS search_unassigned (rowid INTEGER PRIMARY KEY);
+";
+
+/// Marks the matched words in [`SearchHit::snippet`].
+pub const HIGHLIGHT_START: &str = "<b>";
+pub const HIGHLIGHT_END: &str = "</b>";
+
+/// What FTS5 marks matches with, private use characters so they can be told apart from the text after it's escaped.
+const MATCH_START: char = '\u{E000}';
+const MATCH_END: char = '\u{E001}';
+
+pub struct SearchIndex {
+    conn: Connection,
+}
+
+#[derive(Debug, Clone)]
+pub struct SearchQuery {
+    /// Words the message has to contain, the last one may be a prefix so results update while typing.
+    pub text: String,
+    /// Only search these chats, all of them when empty.
+    pub chat_guids: Vec<String>,
+    pub limit: usize,
+    pub offset: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SearchHit {
+    pub guid: String,
+    /// HTML: the part of the text around the match, escaped, with the matched words wrapped in
+    /// [`HIGHLIGHT_START`] and [`HIGHLIGHT_END`]. Those tags are the only markup in it.
+    pub snippet: String,
+}
+
+/// Turns what someone typed into an FTS5 query matching every word, quoted so FTS5's own query syntax
+/// can't be used (or break the query). `None` if there are no words at all.
+fn match_expression(text: &str) -> Option<String> {
+    let terms: Vec<String> = text.split_whitespace().map(|term| format!("\"{}\"", term.replace('"', "\"\""))).collect();
+    return (!terms.is_empty()).then(|| terms.join(" ") + "*");
+}
+
+/// Escapes a snippet marked with [`MATCH_START`] and [`MATCH_END`] for HTML and turns the marks into highlight tags.
+fn highlight(snippet: &str) -> String {
+    let mut html = String::with_capacity(snippet.len());
+    for c in snippet.chars() {
+        match c {
+            MATCH_START => html.push_str(HIGHLIGHT_START),
+            MATCH_END => html.push_str(HIGHLIGHT_END),
+            '&' => html.push_str("&amp;"),
+            '<' => html.push_str("&lt;"),
+            '>' => html.push_str("&gt;"),
+            '"' => html.push_str("&quot;"),
+            '\'' => html.push_str("&#39;"),
+            c => html.push(c),
+        }
+    }
+    return html;
+}
+
+/// Replaces the indexed text of one message, messages without text are only removed. Messages that
+/// aren't in a chat yet are remembered in `search_unassigned` so the next sync can fill in their chat.
+fn upsert(tx: &Transaction, rowid: i64, guid: &str, chat_guid: &str, text: Option<&str>) -> rusqlite::Result<()> {
+    tx.execute("DELETE FROM message_fts WHERE rowid = ?", [rowid])?;
+    tx.execute("DELETE FROM search_unassigned WHERE rowid = ?", [rowid])?;
+    if let Some(text) = text.filter(|text| !text.trim().is_empty()) {
+        // the match marks can't be in the text, they would turn into tags in snippets
+        let text = text.replace([MATCH_START, MATCH_END], "");
+        tx.execute("INSERT INTO message_fts (rowid, text, guid, chat_guid) VALUES (?, ?, ?, ?)", params![rowid, text, guid, chat_guid])?;
+        if chat_guid.is_empty() {
+            tx.execute("INSERT INTO search_unassigned (rowid) VALUES (?)", [rowid])?;
+        }
+    }
+    Ok(())
+}
+
+impl SearchIndex {
+    /// Opens the index at `path`, creating it if needed. Delete the file to rebuild it from scratch.
+    pub fn open(path: impl AsRef<Path>) -> rusqlite::Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(Self { conn })
+    }
+
+    /// An index that only lives as long as the process, for tests and tooling.
+    pub fn open_in_memory() -> rusqlite::Result<Self> {
+        let conn = Connection::open_in_memory()?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(Self { conn })
+    }
+
+    fn state(&self, name: &str) -> rusqlite::Result<i64> {
+        Ok(self.conn.query_row("SELECT value FROM search_state WHERE name = ?", [name], |row| row.get(0)).optional()?.unwrap_or(0))
+    }
+
+    /// `ROWID`s of up to `limit` indexed messages that weren't in a chat when they were indexed, after `after`.
+    fn unassigned_rowids(&self, after: i64, limit: usize) -> rusqlite::Result<Vec<i64>> {
+        let mut stmt = self.conn.prepare("SELECT rowid FROM search_unassigned WHERE rowid > ? ORDER BY rowid LIMIT ?")?;
+        return stmt.query_map([after, limit as i64], |row| row.get(0))?.collect();
+    }
+
+    /// Indexes up to `batch` messages chat.db got since the last sync and returns how many it read.
+    /// Call it until it returns 0 to catch up with a large chat.db without holding it for long.
+    /// Messages that were indexed before chat.db added them to a chat get their chat filled in as well,
+    /// `batch` of them per call, starting over once every one was checked.
+    pub fn sync(&mut self, db: &Database, batch: usize) -> rusqlite::Result<usize> {
+        let texts = db.get_message_texts(self.state("last_rowid")?, batch)?;
+        let unassigned = self.unassigned_rowids(self.state("last_unassigned_rowid")?, batch)?;
+        let assigned: Vec<_> = db.get_message_texts_by_rowid(&unassigned)?.into_iter().filter(|text| !text.chat_guid.is_empty()).collect();
+        let tx = self.conn.transaction()?;
+        for text in assigned.iter().chain(&texts) {
+            upsert(&tx, text.rowid, &text.guid, &text.chat_guid, text.text.as_deref())?;
+        }
+        let set_state = |name: &str, value: i64| {
+            tx.execute("INSERT INTO search_state (name, value) VALUES (?, ?) ON CONFLICT (name) DO UPDATE SET value = excluded.value", params![name, value])
+        };
+        if let Some(last) = texts.last() {
+            set_state("last_rowid", last.rowid)?;
+        }
+        set_state("last_unassigned_rowid", match unassigned.len() < batch {
+            true => 0,
+            false => unassigned.last().copied().unwrap_or(0),
+        })?;
+        tx.commit()?;
+        Ok(texts.len())
+    }
+
+    /// Re-indexes messages that changed after they were synced, e.g. edits.
+    pub fn update<'a>(&mut self, messages: impl IntoIterator<Item = &'a Message>) -> rusqlite::Result<()> {
+        let tx = self.conn.transaction()?;
+        for message in messages {
+            upsert(&tx, message.original_rowid.into(), &message.guid, &message.chat_guid, message.text.as_deref())?;
+        }
+        tx.commit()
+    }
+
+    /// Best matches first.
+    pub fn search(&self, query: &SearchQuery) -> rusqlite::Result<Vec<SearchHit>> {
+        let Some(expression) = match_expression(&query.text) else {
+            return Ok(vec![]);
+        };
+        let chat_guids = (!query.chat_guids.is_empty()).then(|| serde_json::to_string(&query.chat_guids).expect("strings always serialize"));
+        let mut stmt = self.conn.prepare(&format!("SELECT guid, snippet(message_fts, 0, '{MATCH_START}', '{MATCH_END}', '…', 16) AS snippet FROM message_fts \
+            WHERE message_fts MATCH :expression AND (:chat_guids IS NULL OR chat_guid IN (SELECT value FROM json_each(:chat_guids))) \
+            ORDER BY rank, rowid DESC LIMIT :limit OFFSET :offset"))?;
+        return stmt.query_map(named_params! {
+            ":expression": expression,
+            ":chat_guids": chat_guids,
+            ":limit": query.limit as i64,
+            ":offset": query.offset as i64,
+        }, |row| {
+            Ok(SearchHit { guid: row.get("guid")?, snippet: highlight(&row.get::<_, String>("snippet")?) })
+        })?.collect();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::database::{Database, DatabaseOptions, FIXTURE_SCHEMA};
+
+    use super::{match_expression, SearchIndex, SearchQuery};
+
+    fn chat_db() -> Database {
+        let path = std::env::temp_dir().join(format!("bluebubbles-search-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let conn = rusqlite::Connection::open(&path).unwrap();
+        conn.execute_batch(FIXTURE_SCHEMA).unwrap();
+        conn.execute_batch("
+            INSERT INTO chat (ROWID, guid, style, chat_identifier, group_id, last_addressed_handle) VALUES (1, 'iMessage;-;+15555550100', 45, '+15555550100', 'group-1', ''), (2, 'SMS;+;chat1234', 43, 'chat1234', 'group-2', '');
+            INSERT INTO message (ROWID, guid, text, handle_id, date) VALUES
+                (1, 'message-1', 'Lunch at the café tomorrow?', 1, 1),
+                (2, 'message-2', NULL, 1, 2),
+                (3, 'message-3', 'no lunch for me, I had a late breakfast', 0, 3),
+                (4, 'message-4', 'see you at lunchtime', 2, 4);
+            INSERT INTO chat_message_join (chat_id, message_id, message_date) VALUES (1, 1, 1), (1, 2, 2), (1, 3, 3), (2, 4, 4);
+        ").unwrap();
+        let db = Database::open(&path, DatabaseOptions::default()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        db
+    }
+
+    fn guids(index: &SearchIndex, text: &str, chat_guids: &[&str]) -> Vec<String> {
+        let query = SearchQuery { text: text.into(), chat_guids: chat_guids.iter().map(|guid| guid.to_string()).collect(), limit: 10, offset: 0 };
+        let mut guids: Vec<String> = index.search(&query).unwrap().into_iter().map(|hit| hit.guid).collect();
+        guids.sort();
+        guids
+    }
+
+    #[test]
+    fn test_search() {
+        let db = chat_db();
+        let mut index = SearchIndex::open_in_memory().unwrap();
+        assert_eq!(index.sync(&db, 2).unwrap(), 2);
+        assert_eq!(index.sync(&db, 10).unwrap(), 2);
+        assert_eq!(index.sync(&db, 10).unwrap(), 0);
+
+        assert_eq!(guids(&index, "LUNCH", &[]), ["message-1", "message-3", "message-4"]);
+        assert_eq!(guids(&index, "lunch", &["iMessage;-;+15555550100"]), ["message-1", "message-3"]);
+        assert_eq!(guids(&index, "cafe", &[]), ["message-1"]);
+        assert_eq!(guids(&index, "late break", &[]), ["message-3"]);
+        assert!(guids(&index, "   ", &[]).is_empty());
+        // FTS5 syntax is searched for literally instead of failing the query
+        assert!(guids(&index, "lunch\" OR NEAR(", &[]).is_empty());
+
+        let query = SearchQuery { text: "tomorrow".into(), chat_guids: vec![], limit: 10, offset: 0 };
+        assert_eq!(index.search(&query).unwrap()[0].snippet, "Lunch at the café <b>tomorrow</b>?");
+
+        let mut edited = db.get_message_by_guid("message-1".into(), false, false).unwrap().unwrap();
+        edited.text = Some("dinner instead".into());
+        index.update([&edited]).unwrap();
+        assert_eq!(guids(&index, "lunch", &[]), ["message-3", "message-4"]);
+        assert_eq!(guids(&index, "dinner", &["iMessage;-;+15555550100"]), ["message-1"]);
+
+        // snippets are HTML, markup in messages is escaped
+        edited.text = Some("<script>alert('dinner')</script> & \u{E000}more".into());
+        index.update([&edited]).unwrap();
+        let query = SearchQuery { text: "dinner".into(), chat_guids: vec![], limit: 10, offset: 0 };
+        assert_eq!(index.search(&query).unwrap()[0].snippet, "&lt;script&gt;alert(&#39;<b>dinner</b>&#39;)&lt;/script&gt; &amp; more");
+    }
+
+    #[test]
+    fn test_message_added_to_chat_late() {
+        let path = std::env::temp_dir().join(format!("bluebubbles-search-late-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let conn = rusqlite::Connection::open(&path).unwrap();
+        conn.execute_batch(FIXTURE_SCHEMA).unwrap();
+        conn.execute_batch("
+            INSERT INTO chat (ROWID, guid, style, chat_identifier, group_id, last_addressed_handle) VALUES (1, 'iMessage;-;+15555550100', 45, '+15555550100', 'group-1', '');
+            INSERT INTO message (ROWID, guid, text, handle_id, date) VALUES (1, 'message-1', 'running late', 1, 1);
+        ").unwrap();
+        let db = Database::open(&path, DatabaseOptions::default()).unwrap();
+        let mut index = SearchIndex::open_in_memory().unwrap();
+        assert_eq!(index.sync(&db, 10).unwrap(), 1);
+        assert_eq!(guids(&index, "late", &[]), ["message-1"]);
+        assert!(guids(&index, "late", &["iMessage;-;+15555550100"]).is_empty());
+
+        // the join row is written after the message, the next sync picks it up without reading new messages
+        assert_eq!(index.sync(&db, 10).unwrap(), 0);
+        conn.execute("INSERT INTO chat_message_join (chat_id, message_id, message_date) VALUES (1, 1, 1)", []).unwrap();
+        assert_eq!(index.sync(&db, 10).unwrap(), 0);
+        assert_eq!(guids(&index, "late", &["iMessage;-;+15555550100"]), ["message-1"]);
+        assert!(index.unassigned_rowids(0, 10).unwrap().is_empty());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_more_unassigned_messages_than_batch() {
+        let path = std::env::temp_dir().join(format!("bluebubbles-search-unassigned-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let conn = rusqlite::Connection::open(&path).unwrap();
+        conn.execute_batch(FIXTURE_SCHEMA).unwrap();
+        conn.execute_batch("
+            INSERT INTO chat (ROWID, guid, style, chat_identifier, group_id, last_addressed_handle) VALUES (1, 'iMessage;-;+15555550100', 45, '+15555550100', 'group-1', '');
+            INSERT INTO message (ROWID, guid, text, handle_id, date) VALUES
+                (1, 'message-1', 'never in a chat', 1, 1),
+                (2, 'message-2', 'never in a chat', 1, 2),
+                (3, 'message-3', 'never in a chat', 1, 3),
+                (4, 'message-4', 'joined later', 1, 4);
+        ").unwrap();
+        let db = Database::open(&path, DatabaseOptions::default()).unwrap();
+        let mut index = SearchIndex::open_in_memory().unwrap();
+        while index.sync(&db, 2).unwrap() > 0 {}
+        conn.execute("INSERT INTO chat_message_join (chat_id, message_id, message_date) VALUES (1, 4, 4)", []).unwrap();
+
+        // the oldest unassigned messages don't keep the newer ones from being checked
+        for _ in 0..3 {
+            index.sync(&db, 2).unwrap();
+        }
+        assert_eq!(guids(&index, "joined", &["iMessage;-;+15555550100"]), ["message-4"]);
+        assert_eq!(index.unassigned_rowids(0, 10).unwrap(), [1, 2, 3]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_match_expression() {
+        assert_eq!(match_expression("see  you"), Some("\"see\" \"you\"*".into()));
+        assert_eq!(match_expression("say \"hi\""), Some("\"say\" \"\"\"hi\"\"\"*".into()));
+        assert_eq!(match_expression(""), None);
+    }
+}
@@ -0,0 +1,429 @@
+use rusqlite::Row;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::{edits::{parse_summary_info, EditedPart, SummaryInfo}, typedstream::decode_attributed_body, util::apple_to_unix};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Chat {
+    #[serde(rename = "originalROWID")]
+    pub original_rowid: u32,
+    pub guid: String,
+    pub participants: Vec<Participant>,
+    #[serde(rename = "lastMessage")]
+    pub last_message: Option<Message>,
+    pub style: u32,
+    #[serde(rename = "chatIdentifier")]
+    pub chat_identifier: String,
+    #[serde(rename = "isArchived")]
+    pub is_archived: bool,
+    #[serde(rename = "isFiltered")]
+    pub is_filtered: bool,
+    #[serde(rename = "displayName")]
+    pub display_name: Option<String>,
+    #[serde(rename = "groupId")]
+    pub group_id: String,
+    #[serde(rename = "lastAddressedHandle")]
+    pub last_addressed_handle: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Participant {
+    #[serde(rename = "originalROWID")]
+    pub original_rowid: u32,
+    pub address: String,
+    pub country: String,
+    #[serde(rename = "uncanonicalizedId")]
+    pub uncanonicalized_id: Option<u32>,
+    pub service: String,
+    /// Chats this handle is in, only loaded by the handle endpoints.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chats: Option<Vec<Chat>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Message {
+    #[serde(rename = "originalROWID")]
+    pub original_rowid: u32,
+    pub guid: String,
+    pub text: Option<String>,
+    pub handle: Option<Participant>,
+    #[serde(rename = "handleId")]
+    pub handle_id: u32,
+    pub subject: Option<String>,
+    pub error: i32,
+    #[serde(skip_serializing, default)]
+    pub chat_guid: String,
+    pub attachments: Vec<Attachment>,
+    #[serde(rename = "groupActionType")]
+    pub group_action_type: u32,
+    #[serde(rename = "itemType")]
+    pub item_type: u32,
+    #[serde(rename = "otherHandle")]
+    pub other_handle: Option<u32>,
+    #[serde(rename = "dateCreated")]
+    pub date_created: u128,
+    #[serde(rename = "dateRead")]
+    pub date_read: u128,
+    #[serde(rename = "dateDelivered")]
+    pub date_delivered: u128,
+    #[serde(rename = "isFromMe")]
+    pub is_from_me: bool,
+    #[serde(rename = "hasDdResults")]
+    pub has_dd_results: bool,
+    #[serde(rename = "groupTitle")]
+    pub group_title: Option<String>,
+    #[serde(rename = "associatedMessageGuid")]
+    pub associated_message_guid: Option<String>,
+    /// Raw `associated_message_type`, `None` for plain messages. See [`Tapback`] for reactions.
+    #[serde(rename = "associatedMessageType")]
+    pub associated_message_type: Option<u32>,
+    /// Set when this message is a reaction to another one.
+    #[serde(default)]
+    pub tapback: Option<Tapback>,
+    /// Reactions currently on this message, only loaded when asked for with `with=reactions`.
+    #[serde(default)]
+    pub reactions: Vec<Reaction>,
+    #[serde(rename = "expressiveSendStyleId")]
+    pub expressive_send_style_id: Option<String>,
+    #[serde(rename = "threadOriginatorGuid")]
+    pub thread_originator_guid: Option<String>,
+    #[serde(rename = "threadOriginatorPart")]
+    pub thread_originator_part: Option<String>,
+    /// Number of inline replies to this message, only loaded when asked for with `with=replyCount`.
+    #[serde(rename = "replyCount", default)]
+    pub reply_count: Option<u32>,
+    pub country: Option<String>,
+    #[serde(rename = "isDelayed")]
+    pub is_delayed: bool,
+    #[serde(rename = "isAutoReply")]
+    pub is_auto_reply: bool,
+    #[serde(rename = "isSystemMessage")]
+    pub is_system_message: bool,
+    #[serde(rename = "isServiceMessage")]
+    pub is_service_message: bool,
+    #[serde(rename = "isForward")]
+    pub is_forward: bool,
+    #[serde(rename = "isCorrupt")]
+    pub is_corrupt: bool,
+    #[serde(rename = "datePlayed")]
+    pub date_played: u128,
+    #[serde(rename = "cacheRoomnames")]
+    pub cache_roomnames: Option<String>,
+    #[serde(rename = "isSpam")]
+    pub is_spam: bool,
+    #[serde(rename = "isAudioMessage")]
+    pub is_audio_message: bool,
+    #[serde(rename = "replyToGuid")]
+    pub reply_to_guid: Option<String>,
+    #[serde(rename = "shareStatus")]
+    pub share_status: i32,
+    #[serde(rename = "shareDirection")]
+    pub share_direction: i32,
+    #[serde(rename = "wasDeliveredQuietly")]
+    pub was_delivered_quietly: bool,
+    #[serde(rename = "didNotifyRecipient")]
+    pub did_notify_recipient: bool,
+    /// unix time in milliseconds of the latest edit
+    #[serde(rename = "dateEdited", default)]
+    pub date_edited: Option<u128>,
+    /// unix time in milliseconds of the latest unsend
+    #[serde(rename = "dateRetracted", default)]
+    pub date_retracted: Option<u128>,
+    /// Earlier versions of the parts that were edited.
+    #[serde(rename = "editHistory", default)]
+    pub edit_history: Vec<EditedPart>,
+    /// Parts that were unsent, sorted.
+    #[serde(rename = "retractedParts", default)]
+    pub retracted_parts: Vec<u32>,
+    /// Upstream sends a list, there is never more than one body here.
+    #[serde(rename = "attributedBody", default)]
+    pub attributed_body: Vec<AttributedBody>,
+    // pub chats: Vec<Chat>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReactionKind {
+    Love,
+    Like,
+    Dislike,
+    Laugh,
+    Emphasize,
+    Question,
+    /// Any emoji, see `emoji` for which one.
+    Emoji,
+}
+
+impl ReactionKind {
+    /// Reactions are stored as 2000 + kind when added and 3000 + kind when removed.
+    fn from_code(code: u32) -> Option<Self> {
+        Some(match code % 1000 {
+            0 => Self::Love,
+            1 => Self::Like,
+            2 => Self::Dislike,
+            3 => Self::Laugh,
+            4 => Self::Emphasize,
+            5 => Self::Question,
+            6 => Self::Emoji,
+            _ => return None,
+        })
+    }
+}
+
+/// What a reaction message does: add or remove a reaction on one part of another message.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Tapback {
+    pub kind: ReactionKind,
+    pub emoji: Option<String>,
+    pub removed: bool,
+    #[serde(rename = "targetGuid")]
+    pub target_guid: String,
+    /// Which part of the target was reacted to, e.g. one photo of several.
+    #[serde(rename = "partIndex")]
+    pub part_index: u32,
+}
+
+impl Tapback {
+    /// Parses `associated_message_type` and `associated_message_guid`, `None` for anything that isn't a reaction
+    /// (replies, stickers, ...). The guid is `p:<part>/<guid>`, `bp:<guid>` for link previews or just the guid on old messages.
+    pub fn parse(associated_type: u32, associated_guid: &str, emoji: Option<String>) -> Option<Self> {
+        let removed = match associated_type / 1000 {
+            2 => false,
+            3 => true,
+            _ => return None,
+        };
+        let kind = ReactionKind::from_code(associated_type)?;
+        let (part_index, target_guid) = match associated_guid.split_once('/') {
+            Some((part, guid)) => (part.strip_prefix("p:")?.parse().ok()?, guid),
+            None => (0, associated_guid.strip_prefix("bp:").unwrap_or(associated_guid)),
+        };
+        if target_guid.is_empty() {
+            return None;
+        }
+        Some(Self { kind, emoji: emoji.filter(|_| kind == ReactionKind::Emoji), removed, target_guid: target_guid.into(), part_index })
+    }
+}
+
+/// A reaction someone currently has on a message, removed reactions are never listed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Reaction {
+    /// Guid of the message that added it.
+    pub guid: String,
+    pub kind: ReactionKind,
+    pub emoji: Option<String>,
+    #[serde(rename = "partIndex")]
+    pub part_index: u32,
+    /// 0 for reactions from this Mac
+    #[serde(rename = "handleId")]
+    pub handle_id: u32,
+    #[serde(rename = "isFromMe")]
+    pub is_from_me: bool,
+    #[serde(rename = "dateCreated")]
+    pub date_created: u128,
+}
+
+/// Folds reaction messages, oldest first, into the reactions that are still there.
+/// Everyone has at most one reaction per part, a newer one replaces it and a removal clears it.
+pub fn current_reactions(messages: impl IntoIterator<Item = Message>) -> Vec<Reaction> {
+    let mut reactions: Vec<Reaction> = vec![];
+    for message in messages {
+        let Some(tapback) = message.tapback else {
+            continue;
+        };
+        reactions.retain(|reaction| {
+            (reaction.is_from_me, reaction.handle_id, reaction.part_index) != (message.is_from_me, message.handle_id, tapback.part_index)
+        });
+        if !tapback.removed {
+            reactions.push(Reaction {
+                guid: message.guid,
+                kind: tapback.kind,
+                emoji: tapback.emoji,
+                part_index: tapback.part_index,
+                handle_id: message.handle_id,
+                is_from_me: message.is_from_me,
+                date_created: message.date_created,
+            });
+        }
+    }
+    reactions
+}
+
+/// `message.attributedBody`: the text of a message with the attributes Messages attached to parts of it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AttributedBody {
+    pub string: String,
+    /// Cover `string` back to back.
+    pub runs: Vec<AttributeRun>,
+}
+
+impl AttributedBody {
+    /// How many parts Messages split the message into, each run says which part it belongs to.
+    pub fn part_count(&self) -> usize {
+        self.runs.iter().filter_map(|run| run.attributes.get("__kIMMessagePartAttributeName").and_then(Value::as_u64)).max().map_or(0, |part| part as usize + 1)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AttributeRun {
+    /// `[start, length]` in UTF-16 code units, like an `NSRange`.
+    pub range: [usize; 2],
+    /// As Messages names them, e.g. `__kIMMessagePartAttributeName` with the index of the part the run belongs to.
+    pub attributes: Map<String, Value>,
+}
+
+impl AttributeRun {
+    /// Address of the person mentioned with this run.
+    pub fn mention(&self) -> Option<&str> {
+        self.attributes.get("__kIMMentionConfirmedMention").and_then(Value::as_str)
+    }
+
+    /// Url of a link.
+    pub fn link(&self) -> Option<&str> {
+        self.attributes.get("__kIMLinkAttributeName").and_then(Value::as_str)
+    }
+
+    /// Guid of the attachment shown in place of this run's object replacement character.
+    pub fn attachment_guid(&self) -> Option<&str> {
+        self.attributes.get("__kIMFileTransferGUIDAttributeName").and_then(Value::as_str)
+    }
+
+    pub fn is_bold(&self) -> bool {
+        self.flag("__kIMTextBoldAttributeName")
+    }
+
+    pub fn is_italic(&self) -> bool {
+        self.flag("__kIMTextItalicAttributeName")
+    }
+
+    pub fn is_underlined(&self) -> bool {
+        self.flag("__kIMTextUnderlineAttributeName")
+    }
+
+    pub fn is_strikethrough(&self) -> bool {
+        self.flag("__kIMTextStrikethroughAttributeName")
+    }
+
+    fn flag(&self, attribute: &str) -> bool {
+        self.attributes.get(attribute).and_then(Value::as_i64).is_some_and(|flag| flag != 0)
+    }
+}
+
+/// Decodes a `message_summary_info` column, empty if there is none or it can't be parsed.
+pub fn summary_info(bytes: Option<Vec<u8>>) -> SummaryInfo {
+    let Some(bytes) = bytes.filter(|bytes| !bytes.is_empty()) else {
+        return SummaryInfo::default();
+    };
+    match parse_summary_info(&bytes) {
+        Ok(info) => info,
+        Err(err) => {
+            tracing::debug!("ignoring message_summary_info: {err}");
+            SummaryInfo::default()
+        },
+    }
+}
+
+/// An optional apple time column, `None` while it's unset.
+fn optional_date(row: &Row, column: &str) -> Option<u128> {
+    row.get::<_, Option<i64>>(column).ok().flatten().filter(|date| *date > 0).map(|date| apple_to_unix(date as u128)/1000000)
+}
+
+/// Decodes an `attributedBody` column, `None` if it's empty or can't be decoded.
+pub fn attributed_body(bytes: Option<Vec<u8>>) -> Option<AttributedBody> {
+    let bytes = bytes.filter(|bytes| !bytes.is_empty())?;
+    match decode_attributed_body(&bytes) {
+        Ok(body) => Some(body),
+        Err(err) => {
+            tracing::debug!("ignoring attributedBody: {err}");
+            None
+        },
+    }
+}
+
+impl Message {
+    /// System message left in a group chat when someone renames it, `group_title` holds the new name.
+    pub fn is_group_name_change(&self) -> bool {
+        self.item_type == 2
+    }
+
+    pub fn from_row(row: &Row, handle: Option<Participant>, attachments: Vec<Attachment>, chat_guid: String, guid: String, original_rowid: u32) -> rusqlite::Result<Self> {
+        // newer versions of macOS only fill in attributedBody
+        let attributed_body = attributed_body(row.get("attributedBody").ok().flatten());
+        // macOS 13+ only, older databases don't have the column
+        let summary_info = summary_info(row.get("message_summary_info").ok().flatten());
+        let associated_message_guid: Option<String> = row.get("associated_message_guid").ok().flatten();
+        let associated_message_type = row.get::<_, Option<u32>>("associated_message_type")?.filter(|associated_type| *associated_type != 0);
+        // only newer versions of macOS have the emoji column
+        let tapback = associated_message_type.zip(associated_message_guid.as_deref()).and_then(|(associated_type, associated_guid)| {
+            Tapback::parse(associated_type, associated_guid, row.get("associated_message_emoji").ok().flatten())
+        });
+        Ok(Self {
+            original_rowid,
+            guid,
+            text: row.get::<_, Option<String>>("text")?.or_else(|| attributed_body.as_ref().map(|body| body.string.clone())),
+            attributed_body: attributed_body.into_iter().collect(),
+            date_edited: optional_date(row, "date_edited"),
+            date_retracted: optional_date(row, "date_retracted"),
+            edit_history: summary_info.edited_parts,
+            retracted_parts: summary_info.retracted_parts,
+            date_created: apple_to_unix(row.get::<_, Option<usize>>("date")?.unwrap_or(0) as u128)/1000000,
+            handle,
+            chat_guid,
+            attachments,
+            group_action_type: row.get("group_action_type")?,
+            item_type: row.get("item_type")?,
+            other_handle: row.get("other_handle").ok(),
+            is_from_me: row.get("is_from_me")?,
+            associated_message_guid,
+            associated_message_type,
+            tapback,
+            reactions: vec![],
+            cache_roomnames: row.get("cache_roomnames")?,
+            country: row.get("country").ok(),
+            date_delivered: apple_to_unix(row.get::<_, Option<usize>>("date_delivered")?.unwrap_or(0) as u128)/1000000,
+            date_played: apple_to_unix(row.get::<_, Option<usize>>("date_played")?.unwrap_or(0) as u128)/1000000,
+            date_read: apple_to_unix(row.get::<_, Option<usize>>("date_read")?.unwrap_or(0) as u128)/1000000,
+            did_notify_recipient: row.get("did_notify_recipient")?,
+            error: row.get("error")?,
+            expressive_send_style_id: row.get("expressive_send_style_id").ok(),
+            group_title: row.get("group_title").ok(),
+            handle_id: row.get("handle_id")?,
+            subject: row.get("subject").ok(),
+            has_dd_results: row.get("has_dd_results")?,
+            is_audio_message: row.get("is_audio_message")?,
+            is_auto_reply: row.get("is_auto_reply")?,
+            is_corrupt: row.get("is_corrupt")?,
+            is_delayed: row.get("is_delayed")?,
+            is_forward: row.get("is_forward")?,
+            is_service_message: row.get("is_service_message")?,
+            is_spam: row.get("is_spam")?,
+            is_system_message: row.get("is_system_message")?,
+            reply_to_guid: row.get("reply_to_guid").ok(),
+            share_direction: row.get("share_direction")?,
+            share_status: row.get("share_status")?,
+            thread_originator_guid: row.get("thread_originator_guid").ok(),
+            thread_originator_part: row.get("thread_originator_part").ok(),
+            reply_count: None,
+            was_delivered_quietly: row.get("was_delivered_quietly")?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Attachment {
+    pub original_rowid: u32,
+    pub guid: String,
+    pub uti: Option<String>,
+    pub mime_type: Option<String>,
+    pub transfer_name: String,
+    pub total_bytes: u32,
+    pub transfer_state: u32,
+    pub is_outgoing: bool,
+    pub hide_attachment: bool,
+    pub is_sticker: bool,
+    pub original_guid: String,
+    pub has_live_photo: bool,
+    pub height: Option<u32>,
+    pub width: Option<u32>,
+    pub metadata: Option<String>,
+}
\ No newline at end of file
@@ -0,0 +1,182 @@
+//! Resized copies of image attachments, so clients don't have to download full-size photos to draw a
+//! chat bubble. [`ThumbnailCache`] keeps them on disk, keyed by attachment and size.
+
+use std::{fmt, fs, io::Cursor, path::{Path, PathBuf}, str::FromStr};
+
+use image::{codecs::{jpeg::JpegEncoder, png::PngEncoder, webp::WebPEncoder}, io::Reader as ImageReader, DynamicImage};
+
+pub use image::ImageError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ThumbnailFormat {
+    #[default]
+    Jpeg,
+    Png,
+    /// Always lossless, `quality` is ignored.
+    Webp,
+}
+
+impl ThumbnailFormat {
+    pub fn mime_type(&self) -> &'static str {
+        match self {
+            Self::Jpeg => "image/jpeg",
+            Self::Png => "image/png",
+            Self::Webp => "image/webp",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Jpeg => "jpeg",
+            Self::Png => "png",
+            Self::Webp => "webp",
+        }
+    }
+}
+
+impl FromStr for ThumbnailFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "jpeg" | "jpg" => Ok(Self::Jpeg),
+            "png" => Ok(Self::Png),
+            "webp" => Ok(Self::Webp),
+            _ => Err(format!("unknown image format {s:?}, expected jpeg, png or webp")),
+        }
+    }
+}
+
+impl fmt::Display for ThumbnailFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.extension())
+    }
+}
+
+/// The box a thumbnail is fitted into, keeping the aspect ratio. Images are never scaled up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ThumbnailOptions {
+    /// `None` to only limit the height.
+    pub width: Option<u32>,
+    /// `None` to only limit the width.
+    pub height: Option<u32>,
+    /// JPEG quality from 1 to 100.
+    pub quality: u8,
+    pub format: ThumbnailFormat,
+}
+
+impl Default for ThumbnailOptions {
+    fn default() -> Self {
+        Self { width: None, height: None, quality: 80, format: ThumbnailFormat::default() }
+    }
+}
+
+impl ThumbnailOptions {
+    /// Part of the cache file name, every option that changes the output is in it.
+    fn key(&self) -> String {
+        let size = |size: Option<u32>| size.map(|size| size.to_string()).unwrap_or_default();
+        let quality = match self.format {
+            ThumbnailFormat::Jpeg => self.quality.clamp(1, 100),
+            _ => 0,
+        };
+        return format!("{}x{}-q{quality}.{}", size(self.width), size(self.height), self.format.extension());
+    }
+}
+
+/// Decodes the image at `source` and encodes it fitted into `options`.
+pub fn render(source: &Path, options: &ThumbnailOptions) -> Result<Vec<u8>, ImageError> {
+    let image = ImageReader::open(source)?.with_guessed_format()?.decode()?;
+    let width = options.width.unwrap_or(u32::MAX).clamp(1, image.width());
+    let height = options.height.unwrap_or(u32::MAX).clamp(1, image.height());
+    let image = match (width, height) == (image.width(), image.height()) {
+        true => image,
+        false => image.thumbnail(width, height),
+    };
+    return encode(image, options);
+}
+
+fn encode(image: DynamicImage, options: &ThumbnailOptions) -> Result<Vec<u8>, ImageError> {
+    let mut bytes = Cursor::new(vec![]);
+    match options.format {
+        // JPEG has no alpha channel
+        ThumbnailFormat::Jpeg => DynamicImage::ImageRgb8(image.to_rgb8()).write_with_encoder(JpegEncoder::new_with_quality(&mut bytes, options.quality.clamp(1, 100)))?,
+        ThumbnailFormat::Png => image.write_with_encoder(PngEncoder::new(&mut bytes))?,
+        ThumbnailFormat::Webp => DynamicImage::ImageRgba8(image.to_rgba8()).write_with_encoder(WebPEncoder::new_lossless(&mut bytes))?,
+    }
+    return Ok(bytes.into_inner());
+}
+
+/// A directory of rendered thumbnails. Entries are re-rendered when the source file is newer.
+#[derive(Debug, Clone)]
+pub struct ThumbnailCache {
+    dir: PathBuf,
+}
+
+impl ThumbnailCache {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// Where the thumbnail of an attachment is cached, guids are reduced to characters that are safe in file names.
+    pub fn path(&self, guid: &str, options: &ThumbnailOptions) -> PathBuf {
+        let guid: String = guid.chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' }).collect();
+        return self.dir.join(format!("{guid}-{}", options.key()));
+    }
+
+    /// Path of the cached thumbnail of `source`, rendering it first if it's missing or outdated.
+    pub fn get_or_render(&self, guid: &str, source: &Path, options: &ThumbnailOptions) -> Result<PathBuf, ImageError> {
+        let path = self.path(guid, options);
+        let modified = |path: &Path| fs::metadata(path).and_then(|metadata| metadata.modified()).ok();
+        if let (Some(cached), Some(source)) = (modified(&path), modified(source)) {
+            if cached >= source {
+                return Ok(path);
+            }
+        }
+        let bytes = render(source, options)?;
+        fs::create_dir_all(&self.dir)?;
+        // written next to the entry and renamed so concurrent requests never read half a file
+        let partial = path.with_extension(format!("{}.partial-{}", options.format.extension(), std::process::id()));
+        fs::write(&partial, bytes)?;
+        fs::rename(&partial, &path).inspect_err(|_| { let _ = fs::remove_file(&partial); })?;
+        return Ok(path);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use image::{GenericImageView, Rgba, RgbaImage};
+
+    use super::{ThumbnailCache, ThumbnailFormat, ThumbnailOptions};
+
+    #[test]
+    fn test_thumbnail_cache() {
+        let dir = std::env::temp_dir().join(format!("bluebubbles-thumbnails-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("source.png");
+        RgbaImage::from_pixel(400, 200, Rgba([255, 0, 0, 128])).save(&source).unwrap();
+        let cache = ThumbnailCache::new(dir.join("cache"));
+
+        let options = ThumbnailOptions { width: Some(100), ..Default::default() };
+        let path = cache.get_or_render("at_0/guid", &source, &options).unwrap();
+        assert_eq!(path.file_name().unwrap(), "at_0_guid-100x-q80.jpeg");
+        assert_eq!(image::open(&path).unwrap().dimensions(), (100, 50));
+        assert_eq!(cache.get_or_render("at_0/guid", &source, &options).unwrap(), path);
+
+        // never scaled up
+        let options = ThumbnailOptions { width: Some(1000), height: Some(1000), format: ThumbnailFormat::Webp, ..Default::default() };
+        let path = cache.get_or_render("at_0/guid", &source, &options).unwrap();
+        assert_eq!(path.file_name().unwrap(), "at_0_guid-1000x1000-q0.webp");
+        let webp = image::open(&path).unwrap();
+        assert_eq!((webp.dimensions(), webp.get_pixel(0, 0)), ((400, 200), Rgba([255, 0, 0, 128])));
+
+        assert!(cache.get_or_render("text", &dir.join("missing.png"), &options).is_err());
+        std::fs::write(dir.join("text.txt"), "not an image").unwrap();
+        assert!(cache.get_or_render("text", &dir.join("text.txt"), &options).is_err());
+
+        assert_eq!(std::fs::read_dir(dir.join("cache")).unwrap().count(), 2);
+        std::fs::remove_dir_all(dir).unwrap();
+
+        assert_eq!("JPG".parse::<ThumbnailFormat>(), Ok(ThumbnailFormat::Jpeg));
+        assert!("gif".parse::<ThumbnailFormat>().is_err());
+    }
+}
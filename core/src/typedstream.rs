@@ -0,0 +1,639 @@
+//! Decoder for typedstreams, the `NSArchiver` format chat.db keeps `message.attributedBody` in.
+//!
+//! A typedstream is a list of groups, each a type encoding (`@` for an object, `iI` for two
+//! integers, ...) followed by one value per type. Objects are a class followed by groups of their
+//! own until an end tag. Strings, classes and objects are written once and referenced by their
+//! position afterwards, so the reader keeps the same tables the writer did. Only the little endian
+//! flavour Messages writes is supported.
+
+use std::fmt;
+
+use serde_json::{Map, Number, Value as Json};
+
+use crate::structs::{AttributeRun, AttributedBody};
+
+const TAG_INTEGER_2: i8 = -127;
+const TAG_INTEGER_4: i8 = -126;
+const TAG_FLOATING_POINT: i8 = -125;
+const TAG_NEW: i8 = -124;
+const TAG_NIL: i8 = -123;
+const TAG_END_OF_OBJECT: i8 = -122;
+/// Bytes below this are tags, references count up from it.
+const FIRST_REFERENCE: i8 = -110;
+
+/// Deeper nesting than Messages ever writes, stops crafted blobs from overflowing the stack.
+const MAX_DEPTH: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TypedStreamError {
+    /// The blob ended in the middle of a value.
+    UnexpectedEnd,
+    /// Not a little endian version 4 typedstream.
+    InvalidHeader,
+    InvalidReference(i64),
+    /// A type encoding with a type this decoder doesn't know.
+    UnsupportedType(String),
+    /// The bytes don't fit the structure being read.
+    Malformed(&'static str),
+}
+
+impl fmt::Display for TypedStreamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnexpectedEnd => f.write_str("typedstream ended unexpectedly"),
+            Self::InvalidHeader => f.write_str("not a streamtyped typedstream"),
+            Self::InvalidReference(reference) => write!(f, "typedstream references unknown entry {reference}"),
+            Self::UnsupportedType(encoding) => write!(f, "unsupported type encoding {encoding:?}"),
+            Self::Malformed(reason) => write!(f, "malformed typedstream: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for TypedStreamError {}
+
+type Result<T> = std::result::Result<T, TypedStreamError>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Nil,
+    Integer(i64),
+    Float(f64),
+    /// C strings, atoms, selectors and byte strings, the latter hold the contents of `NSString`s.
+    Bytes(Vec<u8>),
+    /// Index into [`TypedStream::entries`].
+    Object(usize),
+    /// Index into [`TypedStream::entries`].
+    Class(usize),
+    /// Arrays and structs.
+    List(Vec<Value>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Class {
+    pub name: String,
+    pub version: i64,
+    pub superclass: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Object {
+    pub class: usize,
+    /// What the object's `encodeWithCoder:` wrote.
+    pub groups: Vec<Vec<Value>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Entry {
+    Class(Class),
+    Object(Object),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Type {
+    Object,
+    Class,
+    /// `*`, `%` and `:`, written as shared strings
+    CString,
+    /// `+`, written inline
+    Bytes,
+    Signed,
+    Unsigned(u32),
+    Float,
+    Double,
+    Void,
+}
+
+/// One type of a type encoding, arrays and structs are flattened into [`Layout`]s.
+#[derive(Debug, Clone, PartialEq)]
+enum Layout {
+    Single(Type),
+    Array(usize, Box<Layout>),
+    Struct(Vec<Layout>),
+}
+
+impl Layout {
+    /// The fewest bytes a value of this layout is written in.
+    fn min_width(&self) -> usize {
+        match self {
+            Self::Single(Type::Void) => 0,
+            Self::Single(_) => 1,
+            Self::Array(length, element) => length.saturating_mul(element.min_width()),
+            Self::Struct(fields) => fields.iter().fold(0, |width, field| width.saturating_add(field.min_width())),
+        }
+    }
+}
+
+fn parse_layouts(encoding: &[u8]) -> Result<Vec<Layout>> {
+    let mut pos = 0;
+    let mut layouts = vec![];
+    while pos < encoding.len() {
+        layouts.push(parse_layout(encoding, &mut pos, 0)?);
+    }
+    return Ok(layouts);
+}
+
+/// `depth` counts the arrays and structs around this type, the encoding comes from the blob.
+fn parse_layout(encoding: &[u8], pos: &mut usize, depth: usize) -> Result<Layout> {
+    if depth > MAX_DEPTH {
+        return Err(TypedStreamError::Malformed("nested too deeply"));
+    }
+    let unsupported = || TypedStreamError::UnsupportedType(String::from_utf8_lossy(encoding).into_owned());
+    let byte = *encoding.get(*pos).ok_or_else(unsupported)?;
+    *pos += 1;
+    let single = match byte {
+        b'@' => Type::Object,
+        b'#' => Type::Class,
+        b'*' | b'%' | b':' => Type::CString,
+        b'+' => Type::Bytes,
+        b'c' | b's' | b'i' | b'l' | b'q' => Type::Signed,
+        b'C' => Type::Unsigned(8),
+        b'S' => Type::Unsigned(16),
+        b'I' | b'L' => Type::Unsigned(32),
+        b'Q' => Type::Unsigned(64),
+        b'f' => Type::Float,
+        b'd' => Type::Double,
+        b'v' => Type::Void,
+        b'[' => {
+            let digits = encoding[*pos..].iter().take_while(|byte| byte.is_ascii_digit()).count();
+            let length = std::str::from_utf8(&encoding[*pos..*pos + digits]).ok().and_then(|digits| digits.parse().ok()).ok_or_else(unsupported)?;
+            *pos += digits;
+            let element = parse_layout(encoding, pos, depth + 1)?;
+            if encoding.get(*pos) != Some(&b']') {
+                return Err(unsupported());
+            }
+            *pos += 1;
+            return Ok(Layout::Array(length, Box::new(element)));
+        },
+        b'{' => {
+            // `{name=types}`, the name is optional and fields can be structs themselves
+            let name = encoding[*pos..].iter().position(|byte| matches!(byte, b'=' | b'{' | b'}' | b'[')).ok_or_else(unsupported)?;
+            if encoding[*pos + name] == b'=' {
+                *pos += name + 1;
+            }
+            let mut fields = vec![];
+            while encoding.get(*pos) != Some(&b'}') {
+                fields.push(parse_layout(encoding, pos, depth + 1)?);
+            }
+            *pos += 1;
+            return Ok(Layout::Struct(fields));
+        },
+        _ => return Err(unsupported()),
+    };
+    return Ok(Layout::Single(single));
+}
+
+/// A decoded typedstream, objects and classes refer to each other through their index in `entries`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TypedStream {
+    pub entries: Vec<Entry>,
+    pub groups: Vec<Vec<Value>>,
+}
+
+impl TypedStream {
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        let mut reader = Reader { bytes, pos: 0, strings: vec![], entries: vec![], depth: 0 };
+        reader.header()?;
+        let mut groups = vec![];
+        while reader.pos < bytes.len() {
+            groups.push(reader.group()?);
+        }
+        return Ok(Self { entries: reader.entries, groups });
+    }
+
+    pub fn object(&self, index: usize) -> Option<&Object> {
+        match self.entries.get(index) {
+            Some(Entry::Object(object)) => Some(object),
+            _ => None,
+        }
+    }
+
+    pub fn class(&self, index: usize) -> Option<&Class> {
+        match self.entries.get(index) {
+            Some(Entry::Class(class)) => Some(class),
+            _ => None,
+        }
+    }
+
+    /// Whether `object`'s class is `name` or inherits from it.
+    pub fn is_kind_of(&self, object: &Object, name: &str) -> bool {
+        let mut class = self.class(object.class);
+        // the writer can't produce cycles, a crafted blob could
+        for _ in 0..MAX_DEPTH {
+            match class {
+                Some(current) if current.name == name => return true,
+                Some(current) => class = current.superclass.and_then(|superclass| self.class(superclass)),
+                None => return false,
+            }
+        }
+        return false;
+    }
+
+    /// The contents of an `NSString` or `NSMutableString`.
+    pub fn string(&self, object: &Object) -> Option<String> {
+        if !self.is_kind_of(object, "NSString") {
+            return None;
+        }
+        return object.groups.iter().flatten().find_map(|value| match value {
+            Value::Bytes(bytes) => Some(String::from_utf8_lossy(bytes).into_owned()),
+            _ => None,
+        });
+    }
+
+    /// Converts the Foundation objects attributes are made of into JSON. Anything that has no
+    /// sensible JSON form, like `NSData`, becomes `null`.
+    pub fn to_json(&self, value: &Value) -> Json {
+        self.to_json_at(value, 0)
+    }
+
+    fn to_json_at(&self, value: &Value, depth: usize) -> Json {
+        if depth > MAX_DEPTH {
+            return Json::Null;
+        }
+        let object = match value {
+            Value::Object(index) => match self.object(*index) {
+                Some(object) => object,
+                None => return Json::Null,
+            },
+            Value::Integer(integer) => return Json::from(*integer),
+            Value::Float(float) => return Number::from_f64(*float).map(Json::Number).unwrap_or(Json::Null),
+            Value::Bytes(bytes) => return Json::String(String::from_utf8_lossy(bytes).into_owned()),
+            Value::List(values) => return Json::Array(values.iter().map(|value| self.to_json_at(value, depth + 1)).collect()),
+            Value::Nil | Value::Class(_) => return Json::Null,
+        };
+        // the first group of collections is their size, every other group one object
+        let members = || object.groups.iter().skip(1).filter_map(|group| group.first());
+        if let Some(string) = self.string(object) {
+            return Json::String(string);
+        } else if self.is_kind_of(object, "NSNumber") {
+            // an encoding string like "q" and then the number itself
+            return object.groups.iter().flatten().rev().find(|value| matches!(value, Value::Integer(_) | Value::Float(_))).map(|value| self.to_json_at(value, depth + 1)).unwrap_or(Json::Null);
+        } else if self.is_kind_of(object, "NSDictionary") {
+            let members: Vec<&Value> = members().collect();
+            let mut map = Map::new();
+            for pair in members.chunks_exact(2) {
+                let key = match self.to_json_at(pair[0], depth + 1) {
+                    Json::String(key) => key,
+                    key => key.to_string(),
+                };
+                map.insert(key, self.to_json_at(pair[1], depth + 1));
+            }
+            return Json::Object(map);
+        } else if self.is_kind_of(object, "NSArray") {
+            return Json::Array(members().map(|value| self.to_json_at(value, depth + 1)).collect());
+        } else if self.is_kind_of(object, "NSURL") {
+            // a relative flag and the url as an NSString
+            return object.groups.iter().flatten().map(|value| self.to_json_at(value, depth + 1)).find(Json::is_string).unwrap_or(Json::Null);
+        }
+        return Json::Null;
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    strings: Vec<Vec<u8>>,
+    entries: Vec<Entry>,
+    depth: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, length: usize) -> Result<&'a [u8]> {
+        let end = self.pos.checked_add(length).filter(|end| *end <= self.bytes.len()).ok_or(TypedStreamError::UnexpectedEnd)?;
+        let bytes = &self.bytes[self.pos..end];
+        self.pos = end;
+        return Ok(bytes);
+    }
+
+    fn head(&mut self) -> Result<i8> {
+        return Ok(self.take(1)?[0] as i8);
+    }
+
+    fn peek(&self) -> Result<i8> {
+        self.bytes.get(self.pos).map(|byte| *byte as i8).ok_or(TypedStreamError::UnexpectedEnd)
+    }
+
+    fn integer_after(&mut self, head: i8) -> Result<i64> {
+        return match head {
+            TAG_INTEGER_2 => Ok(i16::from_le_bytes(self.take(2)?.try_into().unwrap()).into()),
+            TAG_INTEGER_4 => Ok(i32::from_le_bytes(self.take(4)?.try_into().unwrap()).into()),
+            head if head >= FIRST_REFERENCE => Ok(head.into()),
+            _ => Err(TypedStreamError::Malformed("expected an integer")),
+        };
+    }
+
+    fn integer(&mut self) -> Result<i64> {
+        let head = self.head()?;
+        self.integer_after(head)
+    }
+
+    fn reference_after(&mut self, head: i8) -> Result<usize> {
+        let reference = self.integer_after(head)? - i64::from(FIRST_REFERENCE);
+        usize::try_from(reference).map_err(|_| TypedStreamError::InvalidReference(reference))
+    }
+
+    fn unshared_string(&mut self) -> Result<&'a [u8]> {
+        let length = self.integer()?;
+        let length = usize::try_from(length).map_err(|_| TypedStreamError::Malformed("negative string length"))?;
+        self.take(length)
+    }
+
+    fn shared_string(&mut self) -> Result<Option<Vec<u8>>> {
+        return match self.head()? {
+            TAG_NIL => Ok(None),
+            TAG_NEW => {
+                let string = self.unshared_string()?.to_vec();
+                self.strings.push(string.clone());
+                Ok(Some(string))
+            },
+            head => {
+                let reference = self.reference_after(head)?;
+                self.strings.get(reference).cloned().map(Some).ok_or(TypedStreamError::InvalidReference(reference as i64))
+            },
+        };
+    }
+
+    fn header(&mut self) -> Result<()> {
+        let version = self.integer().map_err(|_| TypedStreamError::InvalidHeader)?;
+        let signature = self.unshared_string().map_err(|_| TypedStreamError::InvalidHeader)?;
+        if version != 4 || signature != b"streamtyped" {
+            return Err(TypedStreamError::InvalidHeader);
+        }
+        // the system version, 1000 on every Mac
+        self.integer().map_err(|_| TypedStreamError::InvalidHeader)?;
+        return Ok(());
+    }
+
+    fn enter(&mut self) -> Result<()> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return Err(TypedStreamError::Malformed("nested too deeply"));
+        }
+        return Ok(());
+    }
+
+    fn class(&mut self) -> Result<Option<usize>> {
+        return match self.head()? {
+            TAG_NIL => Ok(None),
+            TAG_NEW => {
+                self.enter()?;
+                let name = self.shared_string()?.ok_or(TypedStreamError::Malformed("class without a name"))?;
+                let version = self.integer()?;
+                // classes are numbered before their superclasses
+                let index = self.entries.len();
+                self.entries.push(Entry::Class(Class { name: String::from_utf8_lossy(&name).into_owned(), version, superclass: None }));
+                let superclass = self.class()?;
+                if let Entry::Class(class) = &mut self.entries[index] {
+                    class.superclass = superclass;
+                }
+                self.depth -= 1;
+                Ok(Some(index))
+            },
+            head => {
+                let reference = self.reference_after(head)?;
+                match self.entries.get(reference) {
+                    Some(Entry::Class(_)) => Ok(Some(reference)),
+                    _ => Err(TypedStreamError::InvalidReference(reference as i64)),
+                }
+            },
+        };
+    }
+
+    fn object(&mut self) -> Result<Value> {
+        return match self.head()? {
+            TAG_NIL => Ok(Value::Nil),
+            TAG_NEW => {
+                self.enter()?;
+                // objects are numbered before their class so they can reference themselves
+                let index = self.entries.len();
+                self.entries.push(Entry::Object(Object { class: usize::MAX, groups: vec![] }));
+                let class = self.class()?.ok_or(TypedStreamError::Malformed("object without a class"))?;
+                let mut groups = vec![];
+                while self.peek()? != TAG_END_OF_OBJECT {
+                    groups.push(self.group()?);
+                }
+                self.pos += 1;
+                self.entries[index] = Entry::Object(Object { class, groups });
+                self.depth -= 1;
+                Ok(Value::Object(index))
+            },
+            head => {
+                let reference = self.reference_after(head)?;
+                match self.entries.get(reference) {
+                    Some(Entry::Object(_)) => Ok(Value::Object(reference)),
+                    _ => Err(TypedStreamError::InvalidReference(reference as i64)),
+                }
+            },
+        };
+    }
+
+    fn group(&mut self) -> Result<Vec<Value>> {
+        let encoding = self.shared_string()?.ok_or(TypedStreamError::Malformed("group without a type encoding"))?;
+        return parse_layouts(&encoding)?.iter().map(|layout| self.value(layout)).collect();
+    }
+
+    fn value(&mut self, layout: &Layout) -> Result<Value> {
+        let single = match layout {
+            // char arrays are raw bytes, like NSData's contents
+            Layout::Array(length, element) if **element == Layout::Single(Type::Signed) || **element == Layout::Single(Type::Unsigned(8)) => {
+                return Ok(Value::Bytes(self.take(*length)?.to_vec()));
+            },
+            Layout::Array(length, element) => {
+                // the length comes from the blob, check it fits in what's left before allocating anything
+                let width = element.min_width();
+                if width == 0 {
+                    return Err(TypedStreamError::Malformed("array of empty elements"));
+                }
+                if length.saturating_mul(width) > self.bytes.len() - self.pos {
+                    return Err(TypedStreamError::UnexpectedEnd);
+                }
+                self.enter()?;
+                let mut values = Vec::with_capacity(*length);
+                for _ in 0..*length {
+                    values.push(self.value(element)?);
+                }
+                self.depth -= 1;
+                return Ok(Value::List(values));
+            },
+            Layout::Struct(fields) => {
+                // counted together with objects, a struct can hold objects that hold structs
+                self.enter()?;
+                let fields = fields.iter().map(|field| self.value(field)).collect::<Result<_>>()?;
+                self.depth -= 1;
+                return Ok(Value::List(fields));
+            },
+            Layout::Single(single) => *single,
+        };
+        return match single {
+            Type::Object => self.object(),
+            Type::Class => Ok(self.class()?.map(Value::Class).unwrap_or(Value::Nil)),
+            // C strings are a shared string behind a second new tag
+            Type::CString => match self.head()? {
+                TAG_NIL => Ok(Value::Nil),
+                TAG_NEW => Ok(self.shared_string()?.map(Value::Bytes).unwrap_or(Value::Nil)),
+                _ => Err(TypedStreamError::Malformed("expected a C string")),
+            },
+            Type::Bytes => Ok(Value::Bytes(self.unshared_string()?.to_vec())),
+            Type::Signed => Ok(Value::Integer(self.integer()?)),
+            Type::Unsigned(bits) => {
+                let integer = self.integer()?;
+                Ok(Value::Integer(if bits < 64 { integer & ((1 << bits) - 1) } else { integer }))
+            },
+            Type::Float | Type::Double => match self.head()? {
+                TAG_FLOATING_POINT if single == Type::Float => Ok(Value::Float(f32::from_le_bytes(self.take(4)?.try_into().unwrap()).into())),
+                TAG_FLOATING_POINT => Ok(Value::Float(f64::from_le_bytes(self.take(8)?.try_into().unwrap()))),
+                head => Ok(Value::Float(self.integer_after(head)? as f64)),
+            },
+            Type::Void => Ok(Value::Nil),
+        };
+    }
+}
+
+/// Decodes `message.attributedBody`, an archived `NSAttributedString`.
+pub fn decode_attributed_body(bytes: &[u8]) -> Result<AttributedBody> {
+    let stream = TypedStream::decode(bytes)?;
+    let root = match stream.groups.first().and_then(|group| group.first()) {
+        Some(Value::Object(index)) => stream.object(*index),
+        _ => None,
+    };
+    let root = root.filter(|root| stream.is_kind_of(root, "NSAttributedString")).ok_or(TypedStreamError::Malformed("not an NSAttributedString"))?;
+
+    let mut groups = root.groups.iter();
+    let string = match groups.next().and_then(|group| group.first()) {
+        Some(Value::Object(index)) => stream.object(*index).and_then(|object| stream.string(object)),
+        _ => None,
+    };
+    let string = string.ok_or(TypedStreamError::Malformed("NSAttributedString without a string"))?;
+
+    // every run is the number of its attributes and its length, attributes are only written
+    // the first time they are used, numbered from 1
+    let mut attributes: Vec<Map<String, Json>> = vec![];
+    let mut runs = vec![];
+    let mut start = 0;
+    while let Some(group) = groups.next() {
+        let (number, length) = match group.as_slice() {
+            [Value::Integer(number), Value::Integer(length)] => (*number, *length),
+            _ => return Err(TypedStreamError::Malformed("expected an attribute run")),
+        };
+        let number = usize::try_from(number).ok().filter(|number| *number > 0).ok_or(TypedStreamError::InvalidReference(number))?;
+        if number == attributes.len() + 1 {
+            let dictionary = groups.next().and_then(|group| group.first()).map(|value| stream.to_json(value));
+            match dictionary {
+                Some(Json::Object(dictionary)) => attributes.push(dictionary),
+                _ => return Err(TypedStreamError::Malformed("expected an attribute dictionary")),
+            }
+        }
+        let length = usize::try_from(length).map_err(|_| TypedStreamError::Malformed("negative run length"))?;
+        runs.push(AttributeRun {
+            range: [start, length],
+            attributes: attributes.get(number - 1).cloned().ok_or(TypedStreamError::InvalidReference(number as i64))?,
+        });
+        start += length;
+    }
+    return Ok(AttributedBody { string, runs });
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::{decode_attributed_body, TypedStream, TypedStreamError, Value};
+
+    const HEADER: &[u8] = b"\x04\x0bstreamtyped\x81\xe8\x03";
+
+    /// "Hello" with one run, as Messages writes a plain text message.
+    fn hello() -> Vec<u8> {
+        [
+            HEADER,
+            b"\x84\x01@\x84\x84\x84\x12NSAttributedString\x00\x84\x84\x08NSObject\x00\x85",
+            b"\x92\x84\x84\x84\x08NSString\x01\x94\x84\x01+\x05Hello\x86",
+            b"\x84\x02iI\x01\x05",
+            b"\x92\x84\x84\x84\x0cNSDictionary\x00\x94\x84\x01i\x01",
+            b"\x92\x84\x96\x96\x1d__kIMMessagePartAttributeName\x86",
+            b"\x92\x84\x84\x84\x08NSNumber\x00\x84\x84\x07NSValue\x00\x94\x84\x01*\x84\x99\x99\x00\x86",
+            b"\x86\x86",
+        ].concat()
+    }
+
+    #[test]
+    fn test_plain_text() {
+        let body = decode_attributed_body(&hello()).unwrap();
+        assert_eq!(body.string, "Hello");
+        assert_eq!(body.runs.len(), 1);
+        assert_eq!(body.runs[0].range, [0, 5]);
+        assert_eq!(json!(body.runs[0].attributes), json!({"__kIMMessagePartAttributeName": 0}));
+    }
+
+    #[test]
+    fn test_mention_and_reused_attributes() {
+        let bytes = [
+            HEADER,
+            b"\x84\x01@\x84\x84\x84\x12NSAttributedString\x00\x84\x84\x08NSObject\x00\x85",
+            b"\x92\x84\x84\x84\x08NSString\x01\x94\x84\x01+\x08hi @Ann!\x86",
+            b"\x84\x02iI\x01\x03",
+            b"\x92\x84\x84\x84\x0cNSDictionary\x00\x94\x84\x01i\x01",
+            b"\x92\x84\x96\x96\x1d__kIMMessagePartAttributeName\x86",
+            b"\x92\x84\x84\x84\x08NSNumber\x00\x84\x84\x07NSValue\x00\x94\x84\x01*\x84\x99\x99\x00\x86",
+            b"\x86",
+            // a second dictionary that references the key and number objects of the first one
+            b"\x97\x02\x04",
+            b"\x92\x84\x98\x99\x02",
+            b"\x92\x99\x92\x9a",
+            b"\x92\x84\x96\x96\x1c__kIMMentionConfirmedMention\x86",
+            b"\x92\x84\x96\x96\x0c+15555550100\x86",
+            b"\x86",
+            // the first dictionary again, written as just its number
+            b"\x97\x01\x01",
+            b"\x86",
+        ].concat();
+        let body = decode_attributed_body(&bytes).unwrap();
+        assert_eq!(body.string, "hi @Ann!");
+        let runs: Vec<_> = body.runs.iter().map(|run| (run.range, run.mention())).collect();
+        assert_eq!(runs, [([0, 3], None), ([3, 4], Some("+15555550100")), ([7, 1], None)]);
+        assert_eq!(body.runs[1].attributes["__kIMMessagePartAttributeName"], json!(0));
+    }
+
+    #[test]
+    fn test_integers_and_references() {
+        let stream = TypedStream::decode(&[HEADER, b"\x84\x03iIs\x81\xc8\x00\x82\xff\xff\xff\xff\xf0\x84\x01i\x7f"].concat()).unwrap();
+        assert_eq!(stream.groups, [vec![Value::Integer(200), Value::Integer(u32::MAX.into()), Value::Integer(-16)], vec![Value::Integer(127)]]);
+        assert_eq!(TypedStream::decode(&[HEADER, b"\x93\x01"].concat()), Err(TypedStreamError::InvalidReference(1)));
+    }
+
+    #[test]
+    fn test_nested_struct() {
+        let encoding = b"{CGRect={CGPoint=dd}{CGSize=dd}}";
+        let bytes = [HEADER, b"\x84", &[encoding.len() as u8], encoding, b"\x01\x02\x03\x04\x84\x01i\x05"].concat();
+        let stream = TypedStream::decode(&bytes).unwrap();
+        let point = Value::List(vec![Value::Float(1.0), Value::Float(2.0)]);
+        let size = Value::List(vec![Value::Float(3.0), Value::Float(4.0)]);
+        assert_eq!(stream.groups, [vec![Value::List(vec![point, size])], vec![Value::Integer(5)]]);
+        assert_eq!(TypedStream::decode(&[HEADER, b"\x84\x05{a={i\x01"].concat()), Err(TypedStreamError::UnsupportedType("{a={i".into())));
+    }
+
+    #[test]
+    fn test_array_length() {
+        let array = TypedStream::decode(&[HEADER, b"\x84\x04[3I]\x01\x02\x03"].concat()).unwrap();
+        assert_eq!(array.groups, [vec![Value::List(vec![Value::Integer(1), Value::Integer(2), Value::Integer(3)])]]);
+        // lengths the rest of the blob can't hold are rejected before anything is allocated
+        assert_eq!(TypedStream::decode(&[HEADER, b"\x84\x0d[4000000000I]\x01"].concat()), Err(TypedStreamError::UnexpectedEnd));
+        assert_eq!(TypedStream::decode(&[HEADER, b"\x84\x10[1000[1000[9I]]]\x01"].concat()), Err(TypedStreamError::UnexpectedEnd));
+        assert_eq!(TypedStream::decode(&[HEADER, b"\x84\x0d[4000000000v]"].concat()), Err(TypedStreamError::Malformed("array of empty elements")));
+        assert_eq!(TypedStream::decode(&[HEADER, b"\x84\x0a[400000{}]"].concat()), Err(TypedStreamError::Malformed("array of empty elements")));
+    }
+
+    #[test]
+    fn test_malformed() {
+        let bytes = hello();
+        // no truncation of a valid blob may panic, or decode to something
+        for end in 0..bytes.len() {
+            assert!(decode_attributed_body(&bytes[..end]).is_err(), "{end}");
+        }
+        assert_eq!(decode_attributed_body(b"bplist00"), Err(TypedStreamError::InvalidHeader));
+        let nested = [HEADER, b"\x84\x01@", &b"\x84\x84\x84\x01A\x00\x85\x92".repeat(100)].concat();
+        assert_eq!(decode_attributed_body(&nested), Err(TypedStreamError::Malformed("nested too deeply")));
+        // nested type encodings are limited as well, the encoding comes from the blob
+        let encoding = ["{".repeat(50_000), "i".into(), "}".repeat(50_000)].concat();
+        let deep = [HEADER, b"\x84\x82", &(encoding.len() as u32).to_le_bytes()[..], encoding.as_bytes(), b"\x01"].concat();
+        assert_eq!(TypedStream::decode(&deep), Err(TypedStreamError::Malformed("nested too deeply")));
+    }
+}
@@ -0,0 +1,17 @@
+use std::path::PathBuf;
+
+pub fn unix_to_apple(unix: u128) -> u128 {
+    unix.max(978307200000000000)-978307200000000000
+}
+
+pub fn apple_to_unix(apple: u128) -> u128 {
+    apple+978307200000000000
+}
+
+/// Resolves the `~/` chat.db starts attachment paths with against `$HOME`.
+pub fn expand_home(path: &str) -> PathBuf {
+    match path.strip_prefix("~/") {
+        Some(rest) => PathBuf::from(std::env::var("HOME").unwrap_or_default()).join(rest),
+        None => PathBuf::from(path),
+    }
+}
@@ -0,0 +1,80 @@
+use std::sync::Arc;
+
+use axum::{extract::{Request, State}, http::header::AUTHORIZATION, middleware::Next, response::Response};
+use subtle::{Choice, ConstantTimeEq};
+
+use crate::{error::ServerError, service::ApiService};
+
+/// The passwords a client sent, looked up the way upstream BlueBubbles does: the `guid` or `password`
+/// query parameter and the `Authorization` header (bare or `Bearer`).
+pub fn request_passwords(query: Option<&str>, authorization: Option<&str>) -> Vec<String> {
+    let mut passwords: Vec<String> = url::form_urlencoded::parse(query.unwrap_or_default().as_bytes())
+        .filter(|(name, _)| name == "guid" || name == "password")
+        .map(|(_, value)| value.into_owned())
+        .collect();
+    if let Some(header) = authorization.map(str::trim) {
+        passwords.push(header.strip_prefix("Bearer ").unwrap_or(header).to_string());
+    }
+    return passwords;
+}
+
+/// Whether any of the passwords sent is right, so a stale `?guid=` doesn't override a correct header.
+/// Every one is compared in constant time so the password can't be guessed a byte at a time from response timings.
+pub fn is_authorized(query: Option<&str>, authorization: Option<&str>, password: &str) -> bool {
+    return request_passwords(query, authorization).iter()
+        .fold(Choice::from(0), |authorized, given| authorized | given.as_bytes().ct_eq(password.as_bytes()))
+        .into();
+}
+
+/// Route layer for everything behind the server password; public routes are simply left out of it.
+pub async fn require_password(State(service): State<Arc<ApiService>>, request: Request, next: Next) -> Result<Response, ServerError> {
+    let authorization = request.headers().get(AUTHORIZATION).and_then(|value| value.to_str().ok());
+    service.authorize(request.uri().query(), authorization)?;
+    return Ok(next.run(request).await);
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use axum::{body::Body, http::{Request, StatusCode}, middleware, routing::get, Router};
+    use tower::ServiceExt;
+
+    use bluebubbles_core::{database::{Database, DatabaseOptions}, search::SearchIndex};
+
+    use crate::{config::Config, service::ApiService};
+
+    use super::require_password;
+
+    fn router() -> Router {
+        let config = Config { password: "hunter2".into(), ..Default::default() };
+        let service = Arc::new(ApiService::new(Database::open_fixture(DatabaseOptions::default()).unwrap(), SearchIndex::open_in_memory().unwrap(), config));
+        let protected = Router::new()
+            .route("/private", get(|| async { "secret" }))
+            .route_layer(middleware::from_fn_with_state(service, require_password));
+        return Router::new().route("/public", get(|| async { "hello" })).merge(protected);
+    }
+
+    async fn status(request: Request<Body>) -> StatusCode {
+        return router().oneshot(request).await.unwrap().status();
+    }
+
+    #[tokio::test]
+    async fn test_require_password() {
+        let get = |uri: &str| Request::get(uri).body(Body::empty()).unwrap();
+        assert_eq!(status(get("/public")).await, StatusCode::OK);
+        assert_eq!(status(get("/private")).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(status(get("/private?guid=hunter")).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(status(get("/private?guid=hunter2")).await, StatusCode::OK);
+        assert_eq!(status(get("/private?password=hunter2&with=chats")).await, StatusCode::OK);
+        let header = |value: &str| Request::get("/private").header("Authorization", value).body(Body::empty()).unwrap();
+        assert_eq!(status(header("hunter2")).await, StatusCode::OK);
+        assert_eq!(status(header("Bearer hunter2")).await, StatusCode::OK);
+        assert_eq!(status(header("Bearer hunter3")).await, StatusCode::UNAUTHORIZED);
+        // either credential is enough when both are sent
+        let both = |uri: &str, value: &str| Request::get(uri).header("Authorization", value).body(Body::empty()).unwrap();
+        assert_eq!(status(both("/private?guid=stale", "Bearer hunter2")).await, StatusCode::OK);
+        assert_eq!(status(both("/private?guid=hunter2", "stale")).await, StatusCode::OK);
+        assert_eq!(status(both("/private?guid=stale&password=old", "Bearer stale")).await, StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,225 @@
+use std::{fmt, net::{IpAddr, Ipv4Addr, SocketAddr}, path::{Path, PathBuf}, str::FromStr, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use clap::Parser;
+use serde::{Deserialize, Deserializer};
+
+use bluebubbles_core::database::{Database, DatabaseOptions, OpenMode};
+
+/// Looked for in the working directory when no `--config` is given.
+const DEFAULT_CONFIG_FILE: &str = "bluebubbles.toml";
+
+/// Command line flags. Every flag can also be set through the environment variable next to it,
+/// both take precedence over the config file.
+#[derive(Debug, Default, Parser)]
+#[command(version, about = "BlueBubbles server that reads straight from chat.db")]
+pub struct Args {
+    /// TOML config file, defaults to ./bluebubbles.toml when it exists.
+    #[arg(long, short, env = "BLUEBUBBLES_CONFIG")]
+    pub config: Option<PathBuf>,
+    /// Password clients have to send with every request.
+    #[arg(long, env = "BLUEBUBBLES_PASSWORD", hide_env_values = true)]
+    pub password: Option<String>,
+    #[arg(long, env = "BLUEBUBBLES_HOST")]
+    pub host: Option<IpAddr>,
+    #[arg(long, env = "BLUEBUBBLES_PORT")]
+    pub port: Option<u16>,
+    #[arg(long, env = "BLUEBUBBLES_CHAT_DB")]
+    pub chat_db: Option<PathBuf>,
+    /// read-write, read-only or immutable (only for copies of chat.db).
+    #[arg(long, env = "BLUEBUBBLES_CHAT_DB_MODE")]
+    pub chat_db_mode: Option<OpenMode>,
+    /// Directory attachments may be served from, can be repeated. The environment variable takes a `:` separated list.
+    #[arg(long = "attachment-root", env = "BLUEBUBBLES_ATTACHMENT_ROOTS", value_delimiter = ':')]
+    pub attachment_roots: Vec<PathBuf>,
+    /// SQLite file the message search index is kept in, created when missing.
+    #[arg(long, env = "BLUEBUBBLES_SEARCH_INDEX")]
+    pub search_index: Option<PathBuf>,
+    /// SQLite file the width and height of image attachments are cached in, created when missing.
+    #[arg(long, env = "BLUEBUBBLES_DIMENSION_CACHE")]
+    pub dimension_cache: Option<PathBuf>,
+    /// Directory resized attachment images are cached in, created when missing.
+    #[arg(long, env = "BLUEBUBBLES_THUMBNAIL_CACHE")]
+    pub thumbnail_cache: Option<PathBuf>,
+    /// How often chat.db is checked for changes, in milliseconds.
+    #[arg(long, env = "BLUEBUBBLES_POLL_INTERVAL_MS")]
+    pub poll_interval_ms: Option<u64>,
+    /// A tracing filter such as `info` or `bluebubbles_server=debug`.
+    #[arg(long, env = "BLUEBUBBLES_LOG")]
+    pub log_level: Option<String>,
+}
+
+/// Everything the server can be configured with, as read from the config file.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub password: String,
+    pub host: IpAddr,
+    pub port: u16,
+    pub chat_db: PathBuf,
+    #[serde(deserialize_with = "from_str")]
+    pub chat_db_mode: OpenMode,
+    pub attachment_roots: Vec<PathBuf>,
+    pub search_index: PathBuf,
+    pub dimension_cache: PathBuf,
+    pub thumbnail_cache: PathBuf,
+    pub poll_interval_ms: u64,
+    pub log_level: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        let home = PathBuf::from(std::env::var("HOME").unwrap_or_default());
+        Self {
+            password: String::new(),
+            host: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            port: 8000,
+            chat_db: Database::default_path(),
+            chat_db_mode: OpenMode::default(),
+            attachment_roots: vec![home.join("Library/Messages/Attachments")],
+            search_index: PathBuf::from("bluebubbles-search.db"),
+            dimension_cache: PathBuf::from("bluebubbles-dimensions.db"),
+            thumbnail_cache: PathBuf::from("bluebubbles-thumbnails"),
+            poll_interval_ms: 1000,
+            log_level: "info".into(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    MissingPassword,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Read(path, err) => write!(f, "failed to read {}: {err}", path.display()),
+            Self::Parse(path, err) => write!(f, "invalid config file {}: {err}", path.display()),
+            Self::MissingPassword => f.write_str("no password configured, set `password` in the config file, BLUEBUBBLES_PASSWORD or --password"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    /// Reads the config file named by `args` (or the default one if it exists) and applies the flags on top.
+    pub fn load(args: Args) -> Result<Self, ConfigError> {
+        let file = match &args.config {
+            Some(path) => Some(read(path)?),
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => Some(read(Path::new(DEFAULT_CONFIG_FILE))?),
+            None => None,
+        };
+        let config = match file {
+            Some((path, contents)) => toml::from_str(&contents).map_err(|err| ConfigError::Parse(path, err))?,
+            None => Config::default(),
+        };
+        return config.merge(args);
+    }
+
+    fn merge(mut self, args: Args) -> Result<Self, ConfigError> {
+        if let Some(password) = args.password {
+            self.password = password;
+        }
+        if let Some(host) = args.host {
+            self.host = host;
+        }
+        if let Some(port) = args.port {
+            self.port = port;
+        }
+        if let Some(chat_db) = args.chat_db {
+            self.chat_db = chat_db;
+        }
+        if let Some(chat_db_mode) = args.chat_db_mode {
+            self.chat_db_mode = chat_db_mode;
+        }
+        if !args.attachment_roots.is_empty() {
+            self.attachment_roots = args.attachment_roots;
+        }
+        if let Some(search_index) = args.search_index {
+            self.search_index = search_index;
+        }
+        if let Some(dimension_cache) = args.dimension_cache {
+            self.dimension_cache = dimension_cache;
+        }
+        if let Some(thumbnail_cache) = args.thumbnail_cache {
+            self.thumbnail_cache = thumbnail_cache;
+        }
+        if let Some(poll_interval_ms) = args.poll_interval_ms {
+            self.poll_interval_ms = poll_interval_ms;
+        }
+        if let Some(log_level) = args.log_level {
+            self.log_level = log_level;
+        }
+        if self.password.is_empty() {
+            return Err(ConfigError::MissingPassword);
+        }
+        return Ok(self);
+    }
+
+    pub fn bind_address(&self) -> SocketAddr {
+        SocketAddr::new(self.host, self.port)
+    }
+
+    pub fn poll_interval(&self) -> Duration {
+        Duration::from_millis(self.poll_interval_ms.max(1))
+    }
+
+    /// How chat.db is opened, `poll` only reports messages that arrive after this is called.
+    pub fn database_options(&self) -> DatabaseOptions {
+        DatabaseOptions {
+            mode: self.chat_db_mode,
+            last_read_time: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos(),
+            dimension_cache: Some(self.dimension_cache.clone()),
+        }
+    }
+}
+
+fn read(path: &Path) -> Result<(PathBuf, String), ConfigError> {
+    let contents = std::fs::read_to_string(path).map_err(|err| ConfigError::Read(path.to_path_buf(), err))?;
+    return Ok((path.to_path_buf(), contents));
+}
+
+fn from_str<'de, D: Deserializer<'de>, T: FromStr<Err = String>>(deserializer: D) -> Result<T, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    return value.parse().map_err(serde::de::Error::custom);
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use bluebubbles_core::database::OpenMode;
+
+    use super::{Args, Config, ConfigError};
+
+    #[test]
+    fn test_file_and_overrides() {
+        let file: Config = toml::from_str(r#"
+            password = "from-file"
+            port = 1234
+            chat_db = "/tmp/chat.db"
+            chat_db_mode = "immutable"
+            attachment_roots = ["/tmp/attachments"]
+        "#).unwrap();
+        assert_eq!(file.password, "from-file");
+        assert_eq!(file.chat_db_mode, OpenMode::Immutable);
+        assert_eq!(file.poll_interval_ms, 1000);
+
+        let config = file.clone().merge(Args {
+            port: Some(4321),
+            attachment_roots: vec![PathBuf::from("/a"), PathBuf::from("/b")],
+            ..Default::default()
+        }).unwrap();
+        assert_eq!(config.bind_address().to_string(), "0.0.0.0:4321");
+        assert_eq!(config.password, "from-file");
+        assert_eq!(config.chat_db, PathBuf::from("/tmp/chat.db"));
+        assert_eq!(config.attachment_roots, vec![PathBuf::from("/a"), PathBuf::from("/b")]);
+
+        assert!(toml::from_str::<Config>("chat_db_mode = \"sometimes\"").is_err());
+        assert!(toml::from_str::<Config>("passwrod = \"typo\"").is_err());
+        assert!(matches!(Config::default().merge(Args::default()), Err(ConfigError::MissingPassword)));
+    }
+}
@@ -0,0 +1,170 @@
+//! Streaming file responses with `Range` support, so clients can seek in videos without downloading
+//! them first. Only single ranges are served, anything else gets the whole file.
+
+use std::io::SeekFrom;
+
+use axum::{body::Body, response::{IntoResponse, Response}};
+use hyper::{header::{HeaderName, HeaderValue}, StatusCode};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use tokio::{fs::File, io::{AsyncReadExt, AsyncSeekExt, Take}};
+use tokio_util::io::ReaderStream;
+
+/// The part of a file a `Range` header asks for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ByteRange {
+    /// No usable `Range` header.
+    Full,
+    /// First and last byte, inclusive.
+    Partial(u64, u64),
+    /// The range starts past the end of the file.
+    Unsatisfiable,
+}
+
+impl ByteRange {
+    /// Parses `bytes=start-end`, `bytes=start-` and `bytes=-suffix` for a file of `size` bytes.
+    /// Malformed headers and multiple ranges are ignored, as RFC 9110 allows.
+    pub fn parse(header: Option<&str>, size: u64) -> Self {
+        let Some(spec) = header.and_then(|header| header.trim().strip_prefix("bytes=")) else {
+            return Self::Full;
+        };
+        let Some((start, end)) = spec.trim().split_once('-') else {
+            return Self::Full;
+        };
+        if spec.contains(',') {
+            return Self::Full;
+        }
+        let (start, end) = match (start.trim(), end.trim()) {
+            ("", suffix) => match suffix.parse::<u64>() {
+                Ok(0) => return Self::Unsatisfiable,
+                Ok(suffix) => (size.saturating_sub(suffix), size.saturating_sub(1)),
+                Err(_) => return Self::Full,
+            },
+            (start, "") => match start.parse::<u64>() {
+                Ok(start) => (start, size.saturating_sub(1)),
+                Err(_) => return Self::Full,
+            },
+            (start, end) => match (start.parse::<u64>(), end.parse::<u64>()) {
+                (Ok(start), Ok(end)) if start <= end => (start, end.min(size.saturating_sub(1))),
+                _ => return Self::Full,
+            },
+        };
+        if size == 0 || start >= size {
+            return Self::Unsatisfiable;
+        }
+        return Self::Partial(start, end);
+    }
+}
+
+/// `attachment` with the name quoted for old clients and percent encoded for the rest.
+pub fn content_disposition(file_name: &str) -> String {
+    let fallback: String = file_name.chars().map(|c| if c.is_ascii_graphic() && c != '"' && c != '\\' || c == ' ' { c } else { '_' }).collect();
+    return format!("attachment; filename=\"{fallback}\"; filename*=UTF-8''{}", utf8_percent_encode(file_name, NON_ALPHANUMERIC));
+}
+
+/// A file ready to be streamed: the status, headers and the bytes the body still has to read.
+#[derive(Debug)]
+pub struct Download {
+    pub status: u16,
+    pub headers: Vec<(&'static str, String)>,
+    /// Positioned at the first byte to send and limited to the last one.
+    pub body: Take<File>,
+}
+
+impl Download {
+    pub async fn open(mut file: File, content_type: &str, file_name: &str, range: Option<&str>) -> std::io::Result<Self> {
+        let size = file.metadata().await?.len();
+        let mut headers = vec![
+            ("Content-Type", content_type.to_string()),
+            ("Content-Disposition", content_disposition(file_name)),
+            ("Accept-Ranges", "bytes".to_string()),
+        ];
+        let (status, start, length) = match ByteRange::parse(range, size) {
+            ByteRange::Full => (200, 0, size),
+            ByteRange::Partial(start, end) => {
+                headers.push(("Content-Range", format!("bytes {start}-{end}/{size}")));
+                (206, start, end - start + 1)
+            },
+            ByteRange::Unsatisfiable => {
+                headers.push(("Content-Range", format!("bytes */{size}")));
+                (416, 0, 0)
+            },
+        };
+        headers.push(("Content-Length", length.to_string()));
+        if start > 0 {
+            file.seek(SeekFrom::Start(start)).await?;
+        }
+        return Ok(Self { status, headers, body: file.take(length) });
+    }
+
+    /// The body as a stream of chunks.
+    pub fn stream(self) -> ReaderStream<Take<File>> {
+        ReaderStream::new(self.body)
+    }
+}
+
+impl IntoResponse for Download {
+    fn into_response(self) -> Response {
+        let mut response = Response::new(Body::empty());
+        *response.status_mut() = StatusCode::from_u16(self.status).unwrap_or(StatusCode::OK);
+        for (name, value) in &self.headers {
+            if let (Ok(name), Ok(value)) = (HeaderName::from_bytes(name.as_bytes()), HeaderValue::from_str(value)) {
+                response.headers_mut().insert(name, value);
+            }
+        }
+        *response.body_mut() = Body::from_stream(self.stream());
+        return response;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::io::AsyncReadExt;
+
+    use super::{content_disposition, ByteRange, Download};
+
+    #[test]
+    fn test_byte_range() {
+        assert_eq!(ByteRange::parse(None, 100), ByteRange::Full);
+        assert_eq!(ByteRange::parse(Some("bytes=0-9"), 100), ByteRange::Partial(0, 9));
+        assert_eq!(ByteRange::parse(Some("bytes=90-"), 100), ByteRange::Partial(90, 99));
+        assert_eq!(ByteRange::parse(Some("bytes=-10"), 100), ByteRange::Partial(90, 99));
+        assert_eq!(ByteRange::parse(Some("bytes=-500"), 100), ByteRange::Partial(0, 99));
+        assert_eq!(ByteRange::parse(Some("bytes=50-500"), 100), ByteRange::Partial(50, 99));
+        assert_eq!(ByteRange::parse(Some("bytes=100-"), 100), ByteRange::Unsatisfiable);
+        assert_eq!(ByteRange::parse(Some("bytes=-0"), 100), ByteRange::Unsatisfiable);
+        assert_eq!(ByteRange::parse(Some("bytes=0-"), 0), ByteRange::Unsatisfiable);
+        assert_eq!(ByteRange::parse(Some("bytes=0-1,5-6"), 100), ByteRange::Full);
+        assert_eq!(ByteRange::parse(Some("bytes=9-0"), 100), ByteRange::Full);
+        assert_eq!(ByteRange::parse(Some("items=0-9"), 100), ByteRange::Full);
+    }
+
+    #[test]
+    fn test_content_disposition() {
+        assert_eq!(content_disposition("IMG 1.jpeg"), "attachment; filename=\"IMG 1.jpeg\"; filename*=UTF-8''IMG%201%2Ejpeg");
+        assert_eq!(content_disposition("\"ü\".txt"), "attachment; filename=\"___.txt\"; filename*=UTF-8''%22%C3%BC%22%2Etxt");
+    }
+
+    #[tokio::test]
+    async fn test_download() {
+        let path = std::env::temp_dir().join(format!("bluebubbles-download-{}", std::process::id()));
+        std::fs::write(&path, b"0123456789").unwrap();
+
+        let mut download = Download::open(tokio::fs::File::open(&path).await.unwrap(), "text/plain", "digits.txt", Some("bytes=2-4")).await.unwrap();
+        assert_eq!(download.status, 206);
+        assert!(download.headers.contains(&("Content-Range", "bytes 2-4/10".into())));
+        assert!(download.headers.contains(&("Content-Length", "3".into())));
+        let mut body = String::new();
+        download.body.read_to_string(&mut body).await.unwrap();
+        assert_eq!(body, "234");
+
+        let mut download = Download::open(tokio::fs::File::open(&path).await.unwrap(), "text/plain", "digits.txt", None).await.unwrap();
+        let mut body = String::new();
+        download.body.read_to_string(&mut body).await.unwrap();
+        assert_eq!((download.status, body.as_str()), (200, "0123456789"));
+
+        let download = Download::open(tokio::fs::File::open(&path).await.unwrap(), "text/plain", "digits.txt", Some("bytes=10-")).await.unwrap();
+        assert_eq!(download.status, 416);
+        assert!(download.headers.contains(&("Content-Range", "bytes */10".into())));
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,181 @@
+use std::fmt;
+
+use axum::{extract::rejection::JsonRejection, response::{IntoResponse, Response}, Json};
+use hyper::StatusCode;
+use serde_json::{json, Value};
+
+use crate::storage::StorageError;
+
+/// Everything a request can fail with, rendered as the BlueBubbles error envelope
+/// `{status, message, error: {type, message}}` with a matching HTTP status.
+#[derive(Debug)]
+pub enum ServerError {
+    /// A parameter was missing or could not be parsed.
+    BadRequest(String),
+    /// The server password was missing or wrong.
+    Unauthorized,
+    /// The chat, message or attachment asked for doesn't exist.
+    NotFound(String),
+    /// The attachment file is outside the configured attachment roots.
+    Forbidden(String),
+    /// The attachment exists but its file is only in iCloud (or still being received).
+    NotDownloaded(String),
+    Database(rusqlite::Error),
+    Io(std::io::Error),
+}
+
+impl ServerError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::BadRequest(_) => StatusCode::BAD_REQUEST,
+            Self::Unauthorized => StatusCode::UNAUTHORIZED,
+            Self::NotFound(_) => StatusCode::NOT_FOUND,
+            Self::Forbidden(_) => StatusCode::FORBIDDEN,
+            Self::NotDownloaded(_) => StatusCode::CONFLICT,
+            Self::Database(_) | Self::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// The `error.type` upstream BlueBubbles clients switch on.
+    pub fn error_type(&self) -> &'static str {
+        match self {
+            Self::BadRequest(_) => "Validation Error",
+            Self::Unauthorized => "Authentication Error",
+            Self::NotFound(_) | Self::Database(_) => "Database Error",
+            Self::NotDownloaded(_) => "iCloud Error",
+            Self::Forbidden(_) | Self::Io(_) => "Server Error",
+        }
+    }
+
+    pub fn message(&self) -> &'static str {
+        match self {
+            Self::BadRequest(_) => "Bad Request",
+            Self::Unauthorized => "You are not authorized to access this resource",
+            Self::NotFound(_) => "Not Found",
+            Self::Forbidden(_) => "Forbidden",
+            Self::NotDownloaded(_) => "Attachment Not Downloaded",
+            Self::Database(_) | Self::Io(_) => "Internal Server Error",
+        }
+    }
+
+    /// The `error.message` clients see. Database and io errors can contain SQL, column names and paths, so
+    /// clients only get a fixed string and the details go to the log.
+    pub fn client_message(&self) -> String {
+        match self {
+            Self::Database(_) => "Database error".into(),
+            Self::Io(_) => "Io error".into(),
+            _ => self.to_string(),
+        }
+    }
+
+    /// Logs server errors, every binary builds its responses from this.
+    pub fn envelope(&self) -> Value {
+        if self.status().is_server_error() {
+            tracing::error!("request failed: {self}");
+        }
+        json!({
+            "status": self.status().as_u16(),
+            "message": self.message(),
+            "error": {
+                "type": self.error_type(),
+                "message": self.client_message(),
+            },
+        })
+    }
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BadRequest(message) | Self::NotFound(message) | Self::Forbidden(message) | Self::NotDownloaded(message) => f.write_str(message),
+            Self::Unauthorized => f.write_str("Unauthorized"),
+            Self::Database(err) => write!(f, "database error: {err}"),
+            Self::Io(err) => write!(f, "io error: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for ServerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Database(err) => Some(err),
+            Self::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<rusqlite::Error> for ServerError {
+    fn from(err: rusqlite::Error) -> Self {
+        match err {
+            rusqlite::Error::QueryReturnedNoRows => Self::NotFound("No matching row".into()),
+            err => Self::Database(err),
+        }
+    }
+}
+
+impl From<std::io::Error> for ServerError {
+    fn from(err: std::io::Error) -> Self {
+        match err.kind() {
+            std::io::ErrorKind::NotFound => Self::NotFound(err.to_string()),
+            _ => Self::Io(err),
+        }
+    }
+}
+
+impl From<StorageError> for ServerError {
+    fn from(err: StorageError) -> Self {
+        match err {
+            StorageError::NotDownloaded => Self::NotDownloaded(err.to_string()),
+            StorageError::Missing => Self::NotFound(err.to_string()),
+            StorageError::OutsideRoots(ref path) => {
+                tracing::warn!("refusing to serve {path:?}");
+                Self::Forbidden("Attachment is outside the attachment roots".into())
+            },
+            StorageError::Io(err) => Self::Io(err),
+        }
+    }
+}
+
+impl From<JsonRejection> for ServerError {
+    fn from(rejection: JsonRejection) -> Self {
+        Self::BadRequest(rejection.body_text())
+    }
+}
+
+impl IntoResponse for ServerError {
+    fn into_response(self) -> Response {
+        (self.status(), Json(self.envelope())).into_response()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ServerError;
+    use crate::storage::StorageError;
+
+    #[test]
+    fn test_envelope() {
+        let err = ServerError::BadRequest("Invalid value for limit: \"ten\"".into());
+        assert_eq!(err.status().as_u16(), 400);
+        assert_eq!(err.envelope(), serde_json::json!({
+            "status": 400,
+            "message": "Bad Request",
+            "error": {"type": "Validation Error", "message": "Invalid value for limit: \"ten\""},
+        }));
+        assert_eq!(ServerError::Unauthorized.envelope(), serde_json::json!({
+            "status": 401,
+            "message": "You are not authorized to access this resource",
+            "error": {"type": "Authentication Error", "message": "Unauthorized"},
+        }));
+        assert_eq!(ServerError::from(rusqlite::Error::QueryReturnedNoRows).status().as_u16(), 404);
+        assert_eq!(ServerError::from(std::io::Error::from(std::io::ErrorKind::NotFound)).status().as_u16(), 404);
+        assert_eq!(ServerError::from(rusqlite::Error::InvalidQuery).status().as_u16(), 500);
+        let err = ServerError::from(rusqlite::Error::SqliteFailure(rusqlite::ffi::Error::new(1), Some("no such column: m.secret".into())));
+        assert!(err.to_string().contains("m.secret"));
+        assert_eq!(err.envelope()["error"]["message"], "Database error");
+        assert_eq!(ServerError::from(std::io::Error::other("/Users/me/Library")).envelope()["error"]["message"], "Io error");
+        assert_eq!(ServerError::from(StorageError::NotDownloaded).envelope()["error"]["type"], "iCloud Error");
+        assert_eq!(ServerError::from(StorageError::OutsideRoots("/etc/passwd".into())).to_string(), "Attachment is outside the attachment roots");
+    }
+}
@@ -0,0 +1,10 @@
+//! The BlueBubbles REST API on top of `bluebubbles-core`. [`service::ApiService`] implements every
+//! route, the axum, Rocket and hyper binaries only adapt it to their HTTP stack.
+#![allow(clippy::needless_return)]
+
+pub mod auth;
+pub mod config;
+pub mod download;
+pub mod error;
+pub mod service;
+pub mod storage;
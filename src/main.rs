@@ -1,352 +1,490 @@
-use std::{collections::HashMap, process::Command, sync::Arc, time::{SystemTime, UNIX_EPOCH}};
+#![allow(clippy::needless_return)]
+use std::{collections::HashMap, future::Future, sync::Arc};
 
-use database::Database;
-use hyper::{StatusCode, Uri};
-use serde::{Deserialize, Serialize};
-use serde_json::Value;
-use socketioxide::{extract::{Bin, Data, SocketRef}, SocketIo};
-use tokio::{fs::File, io::AsyncReadExt, sync::Mutex};
-use axum::{body::Body, extract::Query, http::HeaderValue, response::{IntoResponse, Response}, routing::{get, post}, Json};
-use axum::extract::Path;
+use axum::{extract::{rejection::JsonRejection, Path, Query}, middleware, response::Html, routing::{get, post}, Json};
+use base64::{prelude::BASE64_STANDARD, Engine};
+use bluebubbles_core::{api::{ChatReadStatus, MessageEvent}, database::{ChangeEvent, ChatSort, Database, MessageCursor, MessageFilter, MessageQuery, SortOrder}, search::SearchIndex, structs::{Attachment, Chat, Message, Participant}};
+use bluebubbles_server::{auth, config::{Args, Config}, error::ServerError, service::{millis_to_apple, parse_optional, ApiResponse, ApiService, AttachmentChunkRequest, AttachmentRequest, ChatMessagesRequest, ChatQuery, ChatQueryRequest, ChatRequest, HandleChatsRequest, HandleQuery, HandleRequest, MessageQueryBody, MessageQueryRequest, MessageRequest, MessageSearchBody, MessageThreadRequest, WhereClause, DEFAULT_CHUNK_SIZE, DEFAULT_PAGE_SIZE}};
+use clap::Parser;
+use hyper::{header::{AUTHORIZATION, RANGE}, HeaderMap, StatusCode, Uri};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Value};
+use socketioxide::{extract::{AckSender, Bin, Data, SocketRef}, SocketIo};
+use tokio::{sync::broadcast::{self, error::RecvError}, time::MissedTickBehavior};
+use tracing::{debug, error, info, warn};
+use tracing_subscriber::EnvFilter;
 
-use crate::util::unix_to_apple;
+fn socket_conn(socket: SocketRef, service: Arc<ApiService>) {
+    let parts = socket.req_parts();
+    let authorization = parts.headers.get(AUTHORIZATION).and_then(|value| value.to_str().ok());
+    if service.authorize(parts.uri.query(), authorization).is_err() {
+        warn!("Socket.IO rejected unauthorized client {:?}", socket.id);
+        socket.disconnect().ok();
+        return;
+    }
+    info!("Socket.IO connected: {:?} {:?} {:?}", socket.ns(), socket.id, socket.transport_type());
 
-mod database;
-mod structs;
-mod util;
-const UNAUTHORIZED: &str = "{\"status\":401,\"message\":\"You are not authorized to access this resource\",\"error\":{\"type\":\"Authentication Error\",\"message\":\"Unauthorized\"}}";
-const VERSION: &str = "0.0.1";
-
-fn socket_conn(socket: SocketRef) {
-    
-    println!("Socket.IO connected: {:?} {:?} {:?}", socket.ns(), socket.id, socket.transport_type());
+    on_request(&socket, &service, "get-chats", socket_get_chats);
+    on_request(&socket, &service, "get-chat", socket_get_chat);
+    on_request(&socket, &service, "get-chat-messages", socket_get_chat_messages);
+    on_request(&socket, &service, "get-messages", socket_get_messages);
+    on_request(&socket, &service, "get-last-chat-message", socket_get_last_chat_message);
+    on_request(&socket, &service, "get-participants", socket_get_participants);
+    on_request(&socket, &service, "get-attachment", socket_get_attachment);
+    on_attachment_chunk(&socket, &service);
 
     socket.on(
         "get-server-metadata",
-        |socket: SocketRef, Data::<Value>(data), Bin(bin)| {
-            println!("Received event: {:?} {:?}", data, bin);
+        |_socket: SocketRef, Data::<Value>(data), Bin(bin)| {
+            debug!("Received event: {:?} {:?}", data, bin);
         },
     );
     socket.on(
         "save-vcf",
-        |socket: SocketRef, Data::<Value>(data), Bin(bin)| {
-            println!("Received event: {:?} {:?}", data, bin);
+        |_socket: SocketRef, Data::<Value>(data), Bin(bin)| {
+            debug!("Received event: {:?} {:?}", data, bin);
         },
     );
     socket.on(
         "get-vcf",
-        |socket: SocketRef, Data::<Value>(data), Bin(bin)| {
-            println!("Received event: {:?} {:?}", data, bin);
+        |_socket: SocketRef, Data::<Value>(data), Bin(bin)| {
+            debug!("Received event: {:?} {:?}", data, bin);
         },
     );
     socket.on(
         "change-proxy-service",
-        |socket: SocketRef, Data::<Value>(data), Bin(bin)| {
-            println!("Received event: {:?} {:?}", data, bin);
+        |_socket: SocketRef, Data::<Value>(data), Bin(bin)| {
+            debug!("Received event: {:?} {:?}", data, bin);
         },
     );
     socket.on(
         "get-server-config",
-        |socket: SocketRef, Data::<Value>(data), Bin(bin)| {
-            println!("Received event: {:?} {:?}", data, bin);
+        |_socket: SocketRef, Data::<Value>(data), Bin(bin)| {
+            debug!("Received event: {:?} {:?}", data, bin);
         },
     );
     socket.on(
         "add-fcm-device",
-        |socket: SocketRef, Data::<Value>(data), Bin(bin)| {
-            println!("Received event: {:?} {:?}", data, bin);
+        |_socket: SocketRef, Data::<Value>(data), Bin(bin)| {
+            debug!("Received event: {:?} {:?}", data, bin);
         },
     );
     socket.on(
         "get-fcm-client",
-        |socket: SocketRef, Data::<Value>(data), Bin(bin)| {
-            println!("Received event: {:?} {:?}", data, bin);
+        |_socket: SocketRef, Data::<Value>(data), Bin(bin)| {
+            debug!("Received event: {:?} {:?}", data, bin);
         },
     );
     socket.on(
         "get-logs",
-        |socket: SocketRef, Data::<Value>(data), Bin(bin)| {
-            println!("Received event: {:?} {:?}", data, bin);
-        },
-    );
-    socket.on(
-        "get-chats",
-        |socket: SocketRef, Data::<Value>(data), Bin(bin)| {
-            println!("Received event: {:?} {:?}", data, bin);
-        },
-    );
-    socket.on(
-        "get-chat",
-        |socket: SocketRef, Data::<Value>(data), Bin(bin)| {
-            println!("Received event: {:?} {:?}", data, bin);
-        },
-    );
-    socket.on(
-        "get-chat-messages",
-        |socket: SocketRef, Data::<Value>(data), Bin(bin)| {
-            println!("Received event: {:?} {:?}", data, bin);
-        },
-    );
-    socket.on(
-        "get-messages",
-        |socket: SocketRef, Data::<Value>(data), Bin(bin)| {
-            println!("Received event: {:?} {:?}", data, bin);
-        },
-    );
-    socket.on(
-        "get-attachment",
-        |socket: SocketRef, Data::<Value>(data), Bin(bin)| {
-            println!("Received event: {:?} {:?}", data, bin);
-        },
-    );
-    socket.on(
-        "get-attachment-chunk",
-        |socket: SocketRef, Data::<Value>(data), Bin(bin)| {
-            println!("Received event: {:?} {:?}", data, bin);
-        },
-    );
-    socket.on(
-        "get-last-chat-message",
-        |socket: SocketRef, Data::<Value>(data), Bin(bin)| {
-            println!("Received event: {:?} {:?}", data, bin);
-        },
-    );
-    socket.on(
-        "get-participants",
-        |socket: SocketRef, Data::<Value>(data), Bin(bin)| {
-            println!("Received event: {:?} {:?}", data, bin);
+        |_socket: SocketRef, Data::<Value>(data), Bin(bin)| {
+            debug!("Received event: {:?} {:?}", data, bin);
         },
     );
     socket.on(
         "send-message",
-        |socket: SocketRef, Data::<Value>(data), Bin(bin)| {
-            println!("Received event: {:?} {:?}", data, bin);
+        |_socket: SocketRef, Data::<Value>(data), Bin(bin)| {
+            debug!("Received event: {:?} {:?}", data, bin);
         },
     );
     socket.on(
         "send-message-chunk",
-        |socket: SocketRef, Data::<Value>(data), Bin(bin)| {
-            println!("Received event: {:?} {:?}", data, bin);
+        |_socket: SocketRef, Data::<Value>(data), Bin(bin)| {
+            debug!("Received event: {:?} {:?}", data, bin);
         },
     );
     socket.on(
         "get-contacts-from-vcf",
-        |socket: SocketRef, Data::<Value>(data), Bin(bin)| {
-            println!("Received event: {:?} {:?}", data, bin);
+        |_socket: SocketRef, Data::<Value>(data), Bin(bin)| {
+            debug!("Received event: {:?} {:?}", data, bin);
         },
     );
 }
 
-async fn fallback(uri: Uri) -> (StatusCode, String) {
-    println!("client requested unknown page {uri}");
-    (StatusCode::NOT_FOUND, format!("No route for {uri}"))
+/// Registers an event that answers through the acknowledgement, with the same envelopes as the REST routes.
+fn on_request<F, Fut, T>(socket: &SocketRef, service: &Arc<ApiService>, event: &'static str, handler: F)
+where
+    F: Fn(Arc<ApiService>, Value) -> Fut + Send + Sync + Clone + 'static,
+    Fut: Future<Output = Result<ApiResponse<T>, ServerError>> + Send + 'static,
+    T: Serialize,
+{
+    let service = service.clone();
+    socket.on(event, move |Data::<Value>(data), ack: AckSender| async move {
+        debug!("Received event: {event} {data:?}");
+        let response = handler(service, data).await.map(|response| json!(response)).unwrap_or_else(|err| {
+            warn!("{event} failed: {err}");
+            err.envelope()
+        });
+        ack.send(response).ok();
+    });
 }
 
-struct State<'a> {
-    database: Mutex<Database>,
-    password: &'a str,
+/// `get-attachment-chunk` answers with the chunk base64 encoded in `data`, or with `binary` set, as a binary
+/// attachment of the acknowledgement so it doesn't grow by a third. `data` is null once the file was read to the end.
+fn on_attachment_chunk(socket: &SocketRef, service: &Arc<ApiService>) {
+    let service = service.clone();
+    socket.on("get-attachment-chunk", move |Data::<Value>(data), ack: AckSender| async move {
+        debug!("Received event: get-attachment-chunk {data:?}");
+        let sent = match socket_get_attachment_chunk(service, data).await {
+            Ok((true, Some(chunk))) => ack.bin(vec![chunk]).send(json!(ApiResponse::success(Value::Null))),
+            Ok((_, Some(chunk))) => ack.send(json!(ApiResponse::success(BASE64_STANDARD.encode(chunk)))),
+            Ok((_, None)) => ack.send(json!(ApiResponse::with_message("No data", Value::Null))),
+            Err(err) => {
+                warn!("get-attachment-chunk failed: {err}");
+                ack.send(err.envelope())
+            },
+        };
+        if let Err(err) = sent {
+            warn!("failed to send attachment chunk: {err}");
+        }
+    });
 }
 
-#[derive(Deserialize, Debug)]
-struct ChatQuery {
+/// Older clients send no payload at all when they don't need any parameters.
+fn socket_params<T: DeserializeOwned + Default>(data: Value) -> Result<T, ServerError> {
+    if data.is_null() {
+        return Ok(T::default());
+    }
+    serde_json::from_value(data).map_err(|err| ServerError::BadRequest(err.to_string()))
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase", default)]
+struct SocketChatsParams {
+    with_participants: Option<bool>,
+    with_last_message: bool,
     limit: Option<usize>,
-    offset: Option<usize>,
-    with: Option<Vec<String>>,
+    offset: usize,
     sort: Option<String>,
 }
 
-#[derive(Serialize)]
-struct ServerInfo<'a> {
-    os_version: String,
-    server_version: &'a str,
-    private_api: bool,
-    proxy_service: &'a str,
-    helper_connected: bool,
-    detected_icloud: String,
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase", default)]
+struct SocketChatParams {
+    #[serde(alias = "identifier")]
+    chat_guid: Option<String>,
+    with_participants: Option<bool>,
+    with_last_message: bool,
+}
+
+impl SocketChatParams {
+    fn chat_guid(&self) -> Result<String, ServerError> {
+        self.chat_guid.clone().ok_or_else(|| ServerError::BadRequest("No chat GUID provided".into()))
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase", default)]
+struct SocketMessagesParams {
+    #[serde(alias = "identifier")]
+    chat_guid: Option<String>,
+    offset: usize,
+    limit: Option<usize>,
+    /// unix time in milliseconds
+    after: Option<u128>,
+    /// unix time in milliseconds
+    before: Option<u128>,
+    sort: Option<String>,
+    cursor: Option<String>,
+    with_attachments: Option<bool>,
+    with_handle: Option<bool>,
+    with_reactions: bool,
+    with_reply_count: bool,
+    #[serde(rename = "where")]
+    filters: Vec<WhereClause>,
+}
+
+impl SocketMessagesParams {
+    fn query(self) -> Result<MessageQuery, ServerError> {
+        Ok(MessageQuery {
+            attachments: self.with_attachments.unwrap_or(true),
+            handle: self.with_handle.unwrap_or(true),
+            reactions: self.with_reactions,
+            reply_counts: self.with_reply_count,
+            offset: self.offset,
+            limit: self.limit.unwrap_or(DEFAULT_PAGE_SIZE),
+            sort: parse_optional::<SortOrder>(self.sort.as_deref())?.unwrap_or_default(),
+            after: self.after.map(millis_to_apple).unwrap_or(0),
+            before: self.before.map(millis_to_apple).unwrap_or(u128::MAX),
+            cursor: parse_optional::<MessageCursor>(self.cursor.as_deref())?,
+            filters: self.filters.into_iter().map(MessageFilter::try_from).collect::<Result<_, _>>()?,
+        })
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase", default)]
+struct SocketAttachmentParams {
+    #[serde(alias = "guid")]
+    identifier: Option<String>,
+    start: u64,
+    chunk_size: Option<usize>,
+    compress: bool,
+    binary: bool,
+}
+
+impl SocketAttachmentParams {
+    fn guid(&self) -> Result<String, ServerError> {
+        self.identifier.clone().filter(|guid| !guid.is_empty()).ok_or_else(|| ServerError::BadRequest("No attachment identifier provided".into()))
+    }
+
+    fn chunk_request(&self) -> Result<AttachmentChunkRequest, ServerError> {
+        Ok(AttachmentChunkRequest {
+            guid: self.guid()?,
+            start: self.start,
+            chunk_size: self.chunk_size.unwrap_or(DEFAULT_CHUNK_SIZE),
+            compress: self.compress,
+        })
+    }
+}
+
+async fn socket_get_chats(service: Arc<ApiService>, data: Value) -> Result<ApiResponse<Vec<Chat>>, ServerError> {
+    let params: SocketChatsParams = socket_params(data)?;
+    service.chat_query(ChatQueryRequest {
+        limit: params.limit.unwrap_or(DEFAULT_PAGE_SIZE),
+        offset: params.offset,
+        sort: parse_optional::<ChatSort>(params.sort.as_deref())?.unwrap_or_default(),
+        with_last_message: params.with_last_message,
+        with_participants: params.with_participants.unwrap_or(true),
+    }).await
+}
+
+async fn socket_get_chat(service: Arc<ApiService>, data: Value) -> Result<ApiResponse<Chat>, ServerError> {
+    let params: SocketChatParams = socket_params(data)?;
+    service.chat(ChatRequest {
+        guid: params.chat_guid()?,
+        with_last_message: params.with_last_message,
+        with_participants: params.with_participants.unwrap_or(true),
+    }).await
+}
+
+async fn socket_get_chat_messages(service: Arc<ApiService>, data: Value) -> Result<ApiResponse<Vec<Message>>, ServerError> {
+    let params: SocketMessagesParams = socket_params(data)?;
+    let chat_guid = params.chat_guid.clone().ok_or_else(|| ServerError::BadRequest("No chat GUID provided".into()))?;
+    service.chat_messages(ChatMessagesRequest { chat_guid, query: params.query()? }).await
 }
 
-#[derive(Serialize)]
-struct Statistics {
-    handles: usize,
-    messages: usize,
-    chats: usize,
-    attachments: usize,
+/// Like `get-chat-messages`, but the chat is optional so clients can filter across all of them.
+async fn socket_get_messages(service: Arc<ApiService>, data: Value) -> Result<ApiResponse<Vec<Message>>, ServerError> {
+    let params: SocketMessagesParams = socket_params(data)?;
+    let chat_guid = params.chat_guid.clone().filter(|guid| !guid.is_empty());
+    service.message_query(MessageQueryRequest { chat_guid, query: params.query()? }).await
 }
 
-fn wrap_success(json: String) -> Response<Body> {
-    let mut res = format!("{{\"status\": 200, \"message\": \"Success\", \"data\": {}}}", json).into_response();
-    res.headers_mut().insert("Content-Type", HeaderValue::from_str("application/json").unwrap());
-    res
+async fn socket_get_last_chat_message(service: Arc<ApiService>, data: Value) -> Result<ApiResponse<Option<Message>>, ServerError> {
+    let params: SocketChatParams = socket_params(data)?;
+    service.last_chat_message(params.chat_guid()?).await
 }
 
-fn wrap_status(json: String, code: u32, message: String) -> Response<Body> {
-    let mut res = format!("{{\"status\": {}, \"message\": \"{}\", \"data\": {}}}", code, message, json).into_response();
-    res.headers_mut().insert("Content-Type", HeaderValue::from_str("application/json").unwrap());
-    res
+async fn socket_get_participants(service: Arc<ApiService>, data: Value) -> Result<ApiResponse<Vec<Participant>>, ServerError> {
+    let params: SocketChatParams = socket_params(data)?;
+    service.participants(params.chat_guid()?).await
+}
+
+async fn socket_get_attachment(service: Arc<ApiService>, data: Value) -> Result<ApiResponse<Attachment>, ServerError> {
+    let params: SocketAttachmentParams = socket_params(data)?;
+    service.attachment_info(params.guid()?).await
+}
+
+/// Whether the client wants the chunk as binary, and the chunk.
+async fn socket_get_attachment_chunk(service: Arc<ApiService>, data: Value) -> Result<(bool, Option<Vec<u8>>), ServerError> {
+    let params: SocketAttachmentParams = socket_params(data)?;
+    let chunk = service.attachment_chunk(params.chunk_request()?).await?;
+    Ok((params.binary, chunk))
+}
+
+async fn fallback(uri: Uri) -> (StatusCode, String) {
+    debug!("client requested unknown page {uri}");
+    (StatusCode::NOT_FOUND, format!("No route for {uri}"))
+}
+
+/// Polls chat.db for new and updated messages and publishes them to `events`.
+async fn poll_database(service: Arc<ApiService>, events: broadcast::Sender<ChangeEvent>) {
+    let mut interval = tokio::time::interval(service.config().poll_interval());
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        match service.poll().await {
+            Ok(changes) => {
+                if let Err(err) = service.update_search_index(&changes).await {
+                    error!("failed to update the search index: {err}");
+                }
+                for change in changes {
+                    match &change {
+                        ChangeEvent::NewMessage(message) => debug!("new message {}", message.guid),
+                        ChangeEvent::UpdatedMessage(message) => debug!("updated message {}", message.guid),
+                        ChangeEvent::ChatReadStatusChanged { chat_guid, .. } => debug!("read chat {chat_guid}"),
+                    }
+                    // no connected clients is not an error
+                    let _ = events.send(change);
+                }
+            },
+            Err(err) => error!("failed to poll chat.db: {err}"),
+        }
+    }
+}
+
+/// Forwards changes from the poller to every connected Socket.IO client.
+async fn emit_changes(service: Arc<ApiService>, mut events: broadcast::Receiver<ChangeEvent>, io: SocketIo) {
+    loop {
+        let change = match events.recv().await {
+            Ok(change) => change,
+            Err(RecvError::Lagged(skipped)) => {
+                warn!("socket.io fell behind the poller, dropped {skipped} changes");
+                continue;
+            },
+            Err(RecvError::Closed) => return,
+        };
+        let result = match change {
+            ChangeEvent::NewMessage(message) => {
+                let payload = with_chats(&service, message).await;
+                if payload.message.is_group_name_change() {
+                    if let Err(err) = io.emit("group-name-change", &payload) {
+                        warn!("failed to emit group-name-change: {err}");
+                    }
+                }
+                io.emit("new-message", &payload)
+            },
+            ChangeEvent::UpdatedMessage(message) => io.emit("updated-message", &with_chats(&service, message).await),
+            ChangeEvent::ChatReadStatusChanged { chat_guid, read } => io.emit("chat-read-status-changed", ChatReadStatus { chat_guid, read }),
+        };
+        if let Err(err) = result {
+            warn!("failed to emit change: {err}");
+        }
+    }
+}
+
+async fn with_chats(service: &ApiService, message: Message) -> MessageEvent {
+    let chat = service.chat(ChatRequest { guid: message.chat_guid.clone(), ..Default::default() }).await;
+    let chat = match chat {
+        Ok(response) => Some(response.data),
+        Err(err) => {
+            warn!("failed to look up chat {}: {err}", message.chat_guid);
+            None
+        },
+    };
+    MessageEvent {
+        message,
+        chats: chat.into_iter().collect(),
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    // point the server at a copy of chat.db instead of the live one with --chat-db ./chat.db --chat-db-mode immutable
+    let config = Config::load(Args::parse())?;
+    tracing_subscriber::fmt().with_env_filter(EnvFilter::try_new(&config.log_level)?).init();
+    info!("serving attachments from {:?}", config.attachment_roots);
     let (layer, io) = SocketIo::new_layer();
-    let database = Mutex::new(Database::new(SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos()));
-    let state = State {
-        database,
-        password: "balls",
-    };
-    let state_chat_guid = Arc::new(state);
-    let state_statistics = state_chat_guid.clone();
-    let state_update = state_chat_guid.clone();
-    let state_chat_query = state_chat_guid.clone();
-    let state_server_info = state_chat_guid.clone();
-    let state_contacts = state_chat_guid.clone();
-    let state_chat_count = state_chat_guid.clone();
-    let state_message_guid = state_chat_guid.clone();
-    let state_chat_message = state_chat_guid.clone();
-    let state_attachment_download = state_chat_guid.clone();
+    let database = Database::open(&config.chat_db, config.database_options())?;
+    let search = SearchIndex::open(&config.search_index)?;
+    let address = config.bind_address();
+    let service = Arc::new(ApiService::new(database, search, config));
+    let (events, _) = broadcast::channel(256);
+    tokio::spawn(emit_changes(service.clone(), events.subscribe(), io.clone()));
+    tokio::spawn(poll_database(service.clone(), events));
+    let service_statistics = service.clone();
+    let service_update = service.clone();
+    let service_chat_guid = service.clone();
+    let service_chat_query = service.clone();
+    let service_chat_count = service.clone();
+    let service_server_info = service.clone();
+    let service_contacts = service.clone();
+    let service_message_guid = service.clone();
+    let service_message_thread = service.clone();
+    let service_handle_query = service.clone();
+    let service_handle_count = service.clone();
+    let service_handle_address = service.clone();
+    let service_handle_chats = service.clone();
+    let service_message_query = service.clone();
+    let service_message_search = service.clone();
+    let service_attachment_download = service.clone();
+    let service_attachment_thumbnail = service.clone();
+    let service_chat_message = service.clone();
+    let service_ping = service.clone();
 
     // Register a handler for the default namespace
-    io.ns("/", socket_conn);
+    let service_socket = service.clone();
+    io.ns("/", move |socket: SocketRef| socket_conn(socket, service_socket));
 
-    let app = axum::Router::new()
-    .route("/api/v1/ping", get(|| async move {
-        return wrap_status("\"pong\"".into(), 200, "Ping received!".into());
-    }))
-    .route("/api/v1/server/statistics/totals", get(|Query(params): Query<HashMap<String, String>>| async move {
-        let password = params.get("guid"); 
-        if password.map(|password| password != state_statistics.password).unwrap_or(true) {
-            return UNAUTHORIZED.to_string().into_response();
-        } 
-        let db = state_statistics.database.lock().await;
-        return wrap_success(serde_json::to_string(&Statistics {
-            chats: db.get_count("chat"),
-            messages: db.get_count("message"),
-            attachments: db.get_count("attachment"),
-            handles: db.get_count("handle"),
-        }).unwrap());
+    let protected = axum::Router::new()
+    .route("/api/v1/server/statistics/totals", get(|| async move {
+        return service_statistics.statistics().await;
     }))
-    .route("/api/v1/server/update/check", get(|Query(params): Query<HashMap<String, String>>| async move {
-        let password = params.get("guid"); 
-        if password.map(|password| password != state_update.password).unwrap_or(true) {
-            return UNAUTHORIZED.to_string().into_response();
-        } 
-        return wrap_success(format!("{{\"available\": false,\"current\": \"{VERSION}\",\"metadata\": null}}"));
+    .route("/api/v1/server/update/check", get(|| async move {
+        return service_update.update_check();
     }))
     .route("/api/v1/chat/:guid", get(|Path(guid): Path<String>, Query(params): Query<HashMap<String, String>>| async move {
-        let password = params.get("guid"); 
-        if password.map(|password| password != state_chat_guid.password).unwrap_or(true) {
-            return UNAUTHORIZED.to_string().into_response();
-        }
-        let with = params.get("with");
-        let (last_message, participants) = with.map(|with| { (with.contains("lastmessage"), with.contains("participants")) }).unwrap_or((false, false));
-        let chats = state_chat_guid.database.lock().await.get_chat_by_guid(guid, last_message, participants);
-        return wrap_success(serde_json::to_string(&chats).unwrap());
+        return service_chat_guid.chat(ChatRequest::from_query(guid, &params)?).await;
     }))
-    .route("/api/v1/chat/query", post(|Query(params): Query<HashMap<String, String>>, Json(query): Json<ChatQuery>| async move {
-        let password = params.get("guid"); 
-        if password.map(|password| password != state_chat_query.password).unwrap_or(true) {
-            return UNAUTHORIZED.to_string().into_response();
-        }
-        let with = query.with.unwrap_or(vec![]);
-        let chats = state_chat_query.database.lock().await.query_chats(query.limit.unwrap_or(1000), query.offset.unwrap_or(0), query.sort, with.contains(&"lastmessage".to_string()), true /* clients expect participants even without specifying so */);
-        return wrap_success(serde_json::to_string(&chats).unwrap());
+    .route("/api/v1/chat/query", post(|query: Result<Json<ChatQuery>, JsonRejection>| async move {
+        let Json(query) = query?;
+        return service_chat_query.chat_query(query.try_into()?).await;
     }))
-    .route("/api/v1/chat/count", get(|Query(params): Query<HashMap<String, String>>| async move {
-        let password = params.get("guid"); 
-        if password.map(|password| password != state_chat_count.password).unwrap_or(true) {
-            return UNAUTHORIZED.to_string().into_response();
-        }
-        let chat_count = state_chat_count.database.lock().await.get_chat_service_count();
-        return wrap_success(serde_json::to_string(&chat_count).unwrap());
+    .route("/api/v1/chat/count", get(|| async move {
+        return service_chat_count.chat_count().await;
     }))
-    .route("/api/v1/server/info", get(|Query(params): Query<HashMap<String, String>>| async move {
-        let password = params.get("guid"); 
-        if password.map(|password| password == state_server_info.password).unwrap_or(false) {
-            let mut detected_icloud = String::from_utf8(Command::new("/usr/libexec/PlistBuddy").arg("-c").arg("print :Accounts:0:AccountID").arg(&format!("{}/Library/Preferences/MobileMeAccounts.plist", std::env::var("HOME").unwrap())).output().unwrap().stdout).unwrap();
-            detected_icloud.remove(detected_icloud.len()-1);
-            return wrap_success(serde_json::to_string(&ServerInfo {
-                os_version: String::from_utf8(Command::new("sw_vers").arg("productVersion").output().unwrap().stdout).unwrap(),
-                server_version: VERSION,
-                private_api: false,
-                proxy_service: "Dynamic DNS",
-                helper_connected: false,
-                detected_icloud,
-            }).unwrap());
-        }
-        return UNAUTHORIZED.to_string().into_response();
+    .route("/api/v1/server/info", get(|| async move {
+        return service_server_info.server_info();
     }))
-    .route("/api/v1/contact", get(|Query(params): Query<HashMap<String, String>>| async move {
-        let password = params.get("guid"); 
-        if password.map(|password| password == state_contacts.password).unwrap_or(false) {
-            return "{\"status\":200,\"message\":\"Success\",\"data\":[]}".into();
-        }
-        return UNAUTHORIZED.to_string();
+    .route("/api/v1/contact", get(|| async move {
+        return service_contacts.contacts();
+    }))
+    .route("/api/v1/message/query", post(|body: Result<Json<MessageQueryBody>, JsonRejection>| async move {
+        let Json(body) = body?;
+        return service_message_query.message_query(body.try_into()?).await;
+    }))
+    .route("/api/v1/message/search", post(|body: Result<Json<MessageSearchBody>, JsonRejection>| async move {
+        let Json(body) = body?;
+        return service_message_search.message_search(body.try_into()?).await;
     }))
     .route("/api/v1/message/:guid", get(|Path(guid): Path<String>, Query(params): Query<HashMap<String, String>>| async move {
-        let password = params.get("guid"); 
-        if password.map(|password| password != state_message_guid.password).unwrap_or(true) {
-            return UNAUTHORIZED.to_string().into_response();
-        }
-        let with = params.get("with");
-        let (chats, participants) = with.map(|with| { (with.contains("chats"), with.contains("participants")) }).unwrap_or((false, false));
-        let message = state_message_guid.database.lock().await.get_message_by_guid(guid, chats, participants);
-        return wrap_success(serde_json::to_string(&message).unwrap());
+        return service_message_guid.message(MessageRequest::from_query(guid, &params)?).await;
     }))
-    .route("/api/v1/attachment/:guid/download", get(|Path(guid): Path<String>, Query(params): Query<HashMap<String, String>>| async move {
-        let password = params.get("guid"); 
-        if password.map(|password| password != state_attachment_download.password).unwrap_or(true) {
-            return UNAUTHORIZED.to_string().into_response();
-        }
-        let file_name = state_attachment_download.database.lock().await.get_attachment_path(guid);
-        if let Some(file_name) = file_name {
-            let file_name = file_name.replace("~", &std::env::var("HOME").unwrap());
-            println!("{file_name:?}");
-            let mut file = File::open(file_name).await.unwrap();
-            // if let Ok(mut file) = File::open(file_name).await {
-                println!("{file:?}");
-                let mut bytes: Vec<u8> = vec![];
-                let _ = file.read_to_end(&mut bytes).await;
-                return bytes.into_response();
-            // }
-        }
-        return Response::builder().status(404).body(Body::empty()).unwrap();
+    .route("/api/v1/message/:guid/thread", get(|Path(guid): Path<String>, Query(params): Query<HashMap<String, String>>| async move {
+        return service_message_thread.message_thread(MessageThreadRequest::from_query(guid, &params)?).await;
+    }))
+    .route("/api/v1/handle/query", post(|query: Result<Json<HandleQuery>, JsonRejection>| async move {
+        let Json(query) = query?;
+        return service_handle_query.handle_query(query.into()).await;
+    }))
+    .route("/api/v1/handle/count", get(|| async move {
+        return service_handle_count.handle_count().await;
+    }))
+    .route("/api/v1/handle/:address", get(|Path(address): Path<String>, Query(params): Query<HashMap<String, String>>| async move {
+        return service_handle_address.handle(HandleRequest::from_query(address, &params)?).await;
+    }))
+    .route("/api/v1/handle/:address/chats", get(|Path(address): Path<String>, Query(params): Query<HashMap<String, String>>| async move {
+        return service_handle_chats.handle_chats(HandleChatsRequest::from_query(address, &params)?).await;
+    }))
+    .route("/api/v1/attachment/:guid/download", get(|Path(guid): Path<String>, Query(params): Query<HashMap<String, String>>, headers: HeaderMap| async move {
+        let range = headers.get(RANGE).and_then(|range| range.to_str().ok());
+        return service_attachment_download.attachment(AttachmentRequest::from_query(guid, &params)?, range).await;
+    }))
+    .route("/api/v1/attachment/:guid/thumbnail", get(|Path(guid): Path<String>, Query(params): Query<HashMap<String, String>>, headers: HeaderMap| async move {
+        let range = headers.get(RANGE).and_then(|range| range.to_str().ok());
+        return service_attachment_thumbnail.attachment(AttachmentRequest::thumbnail(guid, &params)?, range).await;
     }))
     .route("/api/v1/chat/:guid/message", get(|Path(guid): Path<String>, Query(params): Query<HashMap<String, String>>| async move {
-        println!("chat messages {} {:?}", guid, params);
-        let password = params.get("guid");
-        if password.map(|password| password != state_chat_message.password).unwrap_or(true) {
-            return UNAUTHORIZED.to_string().into_response();
-        }
-        let with = params.get("with");
-        let (attachments, handle) = with.map(|with| { (with.contains("attachments"), with.contains("participants")) }).unwrap_or((true, true));
-        let limit = params.get("limit").unwrap_or(&String::from("1000")).parse().unwrap();
-        let offset = params.get("offset").unwrap_or(&String::from("0")).parse().unwrap();
-        println!("{offset}");
-        let sort = params.get("sort").map(|string| string.as_str()).unwrap_or("ASC");
-        let after = if params.get("after") == Some(&"".to_string()) {
-            0
-        } else {
-            unix_to_apple(params.get("after").unwrap_or(&String::from("0")).parse::<u128>().unwrap()*1000000)
-        };
-        let before = if params.get("before") == Some(&"".to_string()) {
-            u128::MAX
-        } else {
-            unix_to_apple(params.get("before").map(|string| string.parse::<u128>().unwrap()*1000000).unwrap_or(u128::MAX))
-        };
-        let message = state_chat_message.database.lock().await.get_chat_messages(guid, attachments, handle, offset, limit, sort, after, before);
-        return wrap_success(serde_json::to_string(&message).unwrap());
+        debug!("chat messages {} {:?}", guid, params);
+        return service_chat_message.chat_messages(ChatMessagesRequest::from_query(guid, &params)?).await;
     }))
-    // .route("/api/v1/fcm/client", get(|Query(params): Query<HashMap<String, String>>| async move {
-    //     let password = params.get("guid"); 
-    //     if password.map(|password| password == state_fcm_client.password).unwrap_or(false) {
-    //         return "{\"data\":null, \"status\": 200, \"message\":\"Success\"}".into();
-    //     }
-    //     return UNAUTHORIZED.to_string();
-    // }))
+    .route_layer(middleware::from_fn_with_state(service.clone(), auth::require_password));
+
+    let app = axum::Router::new()
+    .route("/", get(|| async move {
+        return Html(include_str!("homepage.html"));
+    }))
+    .route("/api/v1/ping", get(|| async move {
+        return service_ping.ping();
+    }))
+    .merge(protected)
     .fallback(fallback)
     .layer(layer);
 
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:8000").await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+    let listener = tokio::net::TcpListener::bind(address).await?;
+    info!("listening on {address}");
+    axum::serve(listener, app).await?;
 
     Ok(())
-}
\ No newline at end of file
+}
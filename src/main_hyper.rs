@@ -0,0 +1,132 @@
+#![allow(clippy::needless_return)]
+use std::{collections::HashMap, convert::Infallible, sync::Arc};
+
+use bluebubbles_core::{database::Database, search::SearchIndex};
+use bluebubbles_server::{config::{Args, Config}, download::Download, error::ServerError, service::{ApiResponse, ApiService, AttachmentRequest, ChatMessagesRequest, ChatQuery, ChatRequest, HandleChatsRequest, HandleQuery, HandleRequest, MessageQueryBody, MessageRequest, MessageSearchBody, MessageThreadRequest}};
+use clap::Parser;
+use futures_util::TryStreamExt;
+use http_body_util::{combinators::BoxBody, BodyExt, Full, StreamBody};
+use hyper::{body::{Bytes, Frame, Incoming}, header::{AUTHORIZATION, CONTENT_TYPE, RANGE}, server::conn::http1, service::service_fn, Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use serde::Serialize;
+use tokio::net::TcpListener;
+use tracing_subscriber::EnvFilter;
+
+type Body = BoxBody<Bytes, std::io::Error>;
+
+fn respond(status: u16, content_type: &'static str, body: impl Into<Bytes>) -> Response<Body> {
+    let mut res = Response::new(Full::new(body.into()).map_err(|never| match never {}).boxed());
+    *res.status_mut() = StatusCode::from_u16(status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    res.headers_mut().insert(CONTENT_TYPE, content_type.parse().unwrap());
+    res
+}
+
+fn json<T: Serialize>(response: ApiResponse<T>) -> Response<Body> {
+    respond(response.status, "application/json", serde_json::to_string(&response).unwrap())
+}
+
+fn stream(download: Download) -> Response<Body> {
+    let mut builder = Response::builder().status(download.status);
+    for (name, value) in &download.headers {
+        builder = builder.header(*name, value);
+    }
+    builder.body(StreamBody::new(download.stream().map_ok(Frame::data)).boxed()).unwrap()
+}
+
+async fn router(service: Arc<ApiService>, req: Request<Incoming>) -> Result<Response<Body>, Infallible> {
+    return Ok(handle(&service, req).await.unwrap_or_else(|err| {
+        respond(err.status().as_u16(), "application/json", err.envelope().to_string())
+    }));
+}
+
+async fn handle(service: &ApiService, req: Request<Incoming>) -> Result<Response<Body>, ServerError> {
+    let path: Vec<String> = req.uri().path().trim_matches('/').split('/').map(|segment| percent_encoding::percent_decode_str(segment).decode_utf8_lossy().into_owned()).collect();
+    let path: Vec<&str> = path.iter().map(String::as_str).collect();
+    let params: HashMap<String, String> = url::form_urlencoded::parse(req.uri().query().unwrap_or_default().as_bytes()).into_owned().collect();
+    let method = req.method().clone();
+    let range = req.headers().get(RANGE).and_then(|range| range.to_str().ok()).map(str::to_string);
+
+    match (&method, path.as_slice()) {
+        (&Method::GET, [""]) => return Ok(respond(200, "text/html", include_str!("homepage.html"))),
+        (&Method::GET, ["api", "v1", "ping"]) => return Ok(json(service.ping())),
+        _ => {},
+    }
+    service.authorize(req.uri().query(), req.headers().get(AUTHORIZATION).and_then(|value| value.to_str().ok()))?;
+
+    return Ok(match (&method, path.as_slice()) {
+        (&Method::GET, ["api", "v1", "server", "statistics", "totals"]) => json(service.statistics().await?),
+        (&Method::GET, ["api", "v1", "server", "update", "check"]) => json(service.update_check()),
+        (&Method::GET, ["api", "v1", "server", "info"]) => json(service.server_info()),
+        (&Method::GET, ["api", "v1", "contact"]) => json(service.contacts()),
+        (&Method::GET, ["api", "v1", "chat", "count"]) => json(service.chat_count().await?),
+        (&Method::POST, ["api", "v1", "chat", "query"]) => {
+            let body = req.into_body().collect().await.map_err(|err| ServerError::BadRequest(err.to_string()))?.to_bytes();
+            let query = serde_json::from_slice::<ChatQuery>(&body).map_err(|err| ServerError::BadRequest(err.to_string()))?;
+            json(service.chat_query(query.try_into()?).await?)
+        },
+        (&Method::GET, ["api", "v1", "chat", guid]) => json(service.chat(ChatRequest::from_query(guid.to_string(), &params)?).await?),
+        (&Method::GET, ["api", "v1", "chat", guid, "message"]) => json(service.chat_messages(ChatMessagesRequest::from_query(guid.to_string(), &params)?).await?),
+        (&Method::POST, ["api", "v1", "message", "query"]) => {
+            let body = req.into_body().collect().await.map_err(|err| ServerError::BadRequest(err.to_string()))?.to_bytes();
+            let query = serde_json::from_slice::<MessageQueryBody>(&body).map_err(|err| ServerError::BadRequest(err.to_string()))?;
+            json(service.message_query(query.try_into()?).await?)
+        },
+        (&Method::POST, ["api", "v1", "message", "search"]) => {
+            let body = req.into_body().collect().await.map_err(|err| ServerError::BadRequest(err.to_string()))?.to_bytes();
+            let search = serde_json::from_slice::<MessageSearchBody>(&body).map_err(|err| ServerError::BadRequest(err.to_string()))?;
+            json(service.message_search(search.try_into()?).await?)
+        },
+        (&Method::GET, ["api", "v1", "message", guid]) => json(service.message(MessageRequest::from_query(guid.to_string(), &params)?).await?),
+        (&Method::GET, ["api", "v1", "message", guid, "thread"]) => json(service.message_thread(MessageThreadRequest::from_query(guid.to_string(), &params)?).await?),
+        (&Method::POST, ["api", "v1", "handle", "query"]) => {
+            let body = req.into_body().collect().await.map_err(|err| ServerError::BadRequest(err.to_string()))?.to_bytes();
+            let query = serde_json::from_slice::<HandleQuery>(&body).map_err(|err| ServerError::BadRequest(err.to_string()))?;
+            json(service.handle_query(query.into()).await?)
+        },
+        (&Method::GET, ["api", "v1", "handle", "count"]) => json(service.handle_count().await?),
+        (&Method::GET, ["api", "v1", "handle", address]) => json(service.handle(HandleRequest::from_query(address.to_string(), &params)?).await?),
+        (&Method::GET, ["api", "v1", "handle", address, "chats"]) => json(service.handle_chats(HandleChatsRequest::from_query(address.to_string(), &params)?).await?),
+        (&Method::GET, ["api", "v1", "attachment", guid, "download"]) => stream(service.attachment(AttachmentRequest::from_query(guid.to_string(), &params)?, range.as_deref()).await?),
+        (&Method::GET, ["api", "v1", "attachment", guid, "thumbnail"]) => stream(service.attachment(AttachmentRequest::thumbnail(guid.to_string(), &params)?, range.as_deref()).await?),
+        _ => respond(404, "text/plain", format!("No route for {}", req.uri())),
+    });
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let config = Config::load(Args::parse())?;
+    tracing_subscriber::fmt().with_env_filter(EnvFilter::try_new(&config.log_level)?).init();
+
+    // We create a TcpListener and bind it to the configured address
+    let listener = TcpListener::bind(config.bind_address()).await?;
+
+    let database = Database::open(&config.chat_db, config.database_options())?;
+    let search = SearchIndex::open(&config.search_index)?;
+    let service = Arc::new(ApiService::new(database, search, config));
+    let indexer = service.clone();
+    tokio::spawn(async move { indexer.index_search().await });
+
+    // We start a loop to continuously accept incoming connections
+    loop {
+        let (stream, _) = listener.accept().await?;
+
+        // Use an adapter to access something implementing `tokio::io` traits as if they implement
+        // `hyper::rt` IO traits.
+        let io = TokioIo::new(stream);
+        let service = service.clone();
+
+        // Spawn a tokio task to serve multiple connections concurrently
+        tokio::task::spawn(async move {
+            // Finally, we bind the incoming connection to our `hello` service
+            if let Err(err) = http1::Builder::new()
+                // `service_fn` converts our function in a `Service`
+                .serve_connection(io, service_fn(|req| {
+                    return router(service.clone(), req);
+                }))
+                .await
+            {
+                tracing::warn!("Error serving connection: {:?}", err);
+            }
+        });
+    }
+}
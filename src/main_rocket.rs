@@ -1,30 +1,72 @@
-use std::process::Command;
-use std::thread;
-use std::time::Duration;
-use std::time::SystemTime;
-use std::time::UNIX_EPOCH;
-
-use database::Database;
-use rocket::futures::SinkExt;
-use rocket::futures::StreamExt;
-use rocket::get;
-use rocket::http::ContentType;
-use rocket::response::stream::Event;
-use rocket::response::stream::EventStream;
-use rocket::routes;
-use rocket::tokio::sync::Mutex;
-use rocket::FromFormField;
+#![allow(clippy::needless_return)]
+use std::{collections::HashMap, convert::Infallible, sync::Arc};
+
+use bluebubbles_core::{database::Database, search::SearchIndex};
+use bluebubbles_server::{config::{Args, Config}, download::Download, error::ServerError, service::{ApiResponse, ApiService, AttachmentRequest, ChatMessagesRequest, ChatQuery, ChatRequest, HandleChatsRequest, HandleQuery, HandleRequest, MessageQueryBody, MessageRequest, MessageSearchBody, MessageThreadRequest}};
+use clap::Parser;
+use rocket::{catch, catchers, data::{Data, ToByteUnit}, futures::StreamExt, get, http::{uri::Origin, ContentType, Status}, post, request::{FromRequest, Outcome}, response::{self, Responder}, routes, Request, Response, State};
 use serde::Serialize;
-use util::unix_to_apple;
+use tracing_subscriber::EnvFilter;
+
+type JsonResponse = (Status, (ContentType, String));
+
+fn respond<T: Serialize>(result: Result<ApiResponse<T>, ServerError>) -> JsonResponse {
+    let (status, body) = match result {
+        Ok(response) => (response.status, serde_json::to_string(&response).unwrap()),
+        Err(err) => (err.status().as_u16(), err.envelope().to_string()),
+    };
+    return (Status::new(status), (ContentType::JSON, body));
+}
+
+fn query_params(origin: &Origin<'_>) -> HashMap<String, String> {
+    origin.query().map(|query| url::form_urlencoded::parse(query.as_str().as_bytes()).into_owned().collect()).unwrap_or_default()
+}
+
+/// Request guard for every route behind the server password.
+struct Authorized;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Authorized {
+    type Error = ServerError;
 
-mod database;
-mod structs;
-mod util;
-const UNAUTHORIZED: &str = "{\"status\":401,\"message\":\"You are not authorized to access this resource\",\"error\":{\"type\":\"Authentication Error\",\"message\":\"Unauthorized\"}}";
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let service = request.rocket().state::<Arc<ApiService>>().expect("ApiService is not managed");
+        match service.authorize(request.uri().query().map(|query| query.as_str()), request.headers().get_one("Authorization")) {
+            Ok(()) => Outcome::Success(Authorized),
+            Err(err) => Outcome::Error((Status::Unauthorized, err)),
+        }
+    }
+}
+
+/// The `Range` header, if the request has one.
+struct RangeHeader<'r>(Option<&'r str>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RangeHeader<'r> {
+    type Error = Infallible;
 
-struct State<'a> {
-    database: Mutex<Database>,
-    password: &'a str,
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(RangeHeader(request.headers().get_one("Range")))
+    }
+}
+
+/// A [`Download`] streamed as the response body.
+struct Streamed(Download);
+
+impl<'r> Responder<'r, 'static> for Streamed {
+    fn respond_to(self, _request: &'r Request<'_>) -> response::Result<'static> {
+        let mut response = Response::build();
+        response.status(Status::new(self.0.status));
+        for (name, value) in &self.0.headers {
+            response.raw_header(*name, value.clone());
+        }
+        return response.streamed_body(self.0.body).ok();
+    }
+}
+
+#[catch(401)]
+fn unauthorized() -> JsonResponse {
+    respond::<()>(Err(ServerError::Unauthorized))
 }
 
 #[get("/")]
@@ -32,110 +74,188 @@ async fn home_page() -> (ContentType, &'static str) {
     (ContentType::HTML, include_str!("homepage.html"))
 }
 
-#[derive(Debug, PartialEq, FromFormField)]
-enum ChatExtras {
-    Participants,
-    LastMessage,
+#[get("/ping")]
+async fn ping(service: &State<Arc<ApiService>>) -> JsonResponse {
+    respond(Ok(service.ping()))
 }
 
-#[get("/chat/<chat_guid>?<password>&<with>")]
-async fn get_chat_by_guid(state: &rocket::State<State<'_>>, chat_guid: &str, password: &str, with: Vec<ChatExtras>) -> (ContentType, String) {
-    if state.password == password {
-        let chat = state.database.lock().await.get_chat_by_guid(chat_guid.to_string(), with.contains(&ChatExtras::LastMessage), with.contains(&ChatExtras::Participants));
-        return (ContentType::JSON, wrap_success(serde_json::to_string(&chat).unwrap()));
+#[get("/server/statistics/totals")]
+async fn statistics(_auth: Authorized, service: &State<Arc<ApiService>>) -> JsonResponse {
+    respond(service.statistics().await)
+}
+
+#[get("/server/update/check")]
+async fn update_check(_auth: Authorized, service: &State<Arc<ApiService>>) -> JsonResponse {
+    respond(Ok(service.update_check()))
+}
+
+#[get("/server/info")]
+async fn server_info(_auth: Authorized, service: &State<Arc<ApiService>>) -> JsonResponse {
+    respond(Ok(service.server_info()))
+}
+
+#[get("/contact")]
+async fn contacts(_auth: Authorized, service: &State<Arc<ApiService>>) -> JsonResponse {
+    respond(Ok(service.contacts()))
+}
+
+#[get("/fcm/client")]
+async fn fcm_client(_auth: Authorized, service: &State<Arc<ApiService>>) -> JsonResponse {
+    respond(Ok(service.fcm_client()))
+}
+
+#[get("/chat/<guid>")]
+async fn chat(_auth: Authorized, service: &State<Arc<ApiService>>, guid: &str, origin: &Origin<'_>) -> JsonResponse {
+    match ChatRequest::from_query(guid.to_string(), &query_params(origin)) {
+        Ok(request) => respond(service.chat(request).await),
+        Err(err) => respond::<()>(Err(err)),
     }
-    return (ContentType::JSON, UNAUTHORIZED.to_owned())
 }
 
-#[derive(Debug, PartialEq, FromFormField)]
-enum ChatMessageExtras {
-    Attachment,
-    Handle,
-    //SMS is not supported rn cause I said so
+#[post("/chat/query", data = "<body>")]
+async fn chat_query(_auth: Authorized, service: &State<Arc<ApiService>>, body: Data<'_>) -> JsonResponse {
+    let body = match body.open(1.mebibytes()).into_string().await {
+        Ok(body) => body.into_inner(),
+        Err(err) => return respond::<()>(Err(err.into())),
+    };
+    let query = serde_json::from_str::<ChatQuery>(&body).map_err(|err| ServerError::BadRequest(err.to_string()));
+    match query.and_then(|query| query.try_into()) {
+        Ok(request) => respond(service.chat_query(request).await),
+        Err(err) => respond::<()>(Err(err)),
+    }
 }
 
-#[get("/chat/<chat_guid>/message?<password>&<with>&<after>&<before>&<offset>&<limit>&<sort>")]
-async fn get_chat_messages(state: &rocket::State<State<'_>>, chat_guid: &str, password: &str, with: Vec<ChatMessageExtras>, after: Option<u128>, before: Option<u128>, offset: Option<u32>, limit: Option<u32>, sort: &str) -> Option<(ContentType, String)> {
-    if state.password == password {
-        let after_apple = unix_to_apple(after.unwrap_or(0)*1000000000);
-        let before_apple = unix_to_apple(before.unwrap_or(0)*1000000000);
-        if sort != "ASC" && sort != "DESC" {
-            return None;
-        }
-        let messages = state.database.lock().await.get_chat_messages(chat_guid.to_string(), with.contains(&ChatMessageExtras::Attachment), with.contains(&ChatMessageExtras::Handle), offset.unwrap_or(0), limit.unwrap_or(1000), sort, after_apple, before_apple);
-        return Some((ContentType::JSON,wrap_success(serde_json::to_string(&messages).unwrap())));
+#[get("/chat/count")]
+async fn chat_count(_auth: Authorized, service: &State<Arc<ApiService>>) -> JsonResponse {
+    respond(service.chat_count().await)
+}
+
+#[get("/chat/<guid>/message")]
+async fn chat_messages(_auth: Authorized, service: &State<Arc<ApiService>>, guid: &str, origin: &Origin<'_>) -> JsonResponse {
+    match ChatMessagesRequest::from_query(guid.to_string(), &query_params(origin)) {
+        Ok(request) => respond(service.chat_messages(request).await),
+        Err(err) => respond::<()>(Err(err)),
     }
-    return Some((ContentType::JSON, UNAUTHORIZED.to_owned()))
-}
-
-#[derive(Serialize)]
-struct ServerInfo<'a> {
-    os_version: String,
-    server_version: &'a str,
-    private_api: bool,
-    proxy_service: &'a str,
-    helper_connected: bool,
-}
-
-#[get("/server/info?<guid>")]
-async fn server_info(guid: &str, state: &rocket::State<State<'_>>) -> (ContentType, String) {
-    if guid == state.password {
-        return (ContentType::JSON, serde_json::to_string(&ServerInfo {
-            os_version: String::from_utf8(Command::new("sw_vers").arg("productVersion").output().unwrap().stdout).unwrap(),
-            server_version: "0.0.1-rust",
-            private_api: false,
-            proxy_service: "Dynamic DNS",
-            helper_connected: false,
-        }).unwrap());
+}
+
+#[get("/message/<guid>")]
+async fn message(_auth: Authorized, service: &State<Arc<ApiService>>, guid: &str, origin: &Origin<'_>) -> JsonResponse {
+    match MessageRequest::from_query(guid.to_string(), &query_params(origin)) {
+        Ok(request) => respond(service.message(request).await),
+        Err(err) => respond::<()>(Err(err)),
     }
-    (ContentType::JSON, UNAUTHORIZED.to_string())
 }
 
-#[get("/fcm/client?<guid>")]
-async fn fcm_client_info(guid: &str, state: &rocket::State<State<'_>>) -> (ContentType, String) {
-    if guid == state.password {
-        return (ContentType::JSON, wrap_status("{}".to_string(), 200, "Successfully got FCM data".to_string()));
+#[get("/message/<guid>/thread")]
+async fn message_thread(_auth: Authorized, service: &State<Arc<ApiService>>, guid: &str, origin: &Origin<'_>) -> JsonResponse {
+    match MessageThreadRequest::from_query(guid.to_string(), &query_params(origin)) {
+        Ok(request) => respond(service.message_thread(request).await),
+        Err(err) => respond::<()>(Err(err)),
     }
-    (ContentType::JSON, UNAUTHORIZED.to_string())
 }
 
-#[get("/socket.io?<guid>&<EIO>&<transport>")]
-async fn socket_connection(ws: ws::WebSocket, state: &rocket::State<State<'_>>, guid: &str, EIO: u32, transport: &str) -> ws::Channel<'static> {
-    ws.channel(move |mut stream| Box::pin(async move {
-        while let Some(message) = stream.next().await {
-            println!("{message:?}");
-        }
-        Ok(())
-    }))
+#[post("/message/query", data = "<body>")]
+async fn message_query(_auth: Authorized, service: &State<Arc<ApiService>>, body: Data<'_>) -> JsonResponse {
+    let body = match body.open(1.mebibytes()).into_string().await {
+        Ok(body) => body.into_inner(),
+        Err(err) => return respond::<()>(Err(err.into())),
+    };
+    let query = serde_json::from_str::<MessageQueryBody>(&body).map_err(|err| ServerError::BadRequest(err.to_string()));
+    match query.and_then(|query| query.try_into()) {
+        Ok(request) => respond(service.message_query(request).await),
+        Err(err) => respond::<()>(Err(err)),
+    }
 }
 
-fn wrap_success(json: String) -> String {
-    return format!("{{\"status\": 200, \"message\": \"Success\", \"data\": {}}}", json);
+#[post("/message/search", data = "<body>")]
+async fn message_search(_auth: Authorized, service: &State<Arc<ApiService>>, body: Data<'_>) -> JsonResponse {
+    let body = match body.open(1.mebibytes()).into_string().await {
+        Ok(body) => body.into_inner(),
+        Err(err) => return respond::<()>(Err(err.into())),
+    };
+    let search = serde_json::from_str::<MessageSearchBody>(&body).map_err(|err| ServerError::BadRequest(err.to_string()));
+    match search.and_then(|search| search.try_into()) {
+        Ok(request) => respond(service.message_search(request).await),
+        Err(err) => respond::<()>(Err(err)),
+    }
 }
 
-fn wrap_status(json: String, code: u32, message: String) -> String {
-    return format!("{{\"status\": {}, \"message\": \"{}\", \"data\": {}}}", code, message, json);
+#[post("/handle/query", data = "<body>")]
+async fn handle_query(_auth: Authorized, service: &State<Arc<ApiService>>, body: Data<'_>) -> JsonResponse {
+    let body = match body.open(1.mebibytes()).into_string().await {
+        Ok(body) => body.into_inner(),
+        Err(err) => return respond::<()>(Err(err.into())),
+    };
+    match serde_json::from_str::<HandleQuery>(&body) {
+        Ok(query) => respond(service.handle_query(query.into()).await),
+        Err(err) => respond::<()>(Err(ServerError::BadRequest(err.to_string()))),
+    }
 }
 
+#[get("/handle/count")]
+async fn handle_count(_auth: Authorized, service: &State<Arc<ApiService>>) -> JsonResponse {
+    respond(service.handle_count().await)
+}
+
+#[get("/handle/<address>")]
+async fn handle(_auth: Authorized, service: &State<Arc<ApiService>>, address: &str, origin: &Origin<'_>) -> JsonResponse {
+    match HandleRequest::from_query(address.to_string(), &query_params(origin)) {
+        Ok(request) => respond(service.handle(request).await),
+        Err(err) => respond::<()>(Err(err)),
+    }
+}
+
+#[get("/handle/<address>/chats")]
+async fn handle_chats(_auth: Authorized, service: &State<Arc<ApiService>>, address: &str, origin: &Origin<'_>) -> JsonResponse {
+    match HandleChatsRequest::from_query(address.to_string(), &query_params(origin)) {
+        Ok(request) => respond(service.handle_chats(request).await),
+        Err(err) => respond::<()>(Err(err)),
+    }
+}
+
+#[get("/attachment/<guid>/download")]
+async fn attachment_download(_auth: Authorized, service: &State<Arc<ApiService>>, guid: &str, origin: &Origin<'_>, range: RangeHeader<'_>) -> Result<Streamed, JsonResponse> {
+    let request = AttachmentRequest::from_query(guid.to_string(), &query_params(origin)).map_err(|err| respond::<()>(Err(err)))?;
+    service.attachment(request, range.0).await.map(Streamed).map_err(|err| respond::<()>(Err(err)))
+}
+
+#[get("/attachment/<guid>/thumbnail")]
+async fn attachment_thumbnail(_auth: Authorized, service: &State<Arc<ApiService>>, guid: &str, origin: &Origin<'_>, range: RangeHeader<'_>) -> Result<Streamed, JsonResponse> {
+    let request = AttachmentRequest::thumbnail(guid.to_string(), &query_params(origin)).map_err(|err| respond::<()>(Err(err)))?;
+    service.attachment(request, range.0).await.map(Streamed).map_err(|err| respond::<()>(Err(err)))
+}
+
+#[get("/socket.io")]
+async fn socket_connection(_auth: Authorized, ws: rocket_ws::WebSocket) -> rocket_ws::Channel<'static> {
+    ws.channel(move |mut stream| Box::pin(async move {
+        while let Some(message) = stream.next().await {
+            tracing::debug!("{message:?}");
+        }
+        Ok(())
+    }))
+}
 
 #[rocket::main]
 async fn main() {
-    let database = Mutex::new(Database::new(SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos()));
-    let state = State {
-        database,
-        password: "balls",
-    };
-    let _ = rocket::build()
-    .manage(state)
+    let config = Config::load(Args::parse()).expect("invalid configuration");
+    tracing_subscriber::fmt().with_env_filter(EnvFilter::try_new(&config.log_level).expect("invalid log level")).init();
+    let database = Database::open(&config.chat_db, config.database_options()).expect("failed to open chat.db");
+    let figment = rocket::Config::figment().merge(("address", config.host)).merge(("port", config.port));
+    let search = SearchIndex::open(&config.search_index).expect("failed to open the search index");
+    let service = Arc::new(ApiService::new(database, search, config));
+    let indexer = service.clone();
+    tokio::spawn(async move { indexer.index_search().await });
+    let _ = rocket::custom(figment)
+    .manage(service)
+    .register("/", catchers![unauthorized])
     .mount(
         "/",
         routes![home_page, socket_connection],
     )
     .mount(
         "/api/v1/",
-        routes![get_chat_by_guid, get_chat_messages, server_info, fcm_client_info],
+        routes![ping, statistics, update_check, server_info, contacts, fcm_client, chat, chat_query, chat_count, chat_messages, message, message_thread, message_query, message_search, handle_query, handle_count, handle, handle_chats, attachment_download, attachment_thumbnail],
     )
     .launch()
     .await;
 }
-
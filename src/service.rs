@@ -0,0 +1,959 @@
+use std::{collections::HashMap, io::{SeekFrom, Write}, path::Path, process::Command, str::FromStr};
+
+use axum::{response::{IntoResponse, Response}, Json};
+use flate2::{write::ZlibEncoder, Compression};
+use hyper::StatusCode;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+use tokio::{fs::File, io::{AsyncReadExt, AsyncSeekExt}, sync::Mutex};
+
+use bluebubbles_core::{api::{HandleCount, PageMetadata, SearchResult, ServerInfo, Statistics, UpdateCheck}, database::{ChangeEvent, ChatCounts, ChatSort, Database, MessageCursor, MessageFilter, MessageQuery, SortOrder}, schema::Schema, search::{SearchIndex, SearchQuery}, structs::{Attachment, Chat, Message, Participant}, thumbnail::{ImageError, ThumbnailCache, ThumbnailFormat, ThumbnailOptions}, util::unix_to_apple};
+
+use crate::{auth, config::Config, download::Download, error::ServerError, storage::{AttachmentStorage, StorageError}};
+
+pub const VERSION: &str = "0.0.1";
+
+/// How many chat.db messages are added to the search index at a time, the database is locked meanwhile.
+const SEARCH_INDEX_BATCH: usize = 250;
+
+/// Batches per update. The locks are released between them so requests aren't held up while a large
+/// chat.db is indexed for the first time.
+const SEARCH_INDEX_BATCHES: usize = 20;
+
+/// How many chats, messages or handles a page has when the request doesn't say, over REST and Socket.IO alike.
+pub const DEFAULT_PAGE_SIZE: usize = 1000;
+
+/// Everything the API can do, independent of the HTTP stack in front of it. The axum, Rocket and
+/// hyper binaries only translate requests into these calls and the results back into responses.
+pub struct ApiService {
+    database: Mutex<Database>,
+    search: Mutex<SearchIndex>,
+    /// Probed when chat.db is opened, the columns don't change while it's open.
+    schema: Schema,
+    storage: AttachmentStorage,
+    thumbnails: ThumbnailCache,
+    /// Asked once at startup, running the commands per request would block the runtime.
+    host: HostInfo,
+    config: Config,
+}
+
+/// What `/server/info` reports about the Mac, both empty anywhere else.
+struct HostInfo {
+    os_version: String,
+    detected_icloud: String,
+}
+
+impl HostInfo {
+    fn detect() -> Self {
+        Self {
+            os_version: command_output(Command::new("sw_vers").arg("productVersion")),
+            detected_icloud: command_output(Command::new("/usr/libexec/PlistBuddy").arg("-c").arg("print :Accounts:0:AccountID").arg(format!("{}/Library/Preferences/MobileMeAccounts.plist", std::env::var("HOME").unwrap_or_default()))),
+        }
+    }
+}
+
+/// Envelope every successful response is wrapped in, errors use `ServerError::envelope`.
+#[derive(Debug, Serialize)]
+pub struct ApiResponse<T> {
+    pub status: u16,
+    pub message: &'static str,
+    pub data: T,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<PageMetadata>,
+}
+
+impl<T> ApiResponse<T> {
+    pub fn success(data: T) -> Self {
+        Self::with_message("Success", data)
+    }
+
+    pub fn with_message(message: &'static str, data: T) -> Self {
+        Self {
+            status: 200,
+            message,
+            data,
+            metadata: None,
+        }
+    }
+}
+
+impl<T: Serialize> IntoResponse for ApiResponse<T> {
+    fn into_response(self) -> Response {
+        (StatusCode::from_u16(self.status).unwrap_or(StatusCode::OK), Json(self)).into_response()
+    }
+}
+
+/// The `with` list clients use to ask for related rows, comma separated in query strings and an array in JSON bodies.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct With(Vec<String>);
+
+impl With {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Whether any of the (lowercase) `names` was asked for.
+    pub fn has(&self, names: &[&str]) -> bool {
+        self.0.iter().any(|with| names.contains(&with.as_str()))
+    }
+}
+
+impl FromStr for With {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(s.split(',').collect())
+    }
+}
+
+impl<'a> FromIterator<&'a str> for With {
+    fn from_iter<I: IntoIterator<Item = &'a str>>(iter: I) -> Self {
+        Self(iter.into_iter().map(|with| with.trim().to_ascii_lowercase()).filter(|with| !with.is_empty()).collect())
+    }
+}
+
+impl<'de> Deserialize<'de> for With {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            List(Vec<String>),
+            Joined(String),
+        }
+        return Ok(match Raw::deserialize(deserializer)? {
+            Raw::List(list) => list.iter().map(String::as_str).collect(),
+            Raw::Joined(joined) => joined.split(',').collect(),
+        });
+    }
+}
+
+/// Parses an optional query parameter, clients send empty values for parameters they don't use.
+fn query_param<T: FromStr>(params: &HashMap<String, String>, name: &str) -> Result<Option<T>, ServerError> {
+    params.get(name).filter(|value| !value.is_empty()).map(|value| {
+        value.parse::<T>().map_err(|_| ServerError::BadRequest(format!("Invalid value for {name}: {value:?}")))
+    }).transpose()
+}
+
+pub fn parse_optional<T: FromStr<Err = String>>(value: Option<&str>) -> Result<Option<T>, ServerError> {
+    value.filter(|value| !value.is_empty()).map(|value| value.parse::<T>()).transpose().map_err(ServerError::BadRequest)
+}
+
+/// Unix milliseconds, as clients send them, to the apple nanoseconds chat.db stores.
+pub fn millis_to_apple(millis: u128) -> u128 {
+    unix_to_apple(millis.saturating_mul(1000000))
+}
+
+pub fn chat_not_found() -> ServerError {
+    ServerError::NotFound("Chat does not exist".into())
+}
+
+fn attachment_not_found() -> ServerError {
+    ServerError::NotFound("Attachment does not exist".into())
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ChatRequest {
+    pub guid: String,
+    pub with_last_message: bool,
+    pub with_participants: bool,
+}
+
+impl ChatRequest {
+    pub fn from_query(guid: String, params: &HashMap<String, String>) -> Result<Self, ServerError> {
+        let with = query_param::<With>(params, "with")?.unwrap_or_default();
+        Ok(Self {
+            guid,
+            with_last_message: with.has(&["lastmessage"]),
+            with_participants: with.has(&["participants"]),
+        })
+    }
+}
+
+/// Body of `POST /chat/query`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct ChatQuery {
+    pub limit: Option<usize>,
+    pub offset: Option<usize>,
+    pub with: With,
+    pub sort: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ChatQueryRequest {
+    pub limit: usize,
+    pub offset: usize,
+    pub sort: ChatSort,
+    pub with_last_message: bool,
+    pub with_participants: bool,
+}
+
+impl TryFrom<ChatQuery> for ChatQueryRequest {
+    type Error = ServerError;
+
+    fn try_from(query: ChatQuery) -> Result<Self, Self::Error> {
+        Ok(Self {
+            limit: query.limit.unwrap_or(DEFAULT_PAGE_SIZE),
+            offset: query.offset.unwrap_or(0),
+            sort: parse_optional(query.sort.as_deref())?.unwrap_or_default(),
+            with_last_message: query.with.has(&["lastmessage"]),
+            // clients expect participants even without specifying so
+            with_participants: true,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ChatMessagesRequest {
+    pub chat_guid: String,
+    pub query: MessageQuery,
+}
+
+/// The query string of message listings. `after` and `before` are unix milliseconds, attachments and handles are
+/// included unless `with` says otherwise, reactions and reply counts only with `with=reactions,replyCount`.
+fn message_list_query(params: &HashMap<String, String>, default_sort: SortOrder) -> Result<MessageQuery, ServerError> {
+    let with = query_param::<With>(params, "with")?.unwrap_or_default();
+    Ok(MessageQuery {
+        attachments: with.is_empty() || with.has(&["attachment", "attachments"]),
+        handle: with.is_empty() || with.has(&["handle", "participants"]),
+        reactions: with.has(&["reaction", "reactions"]),
+        reply_counts: with.has(&["replycount"]),
+        offset: query_param(params, "offset")?.unwrap_or(0),
+        limit: query_param(params, "limit")?.unwrap_or(DEFAULT_PAGE_SIZE),
+        sort: parse_optional::<SortOrder>(params.get("sort").map(String::as_str))?.unwrap_or(default_sort),
+        after: query_param(params, "after")?.map(millis_to_apple).unwrap_or(0),
+        before: query_param(params, "before")?.map(millis_to_apple).unwrap_or(u128::MAX),
+        cursor: parse_optional::<MessageCursor>(params.get("cursor").map(String::as_str))?,
+        filters: vec![],
+    })
+}
+
+impl ChatMessagesRequest {
+    pub fn from_query(chat_guid: String, params: &HashMap<String, String>) -> Result<Self, ServerError> {
+        Ok(Self { chat_guid, query: message_list_query(params, SortOrder::Desc)? })
+    }
+}
+
+/// `/message/:guid/thread`, oldest first unless `sort` says otherwise.
+#[derive(Debug, Clone)]
+pub struct MessageThreadRequest {
+    pub guid: String,
+    pub query: MessageQuery,
+}
+
+impl MessageThreadRequest {
+    pub fn from_query(guid: String, params: &HashMap<String, String>) -> Result<Self, ServerError> {
+        Ok(Self { guid, query: message_list_query(params, SortOrder::Asc)? })
+    }
+}
+
+/// One entry of the `where` list of `POST /message/query`, e.g. `{"field": "text", "operator": "like", "value": "%lunch%"}`.
+/// Only the fields of [`MessageFilter`] are accepted, clients never get to send SQL.
+#[derive(Debug, Clone, Deserialize)]
+pub struct WhereClause {
+    pub field: String,
+    pub operator: Option<String>,
+    pub value: Value,
+}
+
+impl TryFrom<WhereClause> for MessageFilter {
+    type Error = ServerError;
+
+    fn try_from(clause: WhereClause) -> Result<Self, Self::Error> {
+        let invalid = || ServerError::BadRequest(format!("Invalid value for {}: {}", clause.field, clause.value));
+        let operator = clause.operator.as_deref().map(str::to_ascii_lowercase);
+        return match (clause.field.as_str(), operator.as_deref()) {
+            // chat.db stores booleans as 0 and 1, accept both spellings
+            ("isFromMe", None | Some("=")) => match &clause.value {
+                Value::Bool(is_from_me) => Ok(Self::IsFromMe(*is_from_me)),
+                Value::Number(number) if matches!(number.as_u64(), Some(0 | 1)) => Ok(Self::IsFromMe(number.as_u64() == Some(1))),
+                _ => Err(invalid()),
+            },
+            ("handleId", None | Some("=")) => clause.value.as_u64().and_then(|id| u32::try_from(id).ok()).map(Self::HandleId).ok_or_else(invalid),
+            ("text", None | Some("like")) => clause.value.as_str().map(|pattern| Self::TextLike(pattern.into())).ok_or_else(invalid),
+            ("isFromMe" | "handleId" | "text", Some(operator)) => Err(ServerError::BadRequest(format!("Unsupported operator for {}: {operator:?}", clause.field))),
+            (field, _) => Err(ServerError::BadRequest(format!("Unsupported where field {field:?}, expected isFromMe, handleId or text"))),
+        };
+    }
+}
+
+/// Body of `POST /message/query`, `after` and `before` are unix milliseconds.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct MessageQueryBody {
+    pub chat_guid: Option<String>,
+    pub with: With,
+    pub offset: Option<usize>,
+    pub limit: Option<usize>,
+    pub sort: Option<String>,
+    pub after: Option<u128>,
+    pub before: Option<u128>,
+    pub cursor: Option<String>,
+    #[serde(rename = "where")]
+    pub filters: Vec<WhereClause>,
+}
+
+/// Messages of one chat, or of all chats without a `chat_guid`.
+#[derive(Debug, Clone)]
+pub struct MessageQueryRequest {
+    pub chat_guid: Option<String>,
+    pub query: MessageQuery,
+}
+
+impl TryFrom<MessageQueryBody> for MessageQueryRequest {
+    type Error = ServerError;
+
+    fn try_from(body: MessageQueryBody) -> Result<Self, Self::Error> {
+        let query = MessageQuery {
+            attachments: body.with.is_empty() || body.with.has(&["attachment", "attachments"]),
+            handle: body.with.is_empty() || body.with.has(&["handle", "participants"]),
+            reactions: body.with.has(&["reaction", "reactions"]),
+            reply_counts: body.with.has(&["replycount"]),
+            offset: body.offset.unwrap_or(0),
+            limit: body.limit.unwrap_or(DEFAULT_PAGE_SIZE),
+            sort: parse_optional(body.sort.as_deref())?.unwrap_or_default(),
+            after: body.after.map(millis_to_apple).unwrap_or(0),
+            before: body.before.map(millis_to_apple).unwrap_or(u128::MAX),
+            cursor: parse_optional(body.cursor.as_deref())?,
+            filters: body.filters.into_iter().map(MessageFilter::try_from).collect::<Result<_, _>>()?,
+        };
+        Ok(Self { chat_guid: body.chat_guid.filter(|guid| !guid.is_empty()), query })
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MessageRequest {
+    pub guid: String,
+    pub with_handle: bool,
+    pub with_attachments: bool,
+    pub with_reactions: bool,
+    pub with_reply_count: bool,
+}
+
+impl MessageRequest {
+    pub fn from_query(guid: String, params: &HashMap<String, String>) -> Result<Self, ServerError> {
+        let with = query_param::<With>(params, "with")?.unwrap_or_default();
+        Ok(Self {
+            guid,
+            with_handle: with.has(&["handle", "participants"]),
+            with_attachments: with.has(&["attachment", "attachments"]),
+            with_reactions: with.has(&["reaction", "reactions"]),
+            with_reply_count: with.has(&["replycount"]),
+        })
+    }
+}
+
+/// Body of `POST /message/search`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct MessageSearchBody {
+    pub query: String,
+    pub chat_guids: Vec<String>,
+    pub with: With,
+    pub limit: Option<usize>,
+    pub offset: Option<usize>,
+}
+
+#[derive(Debug, Clone)]
+pub struct MessageSearchRequest {
+    pub query: SearchQuery,
+    pub with_handle: bool,
+    pub with_attachments: bool,
+}
+
+impl TryFrom<MessageSearchBody> for MessageSearchRequest {
+    type Error = ServerError;
+
+    fn try_from(body: MessageSearchBody) -> Result<Self, Self::Error> {
+        if body.query.trim().is_empty() {
+            return Err(ServerError::BadRequest("No search query provided".into()));
+        }
+        Ok(Self {
+            query: SearchQuery {
+                text: body.query,
+                chat_guids: body.chat_guids,
+                limit: body.limit.unwrap_or(100),
+                offset: body.offset.unwrap_or(0),
+            },
+            with_handle: body.with.has(&["handle", "participants"]),
+            with_attachments: body.with.has(&["attachment", "attachments"]),
+        })
+    }
+}
+
+/// Body of `POST /handle/query`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct HandleQuery {
+    /// Only handles whose address contains this.
+    pub address: Option<String>,
+    pub with: With,
+    pub limit: Option<usize>,
+    pub offset: Option<usize>,
+}
+
+#[derive(Debug, Clone)]
+pub struct HandleQueryRequest {
+    pub address: String,
+    pub with_chats: bool,
+    pub limit: usize,
+    pub offset: usize,
+}
+
+impl From<HandleQuery> for HandleQueryRequest {
+    fn from(query: HandleQuery) -> Self {
+        Self {
+            address: query.address.unwrap_or_default(),
+            with_chats: query.with.has(&["chat", "chats"]),
+            limit: query.limit.unwrap_or(DEFAULT_PAGE_SIZE),
+            offset: query.offset.unwrap_or(0),
+        }
+    }
+}
+
+/// `/handle/:address`, with the chats it's in unless `with` says otherwise.
+#[derive(Debug, Clone, Default)]
+pub struct HandleRequest {
+    pub address: String,
+    pub with_chats: bool,
+}
+
+impl HandleRequest {
+    pub fn from_query(address: String, params: &HashMap<String, String>) -> Result<Self, ServerError> {
+        let with = query_param::<With>(params, "with")?.unwrap_or_default();
+        Ok(Self { address, with_chats: with.is_empty() || with.has(&["chat", "chats"]) })
+    }
+}
+
+/// `/handle/:address/chats`, `with` works like for `/chat/:guid`.
+#[derive(Debug, Clone, Default)]
+pub struct HandleChatsRequest {
+    pub address: String,
+    pub with_last_message: bool,
+    pub with_participants: bool,
+}
+
+impl HandleChatsRequest {
+    pub fn from_query(address: String, params: &HashMap<String, String>) -> Result<Self, ServerError> {
+        let with = query_param::<With>(params, "with")?.unwrap_or_default();
+        Ok(Self {
+            address,
+            with_last_message: with.has(&["lastmessage"]),
+            with_participants: with.has(&["participants"]),
+        })
+    }
+}
+
+/// Width and height thumbnails are fitted into when the request doesn't give a size.
+pub const THUMBNAIL_SIZE: u32 = 300;
+
+/// Query of `/attachment/:guid/download` and `/attachment/:guid/thumbnail`.
+#[derive(Debug, Clone, Default)]
+pub struct AttachmentRequest {
+    pub guid: String,
+    /// `None` for the original file.
+    pub resize: Option<ThumbnailOptions>,
+}
+
+impl AttachmentRequest {
+    /// Downloads are only resized when `width`, `height`, `quality` or `format` is given.
+    pub fn from_query(guid: String, params: &HashMap<String, String>) -> Result<Self, ServerError> {
+        let positive = |name: &str| -> Result<Option<u32>, ServerError> {
+            match query_param::<u32>(params, name)? {
+                Some(0) => Err(ServerError::BadRequest(format!("Invalid value for {name}: \"0\""))),
+                size => Ok(size),
+            }
+        };
+        let width = positive("width")?;
+        let height = positive("height")?;
+        let quality = match query_param::<u8>(params, "quality")? {
+            Some(quality) if !(1..=100).contains(&quality) => return Err(ServerError::BadRequest(format!("Invalid value for quality: \"{quality}\""))),
+            quality => quality,
+        };
+        let format = parse_optional::<ThumbnailFormat>(params.get("format").map(String::as_str))?;
+        if width.is_none() && height.is_none() && quality.is_none() && format.is_none() {
+            return Ok(Self { guid, resize: None });
+        }
+        let defaults = ThumbnailOptions::default();
+        let resize = ThumbnailOptions { width, height, quality: quality.unwrap_or(defaults.quality), format: format.unwrap_or(defaults.format) };
+        return Ok(Self { guid, resize: Some(resize) });
+    }
+
+    /// Like [`AttachmentRequest::from_query`] but always resized, to fit [`THUMBNAIL_SIZE`] unless a size is given.
+    pub fn thumbnail(guid: String, params: &HashMap<String, String>) -> Result<Self, ServerError> {
+        let mut request = Self::from_query(guid, params)?;
+        let resize = request.resize.get_or_insert_with(ThumbnailOptions::default);
+        if resize.width.is_none() && resize.height.is_none() {
+            resize.width = Some(THUMBNAIL_SIZE);
+            resize.height = Some(THUMBNAIL_SIZE);
+        }
+        return Ok(request);
+    }
+}
+
+/// Chunk size of `get-attachment-chunk` when the client doesn't send one.
+pub const DEFAULT_CHUNK_SIZE: usize = 1024 * 1024;
+/// Larger chunks are cut down to this, the whole chunk is held in memory and sent in one Socket.IO packet.
+pub const MAX_CHUNK_SIZE: usize = 8 * 1024 * 1024;
+
+#[derive(Debug, Clone)]
+pub struct AttachmentChunkRequest {
+    pub guid: String,
+    /// Offset into the file.
+    pub start: u64,
+    pub chunk_size: usize,
+    /// zlib compress the chunk.
+    pub compress: bool,
+}
+
+fn handle_not_found() -> ServerError {
+    ServerError::NotFound("Handle does not exist".into())
+}
+
+/// Trimmed stdout of a command, empty if it couldn't be run.
+fn command_output(command: &mut Command) -> String {
+    command.output().ok().and_then(|output| String::from_utf8(output.stdout).ok()).map(|output| output.trim_end().to_string()).unwrap_or_default()
+}
+
+impl ApiService {
+    pub fn new(database: Database, search: SearchIndex, config: Config) -> Self {
+        Self {
+            schema: database.schema().clone(),
+            database: Mutex::new(database),
+            search: Mutex::new(search),
+            storage: AttachmentStorage::new(&config.attachment_roots),
+            thumbnails: ThumbnailCache::new(&config.thumbnail_cache),
+            host: HostInfo::detect(),
+            config,
+        }
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Checks the password sent in the query string or `Authorization` header.
+    pub fn authorize(&self, query: Option<&str>, authorization: Option<&str>) -> Result<(), ServerError> {
+        if !auth::is_authorized(query, authorization, &self.config.password) {
+            return Err(ServerError::Unauthorized);
+        }
+        return Ok(());
+    }
+
+    pub async fn poll(&self) -> rusqlite::Result<Vec<ChangeEvent>> {
+        self.database.lock().await.poll()
+    }
+
+    /// Adds the next few batches of chat.db messages to the search index and re-indexes the messages in `changes`
+    /// that were updated. Returns how many new messages were read, 0 once the index has caught up.
+    pub async fn update_search_index(&self, changes: &[ChangeEvent]) -> rusqlite::Result<usize> {
+        let mut synced = 0;
+        for _ in 0..SEARCH_INDEX_BATCHES {
+            // both mutexes are fair, requests waiting for them go before the next batch
+            let read = self.search.lock().await.sync(&*self.database.lock().await, SEARCH_INDEX_BATCH)?;
+            synced += read;
+            if read < SEARCH_INDEX_BATCH {
+                break;
+            }
+            tokio::task::yield_now().await;
+        }
+        self.search.lock().await.update(changes.iter().filter_map(|change| match change {
+            ChangeEvent::UpdatedMessage(message) => Some(message),
+            _ => None,
+        }))?;
+        return Ok(synced);
+    }
+
+    /// Keeps the search index up to date for binaries that don't run the chat.db poller.
+    pub async fn index_search(&self) {
+        let mut interval = tokio::time::interval(self.config.poll_interval());
+        loop {
+            interval.tick().await;
+            if let Err(err) = self.update_search_index(&[]).await {
+                tracing::error!("failed to update the search index: {err}");
+            }
+        }
+    }
+
+    pub fn ping(&self) -> ApiResponse<&'static str> {
+        ApiResponse::with_message("Ping received!", "pong")
+    }
+
+    pub async fn statistics(&self) -> Result<ApiResponse<Statistics>, ServerError> {
+        let db = self.database.lock().await;
+        return Ok(ApiResponse::success(Statistics {
+            chats: db.get_count("chat")?,
+            messages: db.get_count("message")?,
+            attachments: db.get_count("attachment")?,
+            handles: db.get_count("handle")?,
+        }));
+    }
+
+    pub fn update_check(&self) -> ApiResponse<UpdateCheck> {
+        ApiResponse::success(UpdateCheck {
+            available: false,
+            current: VERSION.into(),
+            metadata: None,
+        })
+    }
+
+    pub fn server_info(&self) -> ApiResponse<ServerInfo> {
+        ApiResponse::success(ServerInfo {
+            os_version: self.host.os_version.clone(),
+            server_version: VERSION.into(),
+            private_api: false,
+            proxy_service: "Dynamic DNS".into(),
+            helper_connected: false,
+            detected_icloud: self.host.detected_icloud.clone(),
+            chat_db_schema: self.schema.version().to_string(),
+            chat_db_capabilities: self.schema.capabilities(),
+        })
+    }
+
+    pub fn contacts(&self) -> ApiResponse<Vec<Value>> {
+        ApiResponse::success(vec![])
+    }
+
+    /// Kept for the Rocket binary, which served `/fcm/client` before the service existed.
+    pub fn fcm_client(&self) -> ApiResponse<Option<Value>> {
+        ApiResponse::with_message("Successfully got FCM data", None)
+    }
+
+    pub async fn chat(&self, request: ChatRequest) -> Result<ApiResponse<Chat>, ServerError> {
+        let chat = self.database.lock().await.get_chat_by_guid(request.guid, request.with_last_message, request.with_participants)?;
+        return Ok(ApiResponse::success(chat.ok_or_else(chat_not_found)?));
+    }
+
+    pub async fn chat_query(&self, request: ChatQueryRequest) -> Result<ApiResponse<Vec<Chat>>, ServerError> {
+        let chats = self.database.lock().await.query_chats(request.limit, request.offset, request.sort, request.with_last_message, request.with_participants)?;
+        return Ok(ApiResponse::success(chats));
+    }
+
+    pub async fn chat_count(&self) -> Result<ApiResponse<ChatCounts>, ServerError> {
+        return Ok(ApiResponse::success(self.database.lock().await.get_chat_service_count()?));
+    }
+
+    pub async fn handle_query(&self, request: HandleQueryRequest) -> Result<ApiResponse<Vec<Participant>>, ServerError> {
+        let db = self.database.lock().await;
+        let mut handles = db.query_handles(&request.address, request.limit, request.offset)?;
+        if request.with_chats {
+            for handle in &mut handles {
+                handle.chats = Some(db.get_handle_chats(&handle.address, false, false)?);
+            }
+        }
+        let mut response = ApiResponse::success(handles);
+        response.metadata = Some(PageMetadata {
+            offset: request.offset,
+            limit: request.limit,
+            count: response.data.len(),
+            next_cursor: None,
+        });
+        return Ok(response);
+    }
+
+    pub async fn handle(&self, request: HandleRequest) -> Result<ApiResponse<Participant>, ServerError> {
+        let db = self.database.lock().await;
+        let mut handle = db.get_handle(&request.address)?.ok_or_else(handle_not_found)?;
+        if request.with_chats {
+            handle.chats = Some(db.get_handle_chats(&handle.address, false, false)?);
+        }
+        return Ok(ApiResponse::success(handle));
+    }
+
+    pub async fn handle_count(&self) -> Result<ApiResponse<HandleCount>, ServerError> {
+        return Ok(ApiResponse::success(HandleCount { total: self.database.lock().await.get_count("handle")? }));
+    }
+
+    /// Chats of every handle with this address.
+    pub async fn handle_chats(&self, request: HandleChatsRequest) -> Result<ApiResponse<Vec<Chat>>, ServerError> {
+        let db = self.database.lock().await;
+        db.get_handle(&request.address)?.ok_or_else(handle_not_found)?;
+        return Ok(ApiResponse::success(db.get_handle_chats(&request.address, request.with_last_message, request.with_participants)?));
+    }
+
+    pub async fn chat_messages(&self, request: ChatMessagesRequest) -> Result<ApiResponse<Vec<Message>>, ServerError> {
+        let page = self.database.lock().await.get_chat_messages(request.chat_guid, &request.query)?.ok_or_else(chat_not_found)?;
+        let metadata = PageMetadata::new(&request.query, &page);
+        let mut response = ApiResponse::success(page.messages);
+        response.metadata = Some(metadata);
+        return Ok(response);
+    }
+
+    pub async fn message_query(&self, request: MessageQueryRequest) -> Result<ApiResponse<Vec<Message>>, ServerError> {
+        let page = {
+            let db = self.database.lock().await;
+            match request.chat_guid {
+                Some(chat_guid) => db.get_chat_messages(chat_guid, &request.query)?.ok_or_else(chat_not_found)?,
+                None => db.query_messages(&request.query)?,
+            }
+        };
+        let metadata = PageMetadata::new(&request.query, &page);
+        let mut response = ApiResponse::success(page.messages);
+        response.metadata = Some(metadata);
+        return Ok(response);
+    }
+
+    pub async fn message_search(&self, request: MessageSearchRequest) -> Result<ApiResponse<Vec<SearchResult>>, ServerError> {
+        let hits = self.search.lock().await.search(&request.query)?;
+        let db = self.database.lock().await;
+        let mut results = Vec::with_capacity(hits.len());
+        for hit in hits {
+            // the index can briefly outlive a deleted message
+            if let Some(message) = db.get_message_by_guid(hit.guid, request.with_handle, request.with_attachments)? {
+                results.push(SearchResult { message, snippet: hit.snippet });
+            }
+        }
+        let mut response = ApiResponse::success(results);
+        response.metadata = Some(PageMetadata {
+            offset: request.query.offset,
+            limit: request.query.limit,
+            count: response.data.len(),
+            next_cursor: None,
+        });
+        return Ok(response);
+    }
+
+    pub async fn last_chat_message(&self, chat_guid: String) -> Result<ApiResponse<Option<Message>>, ServerError> {
+        let chat = self.database.lock().await.get_chat_by_guid(chat_guid, true, false)?.ok_or_else(chat_not_found)?;
+        return Ok(ApiResponse::success(chat.last_message));
+    }
+
+    pub async fn participants(&self, chat_guid: String) -> Result<ApiResponse<Vec<Participant>>, ServerError> {
+        let chat = self.database.lock().await.get_chat_by_guid(chat_guid, false, true)?.ok_or_else(chat_not_found)?;
+        return Ok(ApiResponse::success(chat.participants));
+    }
+
+    pub async fn message(&self, request: MessageRequest) -> Result<ApiResponse<Message>, ServerError> {
+        let db = self.database.lock().await;
+        let mut message = db.get_message_by_guid(request.guid, request.with_handle, request.with_attachments)?.ok_or_else(|| ServerError::NotFound("Message does not exist".into()))?;
+        if request.with_reactions {
+            message.reactions = db.get_reactions(&message.guid)?;
+        }
+        if request.with_reply_count {
+            message.reply_count = Some(db.get_reply_count(&message.guid)?);
+        }
+        return Ok(ApiResponse::success(message));
+    }
+
+    pub async fn message_thread(&self, request: MessageThreadRequest) -> Result<ApiResponse<Vec<Message>>, ServerError> {
+        let page = self.database.lock().await.get_thread(&request.guid, &request.query)?;
+        let page = page.ok_or_else(|| ServerError::NotFound("Message does not exist".into()))?;
+        let metadata = PageMetadata::new(&request.query, &page);
+        let mut response = ApiResponse::success(page.messages);
+        response.metadata = Some(metadata);
+        return Ok(response);
+    }
+
+    pub async fn attachment_info(&self, guid: String) -> Result<ApiResponse<Attachment>, ServerError> {
+        let attachment = self.database.lock().await.get_attachment_by_guid(guid)?;
+        return Ok(ApiResponse::success(attachment.ok_or_else(attachment_not_found)?));
+    }
+
+    /// Up to `chunk_size` bytes of an attachment's file from `start` on, `None` once `start` is past the end.
+    pub async fn attachment_chunk(&self, request: AttachmentChunkRequest) -> Result<Option<Vec<u8>>, ServerError> {
+        let attachment = self.database.lock().await.get_attachment_file(request.guid)?;
+        let path = self.storage.resolve(&attachment.ok_or_else(attachment_not_found)?)?;
+        let mut file = File::open(&path).await?;
+        file.seek(SeekFrom::Start(request.start)).await?;
+        let chunk_size = request.chunk_size.clamp(1, MAX_CHUNK_SIZE);
+        let mut bytes = Vec::with_capacity(chunk_size);
+        file.take(chunk_size as u64).read_to_end(&mut bytes).await?;
+        if bytes.is_empty() {
+            return Ok(None);
+        }
+        if request.compress {
+            let mut encoder = ZlibEncoder::new(Vec::with_capacity(bytes.len() / 2), Compression::default());
+            encoder.write_all(&bytes)?;
+            bytes = encoder.finish()?;
+        }
+        return Ok(Some(bytes));
+    }
+
+    /// An attachment's file, resized if the request asks for it, or the part of it `range` (a `Range` header) asks for.
+    pub async fn attachment(&self, request: AttachmentRequest, range: Option<&str>) -> Result<Download, ServerError> {
+        let attachment = self.database.lock().await.get_attachment_file(request.guid.clone())?;
+        let attachment = attachment.ok_or_else(attachment_not_found)?;
+        let file_not_found = || ServerError::from(StorageError::Missing);
+        let file_name = self.storage.resolve(&attachment)?;
+        tracing::debug!("{file_name:?}");
+        let transfer_name = attachment.transfer_name.filter(|name| !name.is_empty())
+            .or_else(|| file_name.file_name().map(|name| name.to_string_lossy().into_owned()))
+            .unwrap_or_else(|| "attachment".into());
+        let (file_name, mime_type, transfer_name) = match request.resize {
+            None => (file_name, attachment.mime_type.unwrap_or_else(|| "application/octet-stream".into()), transfer_name),
+            Some(options) => {
+                let thumbnails = self.thumbnails.clone();
+                let thumbnail = tokio::task::spawn_blocking(move || thumbnails.get_or_render(&request.guid, &file_name, &options)).await
+                    .map_err(|err| ServerError::Io(std::io::Error::other(err)))?;
+                let thumbnail = thumbnail.map_err(|err| match err {
+                    ImageError::IoError(err) if err.kind() == std::io::ErrorKind::NotFound => file_not_found(),
+                    ImageError::IoError(err) => ServerError::Io(err),
+                    err => ServerError::BadRequest(format!("Attachment can't be resized: {err}")),
+                })?;
+                let transfer_name = Path::new(&transfer_name).with_extension(options.format.extension()).to_string_lossy().into_owned();
+                (thumbnail, options.format.mime_type().to_string(), transfer_name)
+            },
+        };
+        let file = match File::open(&file_name).await {
+            Ok(file) => file,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Err(file_not_found()),
+            Err(err) => return Err(err.into()),
+        };
+        return Ok(Download::open(file, &mime_type, &transfer_name, range).await?);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::HashMap, io::Read};
+
+    use bluebubbles_core::{database::{Database, DatabaseOptions, MessageFilter, SortOrder, FIXTURE_SCHEMA}, search::SearchIndex, thumbnail::{ThumbnailFormat, ThumbnailOptions}};
+    use serde_json::json;
+
+    use crate::config::Config;
+
+    use super::{ApiService, AttachmentChunkRequest, AttachmentRequest, ChatMessagesRequest, ChatRequest, HandleQuery, HandleQueryRequest, HandleRequest, MessageQueryBody, MessageQueryRequest, MessageSearchBody, MessageSearchRequest, MessageThreadRequest, With, THUMBNAIL_SIZE};
+
+    fn params(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn test_attachment_request() {
+        assert!(AttachmentRequest::from_query("a".into(), &params(&[("width", "")])).unwrap().resize.is_none());
+        let request = AttachmentRequest::from_query("a".into(), &params(&[("width", "200"), ("format", "webp")])).unwrap();
+        assert_eq!(request.resize, Some(ThumbnailOptions { width: Some(200), height: None, quality: 80, format: ThumbnailFormat::Webp }));
+        let request = AttachmentRequest::thumbnail("a".into(), &params(&[("quality", "50")])).unwrap();
+        assert_eq!(request.resize, Some(ThumbnailOptions { width: Some(THUMBNAIL_SIZE), height: Some(THUMBNAIL_SIZE), quality: 50, format: ThumbnailFormat::Jpeg }));
+        for invalid in [("width", "0"), ("height", "-1"), ("quality", "101"), ("format", "gif")] {
+            assert_eq!(AttachmentRequest::from_query("a".into(), &params(&[invalid])).unwrap_err().status().as_u16(), 400);
+        }
+    }
+
+    #[test]
+    fn test_with() {
+        let with: With = "lastMessage, participants,".parse().unwrap();
+        assert!(with.has(&["lastmessage"]) && with.has(&["participants"]));
+        let with: With = serde_json::from_str("[\"Participants\"]").unwrap();
+        assert!(with.has(&["participants"]) && !with.has(&["lastmessage"]));
+        let with: With = serde_json::from_str("\"attachment,handle\"").unwrap();
+        assert!(with.has(&["attachment", "attachments"]));
+
+        let request = ChatMessagesRequest::from_query("chat".into(), &params(&[("with", "handle"), ("after", "1000")])).unwrap();
+        assert!(request.query.handle && !request.query.attachments && !request.query.reactions);
+        let request = ChatMessagesRequest::from_query("chat".into(), &params(&[("with", "reactions,replyCount")])).unwrap();
+        assert!(request.query.reactions && request.query.reply_counts && !request.query.handle);
+        let request = MessageThreadRequest::from_query("message".into(), &params(&[])).unwrap();
+        assert_eq!((request.query.sort, request.query.limit), (SortOrder::Asc, super::DEFAULT_PAGE_SIZE));
+        assert_eq!(request.query.after, super::millis_to_apple(1000));
+        assert!(ChatMessagesRequest::from_query("chat".into(), &params(&[("limit", "ten")])).is_err());
+    }
+
+    fn message_query(body: serde_json::Value) -> Result<MessageQueryRequest, u16> {
+        serde_json::from_value::<MessageQueryBody>(body).unwrap().try_into().map_err(|err: crate::error::ServerError| err.status().as_u16())
+    }
+
+    #[test]
+    fn test_message_query_body() {
+        let request = message_query(json!({
+            "chatGuid": "iMessage;-;+15555550100",
+            "with": ["handle"],
+            "after": 1000,
+            "where": [{"field": "isFromMe", "value": 1}, {"field": "handleId", "operator": "=", "value": 2}, {"field": "text", "operator": "LIKE", "value": "%lunch%"}],
+        })).unwrap();
+        assert_eq!(request.chat_guid.as_deref(), Some("iMessage;-;+15555550100"));
+        assert!(request.query.handle && !request.query.attachments);
+        assert_eq!(request.query.filters, [MessageFilter::IsFromMe(true), MessageFilter::HandleId(2), MessageFilter::TextLike("%lunch%".into())]);
+        assert!(message_query(json!({"chatGuid": ""})).unwrap().chat_guid.is_none());
+
+        assert_eq!(message_query(json!({"where": [{"field": "m.ROWID; DROP TABLE message", "value": 1}]})).unwrap_err(), 400);
+        assert_eq!(message_query(json!({"where": [{"field": "text", "operator": ">", "value": "a"}]})).unwrap_err(), 400);
+        assert_eq!(message_query(json!({"where": [{"field": "handleId", "value": "2"}]})).unwrap_err(), 400);
+        assert_eq!(message_query(json!({"where": [{"field": "isFromMe", "value": 2}]})).unwrap_err(), 400);
+    }
+
+    #[tokio::test]
+    async fn test_service() {
+        let config = Config { password: "hunter2".into(), ..Default::default() };
+        let service = ApiService::new(Database::open_fixture(DatabaseOptions::default()).unwrap(), SearchIndex::open_in_memory().unwrap(), config);
+        assert!(service.authorize(Some("guid=hunter2"), None).is_ok());
+        assert_eq!(service.authorize(None, Some("wrong")).unwrap_err().status().as_u16(), 401);
+        let err = service.chat(ChatRequest { guid: "missing".into(), ..Default::default() }).await.unwrap_err();
+        assert_eq!(err.status().as_u16(), 404);
+        assert_eq!(serde_json::to_value(service.ping()).unwrap(), serde_json::json!({"status": 200, "message": "Ping received!", "data": "pong"}));
+
+        let info = service.server_info().data;
+        assert_eq!(info.chat_db_schema, "macOS 15");
+        assert!(info.chat_db_capabilities.edits);
+
+        let err = service.attachment(AttachmentRequest { guid: "missing".into(), resize: None }, None).await.unwrap_err();
+        assert_eq!(err.to_string(), "Attachment does not exist");
+        assert_eq!(err.status().as_u16(), 404);
+
+        assert_eq!(service.handle_count().await.unwrap().data.total, 0);
+        let err = service.handle(HandleRequest::from_query("+15555550100".into(), &HashMap::new()).unwrap()).await.unwrap_err();
+        assert_eq!(err.status().as_u16(), 404);
+        let request = HandleQueryRequest::from(serde_json::from_value::<HandleQuery>(json!({"address": "@example.com", "with": ["chats"]})).unwrap());
+        assert!(request.with_chats && request.address == "@example.com");
+        assert!(service.handle_query(request).await.unwrap().data.is_empty());
+
+        let err = service.message_thread(MessageThreadRequest::from_query("missing".into(), &HashMap::new()).unwrap()).await.unwrap_err();
+        assert_eq!(err.status().as_u16(), 404);
+
+        assert_eq!(service.update_search_index(&[]).await.unwrap(), 0);
+        let body: MessageSearchBody = serde_json::from_value(json!({"query": "lunch", "chatGuids": ["iMessage;-;+15555550100"]})).unwrap();
+        let request = MessageSearchRequest::try_from(body).unwrap();
+        assert_eq!(request.query.chat_guids, ["iMessage;-;+15555550100"]);
+        assert!(service.message_search(request).await.unwrap().data.is_empty());
+        let body: MessageSearchBody = serde_json::from_value(json!({"query": " "})).unwrap();
+        assert_eq!(MessageSearchRequest::try_from(body).unwrap_err().status().as_u16(), 400);
+    }
+
+    #[tokio::test]
+    async fn test_search_index_batches() {
+        let path = std::env::temp_dir().join(format!("bluebubbles-search-batches-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let conn = rusqlite::Connection::open(&path).unwrap();
+        conn.execute_batch(FIXTURE_SCHEMA).unwrap();
+        conn.execute_batch("WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 6000) INSERT INTO message (ROWID, guid, text, date) SELECT i, 'message-' || i, 'hello ' || i, i FROM n").unwrap();
+        let service = ApiService::new(Database::open(&path, DatabaseOptions::default()).unwrap(), SearchIndex::open_in_memory().unwrap(), Config::default());
+        std::fs::remove_file(&path).unwrap();
+
+        // a large chat.db is indexed over several updates, one batch at a time
+        assert_eq!(service.update_search_index(&[]).await.unwrap(), super::SEARCH_INDEX_BATCH * super::SEARCH_INDEX_BATCHES);
+        assert_eq!(service.update_search_index(&[]).await.unwrap(), 6000 - super::SEARCH_INDEX_BATCH * super::SEARCH_INDEX_BATCHES);
+        assert_eq!(service.update_search_index(&[]).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_attachment_chunks() {
+        let dir = std::env::temp_dir().join(format!("bluebubbles-chunks-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("Attachments")).unwrap();
+        std::fs::write(dir.join("Attachments/notes.txt"), "0123456789".repeat(100)).unwrap();
+        let conn = rusqlite::Connection::open(dir.join("chat.db")).unwrap();
+        conn.execute_batch(FIXTURE_SCHEMA).unwrap();
+        conn.execute("INSERT INTO attachment (guid, original_guid, filename, mime_type, transfer_state) VALUES ('notes', 'notes', ?, 'text/plain', 5), ('elsewhere', 'elsewhere', ?, NULL, 5)", [
+            dir.join("Attachments/notes.txt").to_string_lossy(), dir.join("chat.db").to_string_lossy(),
+        ]).unwrap();
+        drop(conn);
+        let config = Config { password: "hunter2".into(), attachment_roots: vec![dir.join("Attachments")], ..Default::default() };
+        let service = ApiService::new(Database::open(dir.join("chat.db"), DatabaseOptions::default()).unwrap(), SearchIndex::open_in_memory().unwrap(), config);
+
+        assert_eq!(service.attachment_info("notes".into()).await.unwrap().data.mime_type.as_deref(), Some("text/plain"));
+        let chunk = |start, chunk_size, compress| AttachmentChunkRequest { guid: "notes".into(), start, chunk_size, compress };
+        assert_eq!(service.attachment_chunk(chunk(5, 10, false)).await.unwrap().unwrap(), b"5678901234");
+        assert_eq!(service.attachment_chunk(chunk(995, 10, false)).await.unwrap().unwrap(), b"56789");
+        assert_eq!(service.attachment_chunk(chunk(1000, 10, false)).await.unwrap(), None);
+
+        let compressed = service.attachment_chunk(chunk(0, 1000, true)).await.unwrap().unwrap();
+        assert!(compressed.len() < 100);
+        let mut decompressed = String::new();
+        flate2::read::ZlibDecoder::new(compressed.as_slice()).read_to_string(&mut decompressed).unwrap();
+        assert_eq!(decompressed, "0123456789".repeat(100));
+
+        let err = service.attachment_chunk(AttachmentChunkRequest { guid: "elsewhere".into(), ..chunk(0, 10, false) }).await.unwrap_err();
+        assert_eq!(err.status().as_u16(), 403);
+        assert_eq!(service.attachment_info("missing".into()).await.unwrap_err().status().as_u16(), 404);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
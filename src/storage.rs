@@ -0,0 +1,163 @@
+//! Where attachment files are read from. `attachment.filename` is whatever chat.db says, so every path
+//! is resolved (`~/` expanded, symlinks and `..` followed) and only served if it ends up inside one of
+//! the configured attachment roots.
+
+use std::{fmt, io, path::{Component, Path, PathBuf}, sync::OnceLock};
+
+use bluebubbles_core::{database::{AttachmentFile, TRANSFER_FINISHED}, util::expand_home};
+
+#[derive(Debug)]
+pub enum StorageError {
+    /// The file isn't on this Mac, either because it's still being received or because it was offloaded to iCloud.
+    NotDownloaded,
+    /// chat.db says the file was downloaded but it's gone.
+    Missing,
+    /// The path leads outside every attachment root.
+    OutsideRoots(PathBuf),
+    Io(io::Error),
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotDownloaded => f.write_str("Attachment has not been downloaded from iCloud"),
+            Self::Missing => f.write_str("Attachment file does not exist"),
+            Self::OutsideRoots(path) => write!(f, "Attachment path {} is outside the attachment roots", path.display()),
+            Self::Io(err) => write!(f, "io error: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+#[derive(Debug, Clone)]
+struct Root {
+    /// As configured, with `~/` expanded.
+    configured: PathBuf,
+    /// With symlinks resolved, set once the directory exists, which might be after startup (e.g. a volume mounted later).
+    canonical: OnceLock<PathBuf>,
+}
+
+impl Root {
+    fn canonical(&self) -> Option<&Path> {
+        if let Some(canonical) = self.canonical.get() {
+            return Some(canonical);
+        }
+        let canonical = self.configured.canonicalize().ok()?;
+        return Some(self.canonical.get_or_init(|| canonical));
+    }
+}
+
+/// The attachment roots files may be served from.
+#[derive(Debug, Clone)]
+pub struct AttachmentStorage {
+    roots: Vec<Root>,
+}
+
+impl AttachmentStorage {
+    pub fn new(roots: &[PathBuf]) -> Self {
+        let roots = roots.iter().map(|root| {
+            let configured = expand_home(&root.to_string_lossy());
+            let canonical = OnceLock::new();
+            if let Err(err) = configured.canonicalize().map(|path| canonical.set(path)) {
+                tracing::warn!("attachment root {configured:?}: {err}");
+            }
+            Root { configured, canonical }
+        }).collect();
+        Self { roots }
+    }
+
+    /// Whether `path`, which has to be canonical, is inside a root.
+    fn contains(&self, path: &Path) -> bool {
+        self.roots.iter().filter_map(Root::canonical).any(|root| path.starts_with(root))
+    }
+
+    /// For paths that don't exist and can't be canonicalized: inside a root without `..` or a relative path.
+    fn contains_lexically(&self, path: &Path) -> bool {
+        path.is_absolute() && !path.components().any(|component| component == Component::ParentDir)
+            && self.roots.iter().any(|root| path.starts_with(&root.configured) || root.canonical().is_some_and(|canonical| path.starts_with(canonical)))
+    }
+
+    /// The canonical path of an attachment's file, which is inside a root and exists.
+    pub fn resolve(&self, file: &AttachmentFile) -> Result<PathBuf, StorageError> {
+        let not_found = || match file.transfer_state {
+            TRANSFER_FINISHED => StorageError::Missing,
+            _ => StorageError::NotDownloaded,
+        };
+        let Some(path) = file.path.as_deref().filter(|path| !path.is_empty()) else {
+            return Err(not_found());
+        };
+        let path = expand_home(path);
+        match path.canonicalize() {
+            Ok(canonical) if self.contains(&canonical) => Ok(canonical),
+            Ok(canonical) => Err(StorageError::OutsideRoots(canonical)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound && self.contains_lexically(&path) => Err(not_found()),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Err(StorageError::OutsideRoots(path)),
+            Err(err) => Err(StorageError::Io(err)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use bluebubbles_core::database::{AttachmentFile, TRANSFER_FINISHED};
+
+    use super::{AttachmentStorage, StorageError};
+
+    fn file(path: Option<&str>, transfer_state: i64) -> AttachmentFile {
+        AttachmentFile { path: path.map(str::to_string), mime_type: None, transfer_name: None, transfer_state }
+    }
+
+    #[test]
+    fn test_resolve() {
+        let dir = std::env::temp_dir().join(format!("bluebubbles-storage-{}", std::process::id()));
+        let root = dir.join("Attachments");
+        std::fs::create_dir_all(root.join("00")).unwrap();
+        std::fs::write(root.join("00/photo.jpeg"), "photo").unwrap();
+        std::fs::write(dir.join("secret.txt"), "secret").unwrap();
+        let storage = AttachmentStorage::new(&[root.clone(), PathBuf::from("/does/not/exist")]);
+        let path = |path: &str| root.join(path).to_string_lossy().into_owned();
+
+        let resolved = storage.resolve(&file(Some(&path("00/photo.jpeg")), TRANSFER_FINISHED)).unwrap();
+        assert_eq!(resolved, root.canonicalize().unwrap().join("00/photo.jpeg"));
+        assert_eq!(storage.resolve(&file(Some(&path("00/../00/photo.jpeg")), TRANSFER_FINISHED)).unwrap(), resolved);
+        assert!(matches!(storage.resolve(&file(Some(&path("../secret.txt")), TRANSFER_FINISHED)), Err(StorageError::OutsideRoots(_))));
+        assert!(matches!(storage.resolve(&file(Some(&dir.join("secret.txt").to_string_lossy()), TRANSFER_FINISHED)), Err(StorageError::OutsideRoots(_))));
+        assert!(matches!(storage.resolve(&file(Some(&path("../missing.txt")), TRANSFER_FINISHED)), Err(StorageError::OutsideRoots(_))));
+        assert!(matches!(storage.resolve(&file(Some("relative/photo.jpeg"), TRANSFER_FINISHED)), Err(StorageError::OutsideRoots(_))));
+
+        assert!(matches!(storage.resolve(&file(Some(&path("00/gone.jpeg")), TRANSFER_FINISHED)), Err(StorageError::Missing)));
+        assert!(matches!(storage.resolve(&file(Some(&path("00/gone.jpeg")), 0)), Err(StorageError::NotDownloaded)));
+        assert!(matches!(storage.resolve(&file(None, 0)), Err(StorageError::NotDownloaded)));
+
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(dir.join("secret.txt"), root.join("00/link.txt")).unwrap();
+            assert!(matches!(storage.resolve(&file(Some(&path("00/link.txt")), TRANSFER_FINISHED)), Err(StorageError::OutsideRoots(_))));
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_root_created_later() {
+        let dir = std::env::temp_dir().join(format!("bluebubbles-storage-later-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        // a symlink, so the configured and canonical paths differ
+        let target = dir.join("volume");
+        let root = dir.join("Attachments");
+        let storage = AttachmentStorage::new(std::slice::from_ref(&root));
+
+        std::fs::create_dir_all(target.join("00")).unwrap();
+        std::fs::write(target.join("00/photo.jpeg"), "photo").unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(&target, &root).unwrap();
+        #[cfg(not(unix))]
+        std::fs::rename(&target, &root).unwrap();
+        let photo = root.join("00/photo.jpeg").to_string_lossy().into_owned();
+        assert_eq!(storage.resolve(&file(Some(&photo), TRANSFER_FINISHED)).unwrap(), root.canonicalize().unwrap().join("00/photo.jpeg"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
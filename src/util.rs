@@ -1,7 +0,0 @@
-pub fn unix_to_apple(unix: u128) -> u128 {
-    unix.max(978307200000000000)-978307200000000000
-}
-
-pub fn apple_to_unix(apple: u128) -> u128 {
-    apple+978307200000000000
-}
\ No newline at end of file